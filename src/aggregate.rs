@@ -0,0 +1,412 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::source::{compare, Row, Rows, Schema};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function {
+    Count,
+    CountDistinct,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+/// One aggregate call from the projection, like `AVG(rating)`,
+/// `COUNT(*)` or `COUNT(DISTINCT userId)`.
+#[derive(Clone, Debug)]
+pub struct Aggregate {
+    function: Function,
+    // None for COUNT(*)
+    idx: Option<usize>,
+    name: String,
+}
+
+impl Aggregate {
+    /// Returns None if the field isn't an aggregate call at all.
+    pub fn parse(field: &str, schema: &Schema) -> Option<Self> {
+        // SUM(rating) OVER (...) is a window function, not an aggregate
+        if field.contains(" OVER ") {
+            return None;
+        }
+        let (function, rest) = field.split_once('(')?;
+        let arg = rest.strip_suffix(')')?.trim();
+        let (function, arg) = match (function.trim(), arg.strip_prefix("DISTINCT ")) {
+            ("COUNT", Some(arg)) => (Function::CountDistinct, arg.trim()),
+            (function, Some(_)) => panic!("DISTINCT is only supported in COUNT, not {function}"),
+            ("COUNT", None) => (Function::Count, arg),
+            ("SUM", None) => (Function::Sum, arg),
+            ("AVG", None) => (Function::Avg, arg),
+            ("MIN", None) => (Function::Min, arg),
+            ("MAX", None) => (Function::Max, arg),
+            _ => return None,
+        };
+        let idx =
+            if arg == "*" && function == Function::Count {
+                None
+            } else {
+                Some(schema.position(arg).unwrap_or_else(|| {
+                    panic!("'{}' field not found in table '{}'", arg, schema.table)
+                }))
+            };
+        Some(Self {
+            function,
+            idx,
+            name: field.to_owned(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Position of the field it reads, None for COUNT(*).
+    pub fn input(&self) -> Option<usize> {
+        self.idx
+    }
+
+    fn state(&self) -> State {
+        match self.function {
+            Function::Count => State::Count(0),
+            Function::CountDistinct => State::Distinct(HashSet::new()),
+            Function::Sum => State::Sum(0.0),
+            Function::Avg => State::Avg(0.0, 0),
+            Function::Min | Function::Max => State::Extreme(None),
+        }
+    }
+
+    fn update(&self, state: &mut State, row: &Row) {
+        let value = match self.idx {
+            Some(idx) => &row[idx],
+            // COUNT(*) counts every row
+            None => {
+                if let State::Count(n) = state {
+                    *n += 1;
+                }
+                return;
+            }
+        };
+        // empty fields are our NULLs, aggregates skip them
+        if value.is_empty() {
+            return;
+        }
+        match state {
+            State::Count(n) => *n += 1,
+            State::Distinct(seen) => {
+                if !seen.contains(value) {
+                    seen.insert(value.clone());
+                }
+            }
+            State::Sum(sum) => *sum += number(value),
+            State::Avg(sum, n) => {
+                *sum += number(value);
+                *n += 1;
+            }
+            State::Extreme(current) => {
+                let replace = match current {
+                    None => true,
+                    Some(current) if self.function == Function::Min => {
+                        compare(value, current).is_lt()
+                    }
+                    Some(current) => compare(value, current).is_gt(),
+                };
+                if replace {
+                    *current = Some(value.clone());
+                }
+            }
+        }
+    }
+}
+
+fn number(value: &str) -> f64 {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("'{value}' is not a number"))
+}
+
+#[derive(Debug)]
+enum State {
+    Count(usize),
+    Distinct(HashSet<String>),
+    Sum(f64),
+    Avg(f64, usize),
+    // MIN or MAX, whatever is the most extreme so far
+    Extreme(Option<String>),
+}
+
+impl State {
+    fn finish(self) -> String {
+        match self {
+            State::Count(n) => n.to_string(),
+            State::Distinct(seen) => seen.len().to_string(),
+            State::Sum(sum) => sum.to_string(),
+            // AVG of nothing is NULL
+            State::Avg(_, 0) => String::new(),
+            State::Avg(sum, n) => (sum / n as f64).to_string(),
+            State::Extreme(value) => value.unwrap_or_default(),
+        }
+    }
+}
+
+/// Splits the projection into the aggregate calls it has, if any.
+pub fn aggregates(projection: &[String], schema: &Schema) -> Vec<Aggregate> {
+    projection
+        .iter()
+        .filter_map(|field| Aggregate::parse(field, schema))
+        .collect()
+}
+
+fn group_idxs(group: &[String], schema: &Schema) -> Vec<usize> {
+    group
+        .iter()
+        .map(|field| {
+            schema.position(field).unwrap_or_else(|| {
+                panic!("'{}' field not found in table '{}'", field, schema.table)
+            })
+        })
+        .collect()
+}
+
+/// What comes out of an aggregation: the GROUP fields followed
+/// by the aggregates, named as they were written (e.g. `AVG(rating)`),
+/// so HAVING, ORDER and PROJECTION can refer to them.
+pub fn schema(group: &[String], aggregates: &[Aggregate], schema: &Schema) -> Schema {
+    Schema {
+        table: schema.table.clone(),
+        fields: group
+            .iter()
+            .cloned()
+            .chain(aggregates.iter().map(|a| a.name.clone()))
+            .collect(),
+    }
+}
+
+fn finish(key: Row, states: Vec<State>) -> Row {
+    key.into_iter()
+        .chain(states.into_iter().map(State::finish))
+        .collect()
+}
+
+/// Builds every group in a hash table before returning anything,
+/// works with the input in any order. Groups come out in the order
+/// they were first seen.
+pub struct HashAggregate<'a> {
+    source: Rows<'a>,
+    groups: Vec<usize>,
+    aggregates: Vec<Aggregate>,
+    results: Option<std::vec::IntoIter<Row>>,
+}
+
+impl<'a> HashAggregate<'a> {
+    pub fn new(
+        group: &[String],
+        aggregates: Vec<Aggregate>,
+        source: impl Iterator<Item = Row> + 'a,
+        schema: &Schema,
+    ) -> Self {
+        Self {
+            groups: group_idxs(group, schema),
+            aggregates,
+            source: Box::new(source),
+            results: None,
+        }
+    }
+}
+
+impl<'a> Iterator for HashAggregate<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.results.is_none() {
+            let mut positions: HashMap<Row, usize> = HashMap::new();
+            let mut groups: Vec<(Row, Vec<State>)> = vec![];
+            for row in &mut *self.source {
+                let key: Row = self.groups.iter().map(|i| row[*i].clone()).collect();
+                let position = match positions.get(&key) {
+                    Some(position) => *position,
+                    None => {
+                        let states = self.aggregates.iter().map(Aggregate::state).collect();
+                        positions.insert(key.clone(), groups.len());
+                        groups.push((key, states));
+                        groups.len() - 1
+                    }
+                };
+                let states = &mut groups[position].1;
+                for (aggregate, state) in self.aggregates.iter().zip(states) {
+                    aggregate.update(state, &row);
+                }
+            }
+            // no GROUP means there's always one group, even with no rows
+            if groups.is_empty() && self.groups.is_empty() {
+                groups.push((
+                    vec![],
+                    self.aggregates.iter().map(Aggregate::state).collect(),
+                ));
+            }
+            let results: Vec<Row> = groups
+                .into_iter()
+                .map(|(key, states)| finish(key, states))
+                .collect();
+            self.results = Some(results.into_iter());
+        }
+        self.results.as_mut()?.next()
+    }
+}
+
+/// Expects the input sorted by the GROUP fields, so it only needs to hold
+/// one group at a time and can return it as soon as the next one starts.
+pub struct StreamAggregate<'a> {
+    source: Rows<'a>,
+    groups: Vec<usize>,
+    aggregates: Vec<Aggregate>,
+    // first row of the next group, we only know a group ended after seeing it
+    pending: Option<Row>,
+    emitted: bool,
+    done: bool,
+}
+
+impl<'a> StreamAggregate<'a> {
+    pub fn new(
+        group: &[String],
+        aggregates: Vec<Aggregate>,
+        source: impl Iterator<Item = Row> + 'a,
+        schema: &Schema,
+    ) -> Self {
+        Self {
+            groups: group_idxs(group, schema),
+            aggregates,
+            source: Box::new(source),
+            pending: None,
+            emitted: false,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for StreamAggregate<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut states: Vec<State> = self.aggregates.iter().map(Aggregate::state).collect();
+        let first = match self.pending.take().or_else(|| self.source.next()) {
+            Some(row) => row,
+            None => {
+                self.done = true;
+                // no GROUP means there's always one group, even with no rows
+                if self.emitted || !self.groups.is_empty() {
+                    return None;
+                }
+                self.emitted = true;
+                return Some(finish(vec![], states));
+            }
+        };
+        let key: Row = self.groups.iter().map(|i| first[*i].clone()).collect();
+        let mut row = first;
+        loop {
+            for (aggregate, state) in self.aggregates.iter().zip(&mut states) {
+                aggregate.update(state, &row);
+            }
+            row = match self.source.next() {
+                Some(next) => next,
+                None => {
+                    self.done = true;
+                    break;
+                }
+            };
+            if self.groups.iter().zip(&key).any(|(i, k)| &row[*i] != k) {
+                self.pending = Some(row);
+                break;
+            }
+        }
+        self.emitted = true;
+        Some(finish(key, states))
+    }
+}
+
+#[test]
+fn test_hash_and_stream_aggregate_agree() {
+    use crate::source::Sort;
+
+    let schema = Schema {
+        table: "ratings".into(),
+        fields: vec!["userId".into(), "movieId".into(), "rating".into()],
+    };
+    let rows: Vec<Row> = [
+        ["1", "10", "4.0"],
+        // another group, even if it's the same number
+        ["4", "10.0", "3.0"],
+        ["2", "20", "3.0"],
+        ["1", "20", "5.0"],
+        ["3", "10", "2.0"],
+        ["2", "10", "4.0"],
+        ["2", "10", "1.0"],
+    ]
+    .iter()
+    .map(|r| r.iter().map(|s| s.to_string()).collect())
+    .collect();
+    let group = vec!["movieId".to_owned()];
+    let projection: Vec<String> = [
+        "movieId",
+        "COUNT(*)",
+        "COUNT(DISTINCT userId)",
+        "SUM(rating)",
+        "AVG(rating)",
+        "MIN(rating)",
+        "MAX(rating)",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+
+    let mut source = rows.clone().into_iter();
+    let hash: Vec<Row> = HashAggregate::new(
+        &group,
+        aggregates(&projection, &schema),
+        &mut source,
+        &schema,
+    )
+    .collect();
+
+    let mut source = rows.into_iter();
+    let mut sorted = Sort::new(group.clone(), &mut source, &schema);
+    let stream: Vec<Row> = StreamAggregate::new(
+        &group,
+        aggregates(&projection, &schema),
+        &mut sorted,
+        &schema,
+    )
+    .collect();
+
+    let expected: Vec<Row> = vec![
+        vec!["10", "4", "3", "11", "2.75", "1.0", "4.0"],
+        vec!["10.0", "1", "1", "3", "3", "3.0", "3.0"],
+        vec!["20", "2", "2", "8", "4", "3.0", "5.0"],
+    ]
+    .into_iter()
+    .map(|r| r.into_iter().map(String::from).collect())
+    .collect();
+    assert_eq!(hash, expected);
+    assert_eq!(stream, expected);
+}
+
+#[test]
+fn test_aggregate_without_group_on_empty_input() {
+    let schema = Schema {
+        table: "ratings".into(),
+        fields: vec!["rating".into()],
+    };
+    let projection = vec!["COUNT(*)".to_owned(), "AVG(rating)".to_owned()];
+
+    let mut source = std::iter::empty();
+    let hash: Vec<Row> =
+        HashAggregate::new(&[], aggregates(&projection, &schema), &mut source, &schema).collect();
+    let mut source = std::iter::empty();
+    let stream: Vec<Row> =
+        StreamAggregate::new(&[], aggregates(&projection, &schema), &mut source, &schema).collect();
+
+    assert_eq!(hash, vec![vec!["0".to_owned(), String::new()]]);
+    assert_eq!(stream, hash);
+}
@@ -0,0 +1,247 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::catalog::Catalog;
+use crate::plan::cost::{ColumnStats, TableStats};
+use crate::source::{compare, Row, Schema};
+
+// rows kept from each table
+const SAMPLE_SIZE: usize = 30_000;
+// most common values kept per column
+const MOST_COMMON: usize = 10;
+const BUCKETS: usize = 10;
+
+/// Keeps a uniform random sample of `size` items out of
+/// however many go through it (algorithm R).
+pub struct Reservoir<T> {
+    size: usize,
+    seen: usize,
+    items: Vec<T>,
+    // xorshift, no need for anything fancier
+    state: u64,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            seen: 0,
+            items: Vec::with_capacity(size),
+            // fixed so ANALYZE always ends up with the same stats
+            state: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    fn random(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    pub fn add(&mut self, item: T) {
+        self.seen += 1;
+        if self.items.len() < self.size {
+            self.items.push(item);
+            return;
+        }
+        let i = (self.random() % self.seen as u64) as usize;
+        if i < self.size {
+            self.items[i] = item;
+        }
+    }
+
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    pub fn into_items(self) -> Vec<T> {
+        self.items
+    }
+}
+
+// 2^12 registers, about 1.6% of error
+const PRECISION: u32 = 12;
+
+/// Estimates how many distinct values went through it
+/// with a few KBs, no matter how many there are.
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; 1 << PRECISION],
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn add(&mut self, value: &str) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        // first bits pick the register, the rest count the leading zeros
+        let register = (hash >> (64 - PRECISION)) as usize;
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count() as f64;
+        // too few values for the registers, linear counting is better there
+        if raw <= 2.5 * m && zeros > 0.0 {
+            m * (m / zeros).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+/// Reads the whole table: counts the rows and the distinct values
+/// of every column, the rest comes from a sample of the rows.
+pub fn analyze(catalog: &Catalog, table: &str) -> TableStats {
+    analyze_rows(catalog.scan(table), &catalog.schema(table))
+}
+
+pub fn analyze_rows(rows: impl Iterator<Item = Row>, schema: &Schema) -> TableStats {
+    let mut sample = Reservoir::new(SAMPLE_SIZE);
+    let mut distinct: Vec<HyperLogLog> = schema.fields.iter().map(|_| Default::default()).collect();
+    for row in rows {
+        for (hll, value) in distinct.iter_mut().zip(&row) {
+            if !value.is_empty() {
+                hll.add(value);
+            }
+        }
+        sample.add(row);
+    }
+    let count = sample.seen();
+    let sample = sample.into_items();
+
+    let columns = schema
+        .fields
+        .iter()
+        .zip(distinct)
+        .enumerate()
+        .map(|(i, (field, hll))| {
+            let values = sample.iter().map(|row| row[i].as_str());
+            (field.clone(), column(values, sample.len(), hll.estimate()))
+        })
+        .collect();
+    TableStats {
+        rows: count as f64,
+        columns,
+    }
+}
+
+// the frequencies are of all the rows, nulls (empty) included
+fn column<'a>(values: impl Iterator<Item = &'a str>, sampled: usize, distinct: f64) -> ColumnStats {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut nulls = 0;
+    for value in values {
+        match value.is_empty() {
+            true => nulls += 1,
+            false => *counts.entry(value).or_default() += 1,
+        }
+    }
+    let sampled = sampled.max(1) as f64;
+
+    // only the ones that show up more than the average value does
+    let average = (sampled - nulls as f64) / counts.len().max(1) as f64;
+    let mut common: Vec<(&str, usize)> = counts
+        .iter()
+        .filter(|(_, count)| **count > 1 && **count as f64 > average)
+        .map(|(value, count)| (*value, *count))
+        .collect();
+    common.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| compare(a.0, b.0)));
+    common.truncate(MOST_COMMON);
+
+    // equi-depth, over whatever isn't a most common value
+    let mut rest: Vec<&str> = counts
+        .iter()
+        .filter(|(value, _)| !common.iter().any(|(c, _)| c == *value))
+        .flat_map(|(value, count)| std::iter::repeat_n(*value, *count))
+        .collect();
+    rest.sort_by(|a, b| compare(a, b));
+    let histogram = match rest.len() > BUCKETS {
+        true => (0..=BUCKETS)
+            .map(|b| rest[(b * (rest.len() - 1)) / BUCKETS].to_owned())
+            .collect(),
+        false => vec![],
+    };
+
+    ColumnStats {
+        null_fraction: nulls as f64 / sampled,
+        distinct: distinct.max(1.0),
+        most_common: common
+            .into_iter()
+            .map(|(value, count)| (value.to_owned(), count as f64 / sampled))
+            .collect(),
+        histogram,
+    }
+}
+
+#[test]
+fn test_hyper_log_log() {
+    let mut hll = HyperLogLog::default();
+    for i in 0..100_000 {
+        // every value twice
+        hll.add(&(i % 50_000).to_string());
+    }
+    let error = (hll.estimate() - 50_000.0).abs() / 50_000.0;
+    assert!(error < 0.05, "estimated {}", hll.estimate());
+
+    let mut few = HyperLogLog::default();
+    for value in ["a", "b", "c", "a"] {
+        few.add(value);
+    }
+    assert_eq!(few.estimate().round(), 3.0);
+}
+
+#[test]
+fn test_reservoir() {
+    let mut reservoir = Reservoir::new(1000);
+    for i in 0..100_000 {
+        reservoir.add(i);
+    }
+    assert_eq!(reservoir.seen(), 100_000);
+    let items = reservoir.into_items();
+    assert_eq!(items.len(), 1000);
+    // uniform, so about half of them from each half
+    let first_half = items.iter().filter(|i| **i < 50_000).count();
+    assert!((400..600).contains(&first_half), "{first_half}");
+}
+
+#[test]
+fn test_analyze_rows() {
+    let schema = Schema {
+        table: "ratings".into(),
+        fields: vec!["movieId".into(), "rating".into(), "tag".into()],
+    };
+    // movie 0 has half of the ratings, the rest are all different
+    let rows = (0..1000).map(|i: usize| {
+        let movie = if i.is_multiple_of(2) { 0 } else { i };
+        let tag = if i.is_multiple_of(4) { "funny" } else { "" };
+        vec![movie.to_string(), (i % 10).to_string(), tag.to_owned()]
+    });
+    let stats = analyze_rows(rows, &schema);
+    assert_eq!(stats.rows, 1000.0);
+
+    let movie = &stats.columns["movieId"];
+    assert_eq!(movie.most_common, vec![("0".to_owned(), 0.5)]);
+    assert!((movie.distinct - 501.0).abs() < 10.0);
+    assert_eq!(movie.histogram.len(), BUCKETS + 1);
+    assert_eq!(movie.histogram.first().unwrap(), "1");
+    assert_eq!(movie.histogram.last().unwrap(), "999");
+
+    let tag = &stats.columns["tag"];
+    assert_eq!(tag.null_fraction, 0.75);
+    assert_eq!(tag.distinct.round(), 1.0);
+}
@@ -7,6 +7,7 @@ use node::SearchResult::*;
 use stack::*;
 
 use std::mem;
+use std::ops::Bound;
 
 pub struct BTreeMap<K, V> {
     root: Node<K, V>,
@@ -39,13 +40,19 @@ impl<K, V> BTreeMap<K, V> {
     }
 }
 
+impl<K, V> Default for BTreeMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 use core::borrow::Borrow;
 
 impl<K: Ord, V> BTreeMap<K, V> {
-    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
-        Q: Ord,
+        Q: Ord + ?Sized,
     {
         let mut curr = &self.root;
         loop {
@@ -60,6 +67,27 @@ impl<K: Ord, V> BTreeMap<K, V> {
         }
     }
 
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut curr = &mut self.root;
+        loop {
+            match curr.search(key) {
+                Found(i) => return Some(unsafe { curr.unsafe_val_mut(i) }),
+                GoDown(i) => curr = curr.edge_mut(i)?,
+            }
+        }
+    }
+
+    /// Every pair with a key inside the bounds, in order.
+    pub fn range(&self, low: Bound<&K>, high: Bound<&K>) -> Vec<(&K, &V)> {
+        let mut found = vec![];
+        range(&self.root, low, high, &mut found);
+        found
+    }
+
     pub fn insert(&mut self, key: K, mut value: V) -> Option<V> {
         use stack::*;
 
@@ -103,6 +131,40 @@ impl<K: Ord, V> BTreeMap<K, V> {
     }
 }
 
+// in order, only going down the edges that can have keys inside the bounds
+fn range<'a, K: Ord, V>(
+    node: &'a Node<K, V>,
+    low: Bound<&K>,
+    high: Bound<&K>,
+    found: &mut Vec<(&'a K, &'a V)>,
+) {
+    let above_low = |key: &K| match low {
+        Bound::Included(low) => key >= low,
+        Bound::Excluded(low) => key > low,
+        Bound::Unbounded => true,
+    };
+    let below_high = |key: &K| match high {
+        Bound::Included(high) => key <= high,
+        Bound::Excluded(high) => key < high,
+        Bound::Unbounded => true,
+    };
+    for i in 0..=node.len() {
+        // everything in edge i is between key i - 1 and key i
+        let before = i.checked_sub(1).and_then(|i| node.key(i));
+        let after = node.key(i);
+        if let Some(edge) = node.edge(i) {
+            if before.is_none_or(&below_high) && after.is_none_or(&above_low) {
+                range(edge, low, high, found);
+            }
+        }
+        if let (Some(key), Some(val)) = (after, node.val(i)) {
+            if above_low(key) && below_high(key) {
+                found.push((key, val));
+            }
+        }
+    }
+}
+
 use std::fmt;
 
 impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for BTreeMap<K, V> {
@@ -115,3 +177,29 @@ impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for BTreeMap<K, V> {
             .finish()
     }
 }
+
+#[test]
+fn test_range() {
+    let mut map = BTreeMap::new();
+    // enough to split a few times
+    for i in (0..500).rev() {
+        map.insert(i, i * 10);
+    }
+    let keys = |pairs: Vec<(&i32, &i32)>| pairs.into_iter().map(|(k, _)| *k).collect::<Vec<_>>();
+    assert_eq!(
+        keys(map.range(Bound::Included(&100), Bound::Excluded(&105))),
+        vec![100, 101, 102, 103, 104]
+    );
+    assert_eq!(
+        keys(map.range(Bound::Excluded(&495), Bound::Unbounded)),
+        vec![496, 497, 498, 499]
+    );
+    assert_eq!(map.range(Bound::Unbounded, Bound::Unbounded).len(), 500);
+    assert!(map
+        .range(Bound::Included(&600), Bound::Unbounded)
+        .is_empty());
+
+    *map.get_mut(&7).unwrap() += 1;
+    assert_eq!(map.get(&7), Some(&71));
+    assert_eq!(map.get_mut(&700), None);
+}
@@ -132,6 +132,11 @@ impl<K, V> Node<K, V> {
         &mut self.keys.as_mut_slice()[index]
     }
 
+    /// Get the node's key at the given index
+    pub fn key(&self, index: usize) -> Option<&K> {
+        self.keys.as_slice().get(index)
+    }
+
     /// Get the node's value at the given index
     pub fn val(&self, index: usize) -> Option<&V> {
         self.vals.as_slice().get(index)
@@ -387,18 +392,18 @@ impl<K, V> Node<K, V> {
 
         self.keys.push(key);
         self.vals.push(val);
-        self.keys.extend(right.keys.into_iter());
-        self.vals.extend(right.vals.into_iter());
-        self.edges.extend(right.edges.into_iter());
+        self.keys.extend(right.keys);
+        self.vals.extend(right.vals);
+        self.edges.extend(right.edges);
     }
 }
 
 impl<K: Ord, V> Node<K, V> {
     // for now linear
-    pub fn search<Q: ?Sized>(&self, key: &Q) -> SearchResult
+    pub fn search<Q>(&self, key: &Q) -> SearchResult
     where
         K: Borrow<Q>,
-        Q: Ord,
+        Q: Ord + ?Sized,
     {
         for (i, k) in self.keys.iter().enumerate() {
             match k.borrow().cmp(key) {
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::data::Shared;
+use crate::fs::{page_lsn, PAGE};
+use crate::wal;
+
+// 8 MiB
+const FRAMES: usize = 1024;
+
+// shared by every thread, like the log (a checkpoint has to write
+// every page changed before it)
+static POOL: Shared<BufferPool> = Shared::new(|dir| BufferPool::new(dir, FRAMES));
+
+/// Runs `f` with the pool every heap file (of the data directory)
+/// goes through.
+pub fn with<T>(f: impl FnOnce(&mut BufferPool) -> T) -> T {
+    POOL.with(f)
+}
+
+// the pages that weren't written are lost
+#[cfg(test)]
+pub(crate) fn crash() {
+    POOL.forget();
+}
+
+/// A page of a table's heap file, counted from the start of the file.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PageId {
+    pub table: String,
+    pub page: usize,
+}
+
+impl PageId {
+    pub fn new(table: &str, page: usize) -> Self {
+        Self {
+            table: table.to_owned(),
+            page,
+        }
+    }
+}
+
+struct Frame {
+    page: Option<PageId>,
+    data: Box<[u8]>,
+    pins: usize,
+    dirty: bool,
+    // the clock gives it another chance if it was used since it last passed
+    referenced: bool,
+}
+
+/// A fixed number of pages in memory, the least recently used ones
+/// (more or less, it's a clock sweep) are written back if they were
+/// changed and replaced when another page is needed. A pinned page
+/// stays where it is until it's unpinned. A page is only written once
+/// the log is flushed up to its LSN (see wal::Wal).
+pub struct BufferPool {
+    frames: Vec<Frame>,
+    // which frame every page in memory is in
+    pages: HashMap<PageId, usize>,
+    // the data directory the tables are in
+    dir: String,
+    files: HashMap<String, File>,
+    hand: usize,
+    hits: usize,
+    misses: usize,
+}
+
+impl BufferPool {
+    pub fn new(dir: &str, frames: usize) -> Self {
+        let frames = (0..frames)
+            .map(|_| Frame {
+                page: None,
+                data: vec![0; PAGE].into_boxed_slice(),
+                pins: 0,
+                dirty: false,
+                referenced: false,
+            })
+            .collect();
+        Self {
+            frames,
+            pages: HashMap::new(),
+            dir: dir.to_owned(),
+            files: HashMap::new(),
+            hand: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Pages that were already in memory.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Pages read from the file.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    /// Brings the page in (if it isn't already) and keeps it there
+    /// until it's unpinned, returns the frame it's in.
+    pub fn pin(&mut self, id: &PageId) -> Result<usize, io::Error> {
+        if let Some(&n) = self.pages.get(id) {
+            self.hits += 1;
+            let frame = &mut self.frames[n];
+            frame.pins += 1;
+            frame.referenced = true;
+            return Ok(n);
+        }
+        let n = self.victim()?;
+        self.misses += 1;
+        let file = open(&mut self.files, &self.dir, &id.table)?;
+        file.seek(SeekFrom::Start((PAGE * id.page) as u64))?;
+        let data = &mut self.frames[n].data;
+        // past the end of the file is all zeroes, like a new page
+        data.fill(0);
+        let mut read = 0;
+        while read < PAGE {
+            match file.read(&mut data[read..])? {
+                0 => break,
+                bytes => read += bytes,
+            }
+        }
+        let frame = &mut self.frames[n];
+        frame.page = Some(id.clone());
+        frame.pins = 1;
+        frame.dirty = false;
+        frame.referenced = true;
+        self.pages.insert(id.clone(), n);
+        Ok(n)
+    }
+
+    /// A new page at the end of the table's file, pinned. The file
+    /// grows (with zeroes) right away, its length is how many pages
+    /// there are, but the page is only written like any other.
+    pub fn allocate(&mut self, id: &PageId) -> Result<usize, io::Error> {
+        let file = open(&mut self.files, &self.dir, &id.table)?;
+        let len = (PAGE * (id.page + 1)) as u64;
+        if file.metadata()?.len() < len {
+            file.set_len(len)?;
+        }
+        self.pin(id)
+    }
+
+    /// `dirty` if the page was changed while it was pinned.
+    pub fn unpin(&mut self, frame: usize, dirty: bool) {
+        let frame = &mut self.frames[frame];
+        assert!(frame.pins > 0, "unpinning a page that isn't pinned");
+        frame.pins -= 1;
+        frame.dirty |= dirty;
+    }
+
+    pub fn page(&self, frame: usize) -> &[u8] {
+        &self.frames[frame].data
+    }
+
+    /// Remember to unpin it as dirty.
+    pub fn page_mut(&mut self, frame: usize) -> &mut [u8] {
+        &mut self.frames[frame].data
+    }
+
+    // an empty frame, or the first unpinned one the clock finds
+    // that wasn't used since the last time it passed
+    fn victim(&mut self) -> Result<usize, io::Error> {
+        for _ in 0..2 * self.frames.len() {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+            let candidate = &mut self.frames[frame];
+            if candidate.pins > 0 {
+                continue;
+            }
+            if candidate.referenced {
+                candidate.referenced = false;
+                continue;
+            }
+            self.evict(frame)?;
+            return Ok(frame);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            "every page in the buffer pool is pinned",
+        ))
+    }
+
+    fn evict(&mut self, frame: usize) -> Result<(), io::Error> {
+        self.write(frame)?;
+        if let Some(id) = self.frames[frame].page.take() {
+            self.pages.remove(&id);
+        }
+        Ok(())
+    }
+
+    // back to the file if it changed
+    fn write(&mut self, frame: usize) -> Result<(), io::Error> {
+        let Some(id) = self.frames[frame].page.clone() else {
+            return Ok(());
+        };
+        if !self.frames[frame].dirty {
+            return Ok(());
+        }
+        // the log has to have the changes before the page does
+        let lsn = page_lsn(&self.frames[frame].data);
+        wal::with(|wal| wal.flush(lsn))?;
+        let file = open(&mut self.files, &self.dir, &id.table)?;
+        file.seek(SeekFrom::Start((PAGE * id.page) as u64))?;
+        file.write_all(&self.frames[frame].data)?;
+        self.frames[frame].dirty = false;
+        Ok(())
+    }
+
+    /// Writes the page if it's in memory and it changed.
+    pub fn flush_page(&mut self, id: &PageId) -> Result<(), io::Error> {
+        match self.pages.get(id) {
+            Some(&frame) => self.write(frame),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes every changed page of the table.
+    pub fn flush(&mut self, table: &str) -> Result<(), io::Error> {
+        for frame in 0..self.frames.len() {
+            if self.frames[frame]
+                .page
+                .as_ref()
+                .is_some_and(|id| id.table == table)
+            {
+                self.write(frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every changed page, of every table, and syncs the files.
+    pub fn sync(&mut self) -> Result<(), io::Error> {
+        for frame in 0..self.frames.len() {
+            self.write(frame)?;
+        }
+        // including the pages written when they were evicted
+        for file in self.files.values() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Drops the table's pages without writing them, for when the
+    /// file is removed or changed behind the pool's back.
+    pub fn forget(&mut self, table: &str) {
+        for frame in self.frames.iter_mut() {
+            if frame.page.as_ref().is_some_and(|id| id.table == table) {
+                assert_eq!(frame.pins, 0, "forgetting a pinned page of '{table}'");
+                frame.page = None;
+                frame.dirty = false;
+                frame.referenced = false;
+            }
+        }
+        self.pages.retain(|id, _| id.table != table);
+        self.files.remove(table);
+    }
+}
+
+// kept open, not borrowing the whole pool so the frames can be read into
+fn open<'a>(
+    files: &'a mut HashMap<String, File>,
+    dir: &str,
+    table: &str,
+) -> Result<&'a mut File, io::Error> {
+    if !files.contains_key(table) {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("{dir}/{table}"))?;
+        files.insert(table.to_owned(), file);
+    }
+    Ok(files.get_mut(table).unwrap())
+}
+
+#[test]
+fn test_buffer_pool() {
+    crate::data::test_dir("test_buffer_pool");
+    let path = crate::data::path("test_buffer_pool");
+    std::fs::write(&path, vec![0; PAGE * 4]).unwrap();
+    let mut pool = BufferPool::new(&crate::data::dir(), 2);
+    let page = |n| PageId::new("test_buffer_pool", n);
+
+    let first = pool.pin(&page(0)).unwrap();
+    pool.page_mut(first)[0] = 1;
+    pool.unpin(first, true);
+    assert_eq!(pool.pin(&page(0)).unwrap(), first);
+    pool.unpin(first, false);
+    assert_eq!((pool.hits(), pool.misses()), (1, 1));
+
+    // page 0 is pinned, so 1 makes room for 2
+    let first = pool.pin(&page(0)).unwrap();
+    let second = pool.pin(&page(1)).unwrap();
+    pool.unpin(second, false);
+    assert_eq!(pool.pin(&page(2)).unwrap(), second);
+    assert!(pool.pin(&page(3)).is_err());
+    pool.unpin(first, false);
+    pool.unpin(second, false);
+
+    // still dirty, it's written when it's evicted
+    let file = std::fs::read(&path).unwrap();
+    assert_eq!(file[0], 0);
+    for n in 1..4 {
+        let frame = pool.pin(&page(n)).unwrap();
+        pool.unpin(frame, false);
+    }
+    let file = std::fs::read(&path).unwrap();
+    assert_eq!(file[0], 1);
+    let frame = pool.pin(&page(0)).unwrap();
+    assert_eq!(pool.page(frame)[0], 1);
+    // 2 was still there
+    assert_eq!((pool.hits(), pool.misses()), (3, 6));
+}
+
+#[test]
+fn test_shared_pool() {
+    let dir = crate::data::test_dir("test_shared_pool");
+    let path = crate::data::path("test_shared");
+    std::fs::write(&path, vec![0; PAGE]).unwrap();
+    let page = PageId::new("test_shared", 0);
+    with(|pool| {
+        let frame = pool.pin(&page).unwrap();
+        pool.page_mut(frame)[100] = 7;
+        pool.unpin(frame, true);
+    });
+    // the same pages on another thread, and it can write them
+    std::thread::spawn(move || {
+        crate::data::use_dir(&dir);
+        with(|pool| {
+            let frame = pool.pin(&page).unwrap();
+            assert_eq!(pool.page(frame)[100], 7);
+            pool.unpin(frame, false);
+            pool.sync().unwrap();
+        });
+    })
+    .join()
+    .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap()[100], 7);
+}
@@ -0,0 +1,550 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+
+use crate::data;
+use crate::fs::{Heap, HeapFile};
+use crate::index::{Index, Indexes};
+use crate::plan::cost::{ColumnStats, TableStats};
+use crate::source::{FileScan, HeapScan, Row, Schema, Source};
+
+// rows looked at to guess the type of each column of a CSV
+const TYPE_SAMPLE: usize = 100;
+pub const TYPES: [&str; 3] = ["INTEGER", "FLOAT", "TEXT"];
+
+/// Where the rows of a table live.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Storage {
+    // ./ml-20m/{table}.csv, read only
+    Csv,
+    // in the data directory, see fs::HeapFile
+    Heap,
+}
+
+impl Storage {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Storage::Csv => "CSV",
+            Storage::Heap => "HEAP",
+        }
+    }
+}
+
+impl From<&str> for Storage {
+    fn from(storage: &str) -> Self {
+        match storage {
+            "CSV" => Storage::Csv,
+            "HEAP" => Storage::Heap,
+            _ => panic!("unknown storage '{storage}'"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    pub name: String,
+    // INTEGER, FLOAT or TEXT
+    pub data_type: String,
+}
+
+impl Column {
+    /// Whether the value can be stored in it, empty (null) always can.
+    pub fn accepts(&self, value: &str) -> bool {
+        match self.data_type.as_str() {
+            _ if value.is_empty() => true,
+            "INTEGER" => value.parse::<i64>().is_ok(),
+            "FLOAT" => value.parse::<f64>().is_ok(),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Table {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub storage: Storage,
+    // from the last ANALYZE
+    pub stats: Option<TableStats>,
+}
+
+impl Table {
+    /// Reads the header (and a few lines) of the CSV to find its columns.
+    pub fn csv(name: &str) -> Self {
+        let schema = Schema::new(name);
+        let sample: Vec<Row> = FileScan::new(name).take(TYPE_SAMPLE).collect();
+        let columns = schema
+            .fields
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| Column {
+                name,
+                data_type: data_type(sample.iter().map(|row| row[i].as_str())).to_owned(),
+            })
+            .collect();
+        Self {
+            name: name.to_owned(),
+            columns,
+            storage: Storage::Csv,
+            stats: None,
+        }
+    }
+
+    pub fn schema(&self) -> Schema {
+        Schema {
+            table: self.name.clone(),
+            fields: self.columns.iter().map(|c| c.name.clone()).collect(),
+        }
+    }
+
+    /// A heap table with its (empty) file, columns go like
+    /// `[("movieId", "INTEGER")]`. For the tests.
+    #[cfg(test)]
+    pub(crate) fn heap(name: &str, columns: &[(&str, &str)]) -> Self {
+        HeapFile::create(name, 0).unwrap();
+        Self {
+            name: name.to_owned(),
+            columns: columns
+                .iter()
+                .map(|(name, data_type)| Column {
+                    name: name.to_string(),
+                    data_type: data_type.to_string(),
+                })
+                .collect(),
+            storage: Storage::Heap,
+            stats: None,
+        }
+    }
+}
+
+// the narrowest type all the (not empty) values fit in
+fn data_type<'a>(mut values: impl Iterator<Item = &'a str> + Clone) -> &'static str {
+    let mut present = values.clone().filter(|v| !v.is_empty());
+    if present.all(|v| v.parse::<i64>().is_ok()) {
+        "INTEGER"
+    } else if values.all(|v| v.is_empty() || v.parse::<f64>().is_ok()) {
+        "FLOAT"
+    } else {
+        "TEXT"
+    }
+}
+
+/// Everything we know about the tables, kept in heap files in the
+/// data directory (see data::dir) so it doesn't have to be worked out again on every run.
+/// Each part of it is a table of its own, `{name}_{n}_tables`,
+/// `{name}_{n}_columns`, `{name}_{n}_indexes` and `{name}_{n}_statistics`,
+/// where n is the generation `{name}.current` says (see save).
+pub struct Catalog {
+    name: String,
+    tables: Vec<Table>,
+    // built again from the table on load, only which ones is stored
+    indexes: Indexes,
+}
+
+impl Catalog {
+    /// The one in the data directory, or a new one with every CSV in ./ml-20m if
+    /// there's none yet.
+    pub fn load() -> Self {
+        match Self::open("catalog") {
+            Ok(catalog) => catalog,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let mut catalog = Self::new("catalog");
+                let mut names: Vec<String> = std::fs::read_dir("./ml-20m")
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                    .filter_map(|file| file.strip_suffix(".csv").map(str::to_owned))
+                    .collect();
+                names.sort();
+                for name in names {
+                    catalog.register(Table::csv(&name));
+                }
+                // until we have CREATE INDEX
+                if catalog.table("movies").is_some() {
+                    catalog.create_index("movies", "movieId");
+                }
+                catalog.save().unwrap();
+                catalog
+            }
+            Err(err) => panic!("couldn't read the catalog: {err}"),
+        }
+    }
+
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            tables: vec![],
+            indexes: Indexes::default(),
+        }
+    }
+
+    pub fn open(name: &str) -> Result<Self, io::Error> {
+        let mut catalog = Self::new(name);
+        let generation = catalog.generation()?;
+        for row in catalog.read(generation, "tables")? {
+            catalog.tables.push(Table {
+                name: row[0].clone(),
+                columns: vec![],
+                storage: row[1].as_str().into(),
+                stats: row[2].parse().ok().map(|rows| TableStats {
+                    rows,
+                    columns: HashMap::new(),
+                }),
+            });
+        }
+        let mut columns = catalog.read(generation, "columns")?;
+        columns.sort_by_key(|row| row[1].parse::<usize>().unwrap());
+        for row in columns {
+            catalog.table_mut(&row[0]).columns.push(Column {
+                name: row[2].clone(),
+                data_type: row[3].clone(),
+            });
+        }
+        for row in catalog.read(generation, "statistics")? {
+            let column = ColumnStats {
+                null_fraction: row[2].parse().unwrap(),
+                distinct: row[3].parse().unwrap(),
+                most_common: serde_json::from_str(&row[4]).unwrap(),
+                histogram: serde_json::from_str(&row[5]).unwrap(),
+            };
+            let table = catalog.table_mut(&row[0]);
+            let stats = table
+                .stats
+                .as_mut()
+                .expect("row count of an analyzed table");
+            stats.columns.insert(row[1].clone(), column);
+        }
+        for row in catalog.read(generation, "indexes")? {
+            let schema = catalog.schema(&row[0]);
+            let mut scan = catalog.scan(&row[0]);
+            catalog.indexes.build(scan.as_mut(), &schema, &row[1]);
+        }
+        Ok(catalog)
+    }
+
+    // the one saved last, NotFound if it was never saved
+    fn generation(&self) -> Result<u64, io::Error> {
+        let current = std::fs::read_to_string(data::path(&format!("{}.current", self.name)))?;
+        Ok(current
+            .parse()
+            .expect("a generation in the catalog's current file"))
+    }
+
+    fn part(&self, generation: u64, part: &str) -> String {
+        format!("{}_{generation}_{part}", self.name)
+    }
+
+    fn read(&self, generation: u64, part: &str) -> Result<Vec<Row>, io::Error> {
+        HeapFile::open(&self.part(generation, part), 0)?
+            .into_iter()
+            .collect()
+    }
+
+    // from scratch every time, it's small
+    fn write(&self, generation: u64, part: &str, rows: Vec<Row>) -> Result<(), io::Error> {
+        let mut heap = HeapFile::create(&self.part(generation, part), 0)?;
+        for row in &rows {
+            heap.insert(row)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every part next to the ones saved last, and then switches
+    /// to them all at once, a crash leaves the old ones or the new ones.
+    pub fn save(&self) -> Result<(), io::Error> {
+        std::fs::create_dir_all(data::dir())?;
+        let old = match self.generation() {
+            Ok(generation) => Some(generation),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        let new = old.map_or(0, |generation| generation + 1);
+        let (tables, columns, indexes, statistics) = self.rows();
+        self.write(new, "tables", tables)?;
+        self.write(new, "columns", columns)?;
+        self.write(new, "indexes", indexes)?;
+        self.write(new, "statistics", statistics)?;
+
+        // the parts are in the log by now, so they're there after a crash
+        let current = data::path(&format!("{}.current", self.name));
+        let mut file = File::create(format!("{current}.new"))?;
+        file.write_all(new.to_string().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(format!("{current}.new"), current)?;
+        if let Some(old) = old {
+            for part in ["tables", "columns", "indexes", "statistics"] {
+                HeapFile::remove(&self.part(old, part))?;
+            }
+        }
+        Ok(())
+    }
+
+    // what's stored, and what information_schema shows
+    fn rows(&self) -> (Vec<Row>, Vec<Row>, Vec<Row>, Vec<Row>) {
+        let (mut tables, mut columns, mut statistics) = (vec![], vec![], vec![]);
+        for table in &self.tables {
+            let rows = table.stats.as_ref().map(|s| s.rows.to_string());
+            tables.push(vec![
+                table.name.clone(),
+                table.storage.as_str().to_owned(),
+                rows.unwrap_or_default(),
+            ]);
+            for (i, column) in table.columns.iter().enumerate() {
+                columns.push(vec![
+                    table.name.clone(),
+                    i.to_string(),
+                    column.name.clone(),
+                    column.data_type.clone(),
+                ]);
+                let Some(stats) = table
+                    .stats
+                    .as_ref()
+                    .and_then(|s| s.columns.get(&column.name))
+                else {
+                    continue;
+                };
+                statistics.push(vec![
+                    table.name.clone(),
+                    column.name.clone(),
+                    stats.null_fraction.to_string(),
+                    stats.distinct.to_string(),
+                    serde_json::to_string(&stats.most_common).unwrap(),
+                    serde_json::to_string(&stats.histogram).unwrap(),
+                ]);
+            }
+        }
+        let indexes = self
+            .indexes
+            .iter()
+            .map(|index| vec![index.table().to_owned(), index.field().to_owned()])
+            .collect();
+        (tables, columns, indexes, statistics)
+    }
+
+    /// Replaces the table if there's already one with that name.
+    pub fn register(&mut self, table: Table) {
+        self.tables.retain(|t| t.name != table.name);
+        self.tables.push(table);
+    }
+
+    /// A new (empty) heap table, see fs::HeapFile.
+    pub fn create_table(&mut self, name: &str, columns: Vec<Column>) -> Result<(), io::Error> {
+        assert!(self.table(name).is_none(), "table '{name}' already exists");
+        // its file would be next to the catalog's
+        assert!(
+            !name.starts_with(&format!("{}_", self.name)) && !name.contains(['/', '.']),
+            "invalid table name '{name}'"
+        );
+        assert!(
+            !columns.is_empty(),
+            "table '{name}' needs at least one column"
+        );
+        for (i, column) in columns.iter().enumerate() {
+            assert!(
+                TYPES.contains(&column.data_type.as_str()),
+                "unknown type '{}' for column '{}'",
+                column.data_type,
+                column.name
+            );
+            assert!(
+                columns[..i].iter().all(|c| c.name != column.name),
+                "column '{}' specified more than once",
+                column.name
+            );
+        }
+        std::fs::create_dir_all(data::dir())?;
+        HeapFile::create(name, 0)?;
+        self.register(Table {
+            name: name.to_owned(),
+            columns,
+            storage: Storage::Heap,
+            stats: None,
+        });
+        Ok(())
+    }
+
+    /// Removes the table and its file, along with its indexes.
+    pub fn drop_table(&mut self, name: &str) -> Result<(), io::Error> {
+        let table = self
+            .table(name)
+            .unwrap_or_else(|| panic!("table '{name}' doesn't exist"));
+        // those aren't ours to delete
+        assert_eq!(
+            table.storage,
+            Storage::Heap,
+            "'{name}' is a CSV table, only heap tables can be dropped"
+        );
+        HeapFile::remove(name)?;
+        self.indexes.remove(name);
+        self.tables.retain(|t| t.name != name);
+        Ok(())
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|t| t.name == name)
+    }
+
+    fn table_mut(&mut self, name: &str) -> &mut Table {
+        self.tables
+            .iter_mut()
+            .find(|t| t.name == name)
+            .unwrap_or_else(|| panic!("table '{name}' doesn't exist"))
+    }
+
+    pub fn schema(&self, name: &str) -> Schema {
+        self.table(name)
+            .unwrap_or_else(|| panic!("table '{name}' doesn't exist"))
+            .schema()
+    }
+
+    pub fn statistics(&self, name: &str) -> Option<&TableStats> {
+        self.table(name)?.stats.as_ref()
+    }
+
+    pub fn set_statistics(&mut self, name: &str, stats: TableStats) {
+        self.table_mut(name).stats = Some(stats);
+    }
+
+    pub fn indexes(&self) -> &Indexes {
+        &self.indexes
+    }
+
+    pub fn add_index(&mut self, index: Index) {
+        self.indexes.insert(index);
+    }
+
+    /// Whatever reads the table, depending on where it's stored.
+    pub fn scan(&self, name: &str) -> Box<dyn Source> {
+        let table = self
+            .table(name)
+            .unwrap_or_else(|| panic!("table '{name}' doesn't exist"));
+        match table.storage {
+            Storage::Csv => Box::new(FileScan::new(name)),
+            Storage::Heap => Box::new(HeapScan::new(name, None)),
+        }
+    }
+
+    /// Builds it right away, nothing happens if it's already there.
+    pub fn create_index(&mut self, table: &str, field: &str) {
+        if self.indexes.get(table, field).is_none() {
+            let schema = self.schema(table);
+            let mut scan = self.scan(table);
+            self.indexes.build(scan.as_mut(), &schema, field);
+        }
+    }
+
+    /// The catalog itself as tables, like `information_schema.columns`.
+    pub fn information_schema(&self, name: &str) -> Option<(Schema, Vec<Row>)> {
+        let (tables, columns, indexes, statistics) = self.rows();
+        let (fields, rows) = match name.strip_prefix("information_schema.")? {
+            "tables" => (vec!["table_name", "storage", "rows"], tables),
+            "columns" => (
+                vec!["table_name", "position", "column_name", "data_type"],
+                columns,
+            ),
+            "indexes" => (vec!["table_name", "column_name"], indexes),
+            "statistics" => (
+                vec![
+                    "table_name",
+                    "column_name",
+                    "null_fraction",
+                    "distinct",
+                    "most_common",
+                    "histogram",
+                ],
+                statistics,
+            ),
+            _ => panic!("unknown table '{name}'"),
+        };
+        let schema = Schema {
+            table: name.to_owned(),
+            fields: fields.into_iter().map(str::to_owned).collect(),
+        };
+        Some((schema, rows))
+    }
+}
+
+#[test]
+fn test_catalog() {
+    data::test_dir("test_catalog");
+    let mut catalog = Catalog::new("test_catalog");
+    catalog.register(Table {
+        storage: Storage::Csv,
+        ..Table::heap("ratings", &[("movieId", "INTEGER"), ("rating", "FLOAT")])
+    });
+    catalog.register(Table::heap("notes", &[("note", "TEXT")]));
+    let rating = ColumnStats {
+        null_fraction: 0.1,
+        distinct: 10.0,
+        most_common: vec![("4.0".into(), 0.3)],
+        histogram: vec!["0.5".into(), "5.0".into()],
+    };
+    let stats = TableStats {
+        rows: 1000.0,
+        columns: [("rating".to_owned(), rating)].into(),
+    };
+    catalog.set_statistics("ratings", stats.clone());
+    catalog.save().unwrap();
+
+    let catalog = Catalog::open("test_catalog").unwrap();
+    assert_eq!(catalog.schema("ratings").fields, vec!["movieId", "rating"]);
+    assert_eq!(catalog.table("notes").unwrap().storage, Storage::Heap);
+    assert_eq!(catalog.statistics("ratings"), Some(&stats));
+    assert_eq!(catalog.statistics("notes"), None);
+
+    let (schema, rows) = catalog
+        .information_schema("information_schema.columns")
+        .unwrap();
+    assert_eq!(schema.fields[2], "column_name");
+    assert_eq!(
+        rows,
+        vec![
+            vec!["ratings", "0", "movieId", "INTEGER"],
+            vec!["ratings", "1", "rating", "FLOAT"],
+            vec!["notes", "0", "note", "TEXT"],
+        ]
+    );
+    assert!(catalog.information_schema("ratings").is_none());
+
+    // a crash while saving it again, halfway through writing the parts
+    HeapFile::create("test_catalog_1_tables", 0).unwrap();
+    let mut catalog = Catalog::open("test_catalog").unwrap();
+    assert_eq!(catalog.tables.len(), 2);
+    catalog.register(Table::heap("more_notes", &[("note", "TEXT")]));
+    catalog.save().unwrap();
+    let catalog = Catalog::open("test_catalog").unwrap();
+    assert_eq!(catalog.tables.len(), 3);
+    // the old parts are gone
+    assert!(!std::path::Path::new(&data::path("test_catalog_0_tables")).exists());
+}
+
+#[test]
+fn test_data_type() {
+    assert_eq!(data_type(["1", "", "20"].into_iter()), "INTEGER");
+    assert_eq!(data_type(["1", "2.5"].into_iter()), "FLOAT");
+    assert_eq!(data_type(["1", "Toy Story"].into_iter()), "TEXT");
+}
+
+#[test]
+fn test_create_and_drop_table() {
+    data::test_dir("test_create_and_drop_table");
+    let mut catalog = Catalog::new("test_ddl");
+    let columns = vec![
+        Column {
+            name: "userId".into(),
+            data_type: "INTEGER".into(),
+        },
+        Column {
+            name: "note".into(),
+            data_type: "TEXT".into(),
+        },
+    ];
+    catalog.create_table("test_watchlist", columns).unwrap();
+    assert!(std::path::Path::new(&data::path("test_watchlist")).exists());
+    let table = catalog.table("test_watchlist").unwrap();
+    assert_eq!(table.storage, Storage::Heap);
+    assert_eq!(table.schema().fields, vec!["userId", "note"]);
+
+    catalog.drop_table("test_watchlist").unwrap();
+    assert!(!std::path::Path::new(&data::path("test_watchlist")).exists());
+    assert!(catalog.table("test_watchlist").is_none());
+}
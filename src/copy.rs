@@ -0,0 +1,244 @@
+use std::io::{self, BufRead};
+
+use crate::catalog::{Storage, Table};
+use crate::fs::{buf_reader, BulkWriter};
+use crate::index::Indexes;
+use crate::source::Row;
+use crate::wal::Xid;
+
+// how often it says how it's going
+const PROGRESS: usize = 100_000;
+
+/// What goes after WITH, like `["HEADER", "DELIMITER", ";"]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    // skip the first line
+    pub header: bool,
+    pub delimiter: char,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            header: false,
+            delimiter: ',',
+        }
+    }
+}
+
+impl From<&[String]> for Options {
+    fn from(parts: &[String]) -> Self {
+        let mut options = Self::default();
+        let mut parts = parts.iter();
+        while let Some(option) = parts.next() {
+            match option.as_str() {
+                "HEADER" => options.header = true,
+                "DELIMITER" => {
+                    let delimiter = parts.next().expect("a delimiter after DELIMITER");
+                    let mut chars = delimiter.chars();
+                    options.delimiter = match (chars.next(), chars.next()) {
+                        (Some(c), None) => c,
+                        _ => panic!("the delimiter should be a single character"),
+                    };
+                }
+                _ => panic!("unknown COPY option '{option}'"),
+            }
+        }
+        options
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Copied {
+    pub rows: usize,
+    // malformed lines, they're skipped
+    pub rejected: usize,
+    pub pages: usize,
+}
+
+/// Streams the CSV into the (heap) table a page at a time. Lines that
+/// can't be parsed, or don't fit the columns, are logged and skipped.
+/// The rows are part of the transaction, if it doesn't commit (or
+/// the load fails halfway) they're never seen. The pages aren't
+/// logged, see fs::BulkWriter. The table's indexes point at them too.
+pub fn copy(
+    table: &Table,
+    path: &str,
+    options: &Options,
+    xid: Xid,
+    indexes: &Indexes,
+) -> Result<Copied, io::Error> {
+    assert_eq!(
+        table.storage,
+        Storage::Heap,
+        "'{}' is a CSV table, only heap tables can be copied into",
+        table.name
+    );
+    let schema = table.schema();
+    let mut writer = BulkWriter::open(&table.name, xid)?;
+    let mut copied = Copied::default();
+    let lines = buf_reader(path)?.lines().enumerate();
+    for (n, line) in lines.skip(options.header as usize) {
+        let line = line?;
+        let row = match parse(&line, options.delimiter) {
+            Some(row) => row,
+            None => {
+                eprintln!("{path}:{}: unterminated quote, skipped", n + 1);
+                copied.rejected += 1;
+                continue;
+            }
+        };
+        if let Err(problem) = fits(table, &row) {
+            eprintln!("{path}:{}: {problem}, skipped", n + 1);
+            copied.rejected += 1;
+            continue;
+        }
+        let tid = writer.write(&row)?;
+        indexes.add(&schema, &row, tid);
+        copied.rows += 1;
+        if copied.rows.is_multiple_of(PROGRESS) {
+            eprintln!("copied {} rows into {}", copied.rows, table.name);
+        }
+    }
+    copied.pages = writer.finish()?;
+    Ok(copied)
+}
+
+fn fits(table: &Table, row: &Row) -> Result<(), String> {
+    if row.len() != table.columns.len() {
+        return Err(format!(
+            "{} fields, '{}' has {} columns",
+            row.len(),
+            table.name,
+            table.columns.len()
+        ));
+    }
+    match table.columns.iter().zip(row).find(|(c, v)| !c.accepts(v)) {
+        Some((column, value)) => Err(format!(
+            "'{value}' isn't a valid {} for column '{}'",
+            column.data_type, column.name
+        )),
+        None => Ok(()),
+    }
+}
+
+/// A CSV line into its fields, which can be quoted (to have the
+/// delimiter in them) with `""` for a quote inside. None if a quote
+/// isn't closed, a field can't span lines.
+pub fn parse(line: &str, delimiter: char) -> Option<Row> {
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches(['\r', '\n']).chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            c if c == delimiter && !quoted => row.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    row.push(field);
+    Some(row)
+}
+
+#[test]
+fn test_parse() {
+    let row = |fields: &[&str]| Some(fields.iter().map(|f| f.to_string()).collect::<Row>());
+    assert_eq!(
+        parse("1,Heat (1995),Action", ','),
+        row(&["1", "Heat (1995)", "Action"])
+    );
+    assert_eq!(
+        parse("11,\"American President, The (1995)\",Comedy\r\n", ','),
+        row(&["11", "American President, The (1995)", "Comedy"])
+    );
+    assert_eq!(
+        parse("1;\"say \"\"hi\"\"\";", ';'),
+        row(&["1", "say \"hi\"", ""])
+    );
+    assert_eq!(parse("1,\"never closed", ','), None);
+}
+
+#[test]
+fn test_copy() {
+    use crate::fs::{Heap, HeapFile};
+    use crate::transaction::{self, Transaction};
+
+    crate::data::test_dir("test_copy");
+    let table = Table::heap("test_copy", &[("movieId", "INTEGER"), ("title", "TEXT")]);
+
+    let mut csv = String::from("movieId;title\n");
+    for i in 0..1000 {
+        csv += &format!("{i};\"Movie {i}; the sequel\"\n");
+    }
+    // not a number, too many fields and not closed
+    csv += "one;Heat\n2;Heat;Action\n3;\"Heat\n";
+    let path = crate::data::path("test_copy.csv");
+    std::fs::write(&path, csv).unwrap();
+
+    let options = Options::from(&["HEADER".to_owned(), "DELIMITER".into(), ";".into()][..]);
+    let copied = transaction::autocommit(None, |xid| {
+        copy(&table, &path, &options, xid, &Indexes::default())
+    })
+    .unwrap();
+    assert_eq!(copied.rows, 1000);
+    assert_eq!(copied.rejected, 3);
+    assert!(copied.pages > 1, "{copied:?}");
+
+    // starting over the empty page the table was created with
+    let rows: Vec<Row> = HeapFile::open(&table.name, 0)
+        .unwrap()
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(rows.len(), 1000);
+    assert_eq!(rows[0], vec!["0", "Movie 0; the sequel"]);
+    assert_eq!(rows[999], vec!["999", "Movie 999; the sequel"]);
+    let heap = HeapFile::open(&table.name, 0).unwrap();
+    assert_eq!(heap.pages(), copied.pages);
+    drop(heap);
+
+    // rolled back, so they aren't there
+    let transaction = Transaction::begin();
+    copy(
+        &table,
+        &path,
+        &options,
+        transaction.xid,
+        &Indexes::default(),
+    )
+    .unwrap();
+    transaction.rollback().unwrap();
+    let heap = HeapFile::open(&table.name, 0).unwrap();
+    assert_eq!(heap.pages(), 2 * copied.pages);
+    assert_eq!(heap.into_iter().count(), 1000);
+
+    // a crash before it commits, the rows still aren't there once
+    // another transaction commits
+    let transaction = Transaction::begin();
+    copy(
+        &table,
+        &path,
+        &options,
+        transaction.xid,
+        &Indexes::default(),
+    )
+    .unwrap();
+    crate::buffer::crash();
+    crate::wal::crash();
+    crate::xact::crash();
+    crate::recovery::recover().unwrap();
+    let other = Transaction::begin();
+    assert_ne!(other.xid, transaction.xid);
+    other.commit().unwrap();
+    let heap = HeapFile::open(&table.name, 0).unwrap();
+    assert_eq!(heap.into_iter().count(), 1000);
+}
@@ -0,0 +1,77 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+thread_local! {
+    // ./data unless it's changed, every test uses its own
+    static DIR: RefCell<String> = RefCell::new("./data".to_owned());
+}
+
+/// Where the heap files, the log (see wal) and the transaction
+/// statuses (see xact) are, for everything this thread does.
+pub fn dir() -> String {
+    DIR.with(|dir| dir.borrow().clone())
+}
+
+/// From now on this thread uses `dir` instead, it has to be there.
+pub fn use_dir(dir: &str) {
+    DIR.with(|current| *current.borrow_mut() = dir.to_owned());
+}
+
+/// The file (or folder) `name` in the data directory.
+pub fn path(name: &str) -> String {
+    format!("{}/{name}", dir())
+}
+
+/// One of something (like the log) for each data directory, every
+/// thread that uses the directory uses the same one.
+pub struct Shared<T> {
+    all: Mutex<BTreeMap<String, Arc<Mutex<T>>>>,
+    // the first time it's needed in a directory
+    open: fn(&str) -> T,
+}
+
+impl<T> Shared<T> {
+    pub const fn new(open: fn(&str) -> T) -> Self {
+        Self {
+            all: Mutex::new(BTreeMap::new()),
+            open,
+        }
+    }
+
+    /// Runs `f` with the one of this thread's data directory.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let one = self
+            .all
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(dir())
+            .or_insert_with_key(|dir| Arc::new(Mutex::new((self.open)(dir))))
+            .clone();
+        // a panic while holding it doesn't leave it unusable
+        let mut one = one.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut one)
+    }
+
+    /// Drops the one of this thread's data directory without writing
+    /// anything (like a crash), the next `with` opens it again.
+    #[cfg(test)]
+    pub(crate) fn forget(&self) {
+        self.all
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&dir());
+    }
+}
+
+/// An empty data directory of its own for the test, this thread
+/// uses it from now on. Returns where it is.
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join("daigrass").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let dir = dir.to_str().unwrap().to_owned();
+    use_dir(&dir);
+    dir
+}
@@ -0,0 +1,176 @@
+use std::io;
+
+use crate::catalog::{Storage, Table};
+use crate::fs::{Heap, HeapFile};
+use crate::index::Indexes;
+use crate::source::{holds, Row};
+use crate::wal::Xid;
+
+// the CSVs are read only, the changes are part of the transaction
+fn open(table: &Table, xid: Xid) -> Result<HeapFile, io::Error> {
+    assert_eq!(
+        table.storage,
+        Storage::Heap,
+        "'{}' is a CSV table, only heap tables can be changed",
+        table.name
+    );
+    let mut heap = HeapFile::open(&table.name, 0)?;
+    heap.set_xid(xid);
+    Ok(heap)
+}
+
+fn check(table: &Table, row: &Row) {
+    assert_eq!(
+        row.len(),
+        table.columns.len(),
+        "'{}' has {} columns, got {} values",
+        table.name,
+        table.columns.len(),
+        row.len()
+    );
+    for (column, value) in table.columns.iter().zip(row) {
+        assert!(
+            column.accepts(value),
+            "'{value}' isn't a valid {} for column '{}'",
+            column.data_type,
+            column.name
+        );
+    }
+}
+
+// the page and slot of every row where the condition holds, all of them without one
+fn matching(
+    table: &Table,
+    heap: &mut HeapFile,
+    condition: Option<&[String]>,
+) -> Result<Vec<(usize, usize, Row)>, io::Error> {
+    let schema = table.schema();
+    let idx = condition.map(|c| {
+        schema
+            .position(&c[0])
+            .unwrap_or_else(|| panic!("'{}' field not found in table '{}'", c[0], table.name))
+    });
+    let mut found = vec![];
+    for page in 0..heap.pages() {
+        for slot in 0..heap.slots_in(page) {
+            let Some(row) = heap.get_slot(page, slot)? else {
+                continue;
+            };
+            if condition.zip(idx).is_none_or(|(c, i)| holds(&row[i], c)) {
+                found.push((page, slot, row));
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Appends the rows, nothing is written unless all of them are valid.
+/// The table's indexes point at them too.
+pub fn insert(
+    table: &Table,
+    rows: Vec<Row>,
+    xid: Xid,
+    indexes: &Indexes,
+) -> Result<usize, io::Error> {
+    for row in &rows {
+        check(table, row);
+    }
+    let schema = table.schema();
+    let mut heap = open(table, xid)?;
+    for row in &rows {
+        heap.insert(row)?;
+        indexes.add(&schema, row, heap.last());
+    }
+    Ok(rows.len())
+}
+
+/// Sets the fields of the rows where the condition holds, each
+/// updated row is deleted and inserted again (like Postgres does),
+/// so the indexes get the new version too.
+pub fn update(
+    table: &Table,
+    assignments: &[(String, String)],
+    condition: Option<&[String]>,
+    xid: Xid,
+    indexes: &Indexes,
+) -> Result<usize, io::Error> {
+    let schema = table.schema();
+    let assignments: Vec<(usize, &String)> = assignments
+        .iter()
+        .map(|(field, value)| {
+            let i = schema
+                .position(field)
+                .unwrap_or_else(|| panic!("'{field}' field not found in table '{}'", table.name));
+            (i, value)
+        })
+        .collect();
+    let mut heap = open(table, xid)?;
+    // all of them before changing anything, so the new versions aren't updated again
+    let mut rows = matching(table, &mut heap, condition)?;
+    for (_, _, row) in rows.iter_mut() {
+        for (i, value) in &assignments {
+            row[*i] = value.to_string();
+        }
+        check(table, row);
+    }
+    for (page, slot, row) in &rows {
+        heap.delete_slot(*page, *slot)?;
+        heap.insert(row)?;
+        indexes.add(&schema, row, heap.last());
+    }
+    Ok(rows.len())
+}
+
+pub fn delete(table: &Table, condition: Option<&[String]>, xid: Xid) -> Result<usize, io::Error> {
+    let mut heap = open(table, xid)?;
+    let rows = matching(table, &mut heap, condition)?;
+    for (page, slot, _) in &rows {
+        heap.delete_slot(*page, *slot)?;
+    }
+    Ok(rows.len())
+}
+
+#[test]
+fn test_insert_update_delete() {
+    crate::data::test_dir("test_insert_update_delete");
+    let table = Table::heap("test_dml", &[("movieId", "INTEGER"), ("note", "TEXT")]);
+    let rows = |rows: &[(&str, &str)]| -> Vec<Row> {
+        rows.iter()
+            .map(|(m, n)| vec![m.to_string(), n.to_string()])
+            .collect()
+    };
+    let read = || -> Vec<Row> {
+        let heap = HeapFile::open(&table.name, 0).unwrap();
+        heap.into_iter().map(Result::unwrap).collect()
+    };
+
+    let inserted = rows(&[("1", "good"), ("2", "meh"), ("3", "")]);
+    assert_eq!(
+        insert(&table, inserted.clone(), 0, &Indexes::default()).unwrap(),
+        3
+    );
+    assert_eq!(read(), inserted);
+
+    let meh = ["movieId".to_owned(), "GREATER".into(), "1".into()];
+    let set = [("note".to_owned(), "bad".to_owned())];
+    assert_eq!(
+        update(&table, &set, Some(&meh), 0, &Indexes::default()).unwrap(),
+        2
+    );
+    // the new versions end up after the rest
+    assert_eq!(read(), rows(&[("1", "good"), ("2", "bad"), ("3", "bad")]));
+
+    let good = ["note".to_owned(), "EQUALS".into(), "good".into()];
+    assert_eq!(delete(&table, Some(&good), 0).unwrap(), 1);
+    assert_eq!(read(), rows(&[("2", "bad"), ("3", "bad")]));
+    assert_eq!(delete(&table, None, 0).unwrap(), 2);
+    assert!(read().is_empty());
+}
+
+#[test]
+#[should_panic(expected = "'one' isn't a valid INTEGER for column 'movieId'")]
+fn test_insert_checks_types() {
+    crate::data::test_dir("test_insert_checks_types");
+    let table = Table::heap("test_dml_types", &[("movieId", "INTEGER")]);
+    insert(&table, vec![vec!["one".into()]], 0, &Indexes::default()).unwrap();
+}
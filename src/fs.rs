@@ -2,7 +2,13 @@ use std::fs::{File, OpenOptions};
 use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
+use crate::buffer::{self, PageId};
+use crate::data;
+use crate::recovery;
 use crate::source::Row;
+use crate::transaction::{self, Snapshot};
+use crate::wal::{self, Lsn, Record, Xid};
+use crate::xact::{self, Status};
 
 pub fn buf_reader<P>(filename: P) -> io::Result<io::BufReader<File>>
 where
@@ -19,7 +25,13 @@ where
     buf_reader(filename).map(|b| b.lines())
 }
 
-trait Heap {
+/// How many pages (8192 bytes each) the table's heap file has.
+pub fn pages(table: &str) -> Result<usize, io::Error> {
+    let len = std::fs::metadata(data::path(table))?.len();
+    Ok(len.div_ceil(8192) as usize)
+}
+
+pub trait Heap {
     fn create(table: &str, offset: u64) -> Result<Self, io::Error>
     where
         Self: Sized;
@@ -28,17 +40,70 @@ trait Heap {
         Self: Sized;
     fn insert(&mut self, row: &Row) -> Result<(), io::Error>;
     fn get(&mut self, n: usize) -> Result<Option<Row>, io::Error>;
+    // false if there was nothing there (or it was already deleted)
+    fn delete(&mut self, n: usize) -> Result<bool, io::Error>;
+}
+
+// set in the line pointer of a tuple deleted without a transaction,
+// the tuple itself stays where it was (until there's some sort of vacuum)
+const DEAD: u16 = 0x8000;
+
+// every tuple starts with the transaction that inserted it (xmin)
+// and the one that deleted it (xmax, 0 until then), the columns
+// come after
+const XMIN: usize = 0;
+const XMAX: usize = 8;
+const TUPLE_HEADER: usize = 16;
+
+pub(crate) const PAGE: usize = 8192;
+
+// the LSN of the last change (see wal::Lsn), ptr_lower and ptr_upper
+const HEADER: u16 = 12;
+const LOWER: usize = 8;
+const UPPER: usize = 10;
+
+/// The LSN every page starts with, 0 if it was never written.
+pub(crate) fn page_lsn(page: &[u8]) -> Lsn {
+    Lsn::from_be_bytes(page[0..8].try_into().unwrap())
+}
+
+pub(crate) fn set_page_lsn(page: &mut [u8], lsn: Lsn) {
+    page[0..8].copy_from_slice(&lsn.to_be_bytes());
 }
 
+/// Where a row of a heap table is, it stays there until the row is
+/// deleted (an update is a delete and an insert, so it moves).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tid {
+    pub page: usize,
+    pub slot: usize,
+}
+
+/// Every page of a heap file from `offset` on, `n` in `get` and
+/// `delete` counts the slots of all of them (first page first).
+/// The pages are read and changed in the buffer pool (see
+/// buffer::BufferPool), the changed ones are written when it's dropped.
+/// Only the rows its snapshot sees are there (see
+/// transaction::Snapshot), the one in use when it was opened.
 pub struct HeapFile {
     heap: Vec<HeapBlock>,
+    // the last one, where the rows are inserted
     n: usize,
+    // where the last row inserted went
+    last: Tid,
+    offset: u64,
     table: String,
+    // the transaction its changes are part of
+    xid: Xid,
+    snapshot: Snapshot,
+    // logged something since the last commit
+    changed: bool,
 }
 
 pub struct HeapFileIterator {
+    heap: HeapFile,
     block: usize,
-    heap: Vec<HeapBlockIterator>,
+    slot: usize,
 }
 
 impl IntoIterator for HeapFile {
@@ -46,13 +111,10 @@ impl IntoIterator for HeapFile {
     type IntoIter = HeapFileIterator;
 
     fn into_iter(self) -> Self::IntoIter {
-        let mut iters = vec![];
-        for heap in self.heap {
-            iters.push(heap.into_iter());
-        }
         Self::IntoIter {
-            heap: iters,
+            heap: self,
             block: 0,
+            slot: 0,
         }
     }
 }
@@ -60,200 +122,391 @@ impl IntoIterator for HeapFile {
 impl Iterator for HeapFileIterator {
     type Item = Result<Row, io::Error>;
     fn next(&mut self) -> Option<Self::Item> {
-        match self.heap[self.block].next() {
-            Some(row) => Some(row),
-            None => {
-                let next_heap = self.heap.get_mut(self.block + 1)?;
+        // skipping the deleted ones
+        loop {
+            if self.slot >= self.heap.heap.get(self.block)?.slots() {
                 self.block += 1;
-                next_heap.next()
-            },
+                self.slot = 0;
+                continue;
+            }
+            let row = self.heap.get_slot(self.block, self.slot).transpose();
+            self.slot += 1;
+            if row.is_some() {
+                return row;
+            }
         }
     }
 }
 
 impl HeapFile {
+    /// Deletes the whole file, fine if there wasn't one.
+    pub fn remove(table: &str) -> Result<(), io::Error> {
+        let record = Record::Remove {
+            table: table.to_owned(),
+        };
+        wal::with(|wal| wal.log(&record))?;
+        buffer::with(|pool| pool.forget(table));
+        match std::fs::remove_file(data::path(table)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Commits what it changed (the log is synced) and writes the
+    /// pages, dropping it does it too. Unless it's part of a
+    /// transaction, that commits on its own.
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        if self.changed && self.xid == 0 {
+            wal::with(|wal| wal.flush(wal.lsn()))?;
+            self.changed = false;
+        }
+        buffer::with(|pool| pool.flush(&self.table))
+    }
+
+    /// Makes what it changes part of the transaction (see
+    /// transaction::Transaction), it sees those changes too.
+    pub fn set_xid(&mut self, xid: Xid) {
+        self.xid = xid;
+        self.snapshot.xid = xid;
+    }
+
     pub fn ptr_lower(&self) -> u16 {
-        self.heap[self.n].ptr_lower
+        self.heap[self.n].ptrs().0
     }
     pub fn ptr_upper(&self) -> u16 {
-        self.heap[self.n].ptr_upper
+        self.heap[self.n].ptrs().1
     }
     pub fn free_space(&self) -> u16 {
-        self.heap[self.n].free_space
+        let (lower, upper) = self.heap[self.n].ptrs();
+        upper - lower
+    }
+    pub fn pages(&self) -> usize {
+        self.heap.len()
+    }
+    /// Line pointers in every page, deleted tuples included.
+    pub fn slots(&self) -> usize {
+        self.heap.iter().map(HeapBlock::slots).sum()
+    }
+    /// Line pointers in the page, deleted tuples included.
+    pub fn slots_in(&self, page: usize) -> usize {
+        self.heap.get(page).map_or(0, HeapBlock::slots)
+    }
+    /// Where the last row inserted went (the page counted from the offset).
+    pub fn last(&self) -> Tid {
+        self.last
+    }
+    // the page and slot of the n-th slot of the file
+    fn address(&self, mut n: usize) -> Option<(usize, usize)> {
+        for (page, block) in self.heap.iter().enumerate() {
+            if n < block.slots() {
+                return Some((page, n));
+            }
+            n -= block.slots();
+        }
+        None
+    }
+    /// The row at `slot` in `page` (pages counted from the offset
+    /// the file was opened at), None if it's deleted, the snapshot
+    /// doesn't see it or it isn't there.
+    pub fn get_slot(&mut self, page: usize, slot: usize) -> Result<Option<Row>, io::Error> {
+        match self.heap.get(page) {
+            Some(block) => block.get(slot, &self.snapshot),
+            None => Ok(None),
+        }
+    }
+    /// Same as get_slot.
+    pub fn fetch(&mut self, tid: Tid) -> Result<Option<Row>, io::Error> {
+        self.get_slot(tid.page, tid.slot)
+    }
+    /// In a transaction, the row is still there for the others
+    /// until it commits. An error if another transaction deleted
+    /// it, and didn't roll back.
+    pub fn delete_slot(&mut self, page: usize, slot: usize) -> Result<bool, io::Error> {
+        self.changed = true;
+        match self.heap.get(page) {
+            Some(block) => block.delete(slot, self.xid),
+            None => Ok(false),
+        }
+    }
+    fn page(&self, page: usize) -> PageId {
+        PageId::new(&self.table, self.offset as usize / PAGE + page)
+    }
+}
+
+impl Drop for HeapFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            eprintln!("couldn't write the pages of '{}': {err}", self.table);
+        }
     }
 }
 
 impl Heap for HeapFile {
     fn insert(&mut self, row: &Row) -> Result<(), io::Error> {
-        match self.heap[self.n].insert(row) {
-            Ok(u) => Ok(u),
+        self.changed = true;
+        let slot = match self.heap[self.n].insert(row, self.xid) {
+            Ok(slot) => slot,
             Err(err) if err.kind() == io::ErrorKind::OutOfMemory => {
+                // a long load doesn't keep all of the log around
+                if wal::with(|wal| wal.due()) {
+                    recovery::checkpoint()?;
+                }
                 let next = self.n + 1;
-                let offset = 8192 * next as u64;
-                self.heap.push(HeapBlock::create(&self.table, offset)?);
+                self.heap.push(HeapBlock::create(self.page(next))?);
                 self.n = next;
-                self.heap[self.n].insert(row)
+                self.heap[self.n].insert(row, self.xid)?
             },
-            Err(err) => Err(err),
-        }
+            Err(err) => return Err(err),
+        };
+        self.last = Tid { page: self.n, slot };
+        Ok(())
     }
     fn get(&mut self, n: usize) -> Result<Option<Row>, io::Error> {
-        // FIXME: we should consider a different n
-        // for the other blocks
-        self.heap[self.n].get(n)
+        match self.address(n) {
+            Some((page, slot)) => self.get_slot(page, slot),
+            None => Ok(None),
+        }
+    }
+    fn delete(&mut self, n: usize) -> Result<bool, io::Error> {
+        match self.address(n) {
+            Some((page, slot)) => self.delete_slot(page, slot),
+            None => Ok(false),
+        }
     }
     fn open(table: &str, offset: u64) -> Result<Self, io::Error>
     where
         Self: Sized,
     {
-        Ok(Self {
-            heap: vec![Heap::open(table, offset)?],
-            table: table.to_owned(),
+        // new pages are written right away, so the length is right
+        let len = std::fs::metadata(data::path(table))?.len();
+        let mut heap = Self {
+            heap: vec![],
             n: 0,
-        })
+            last: Tid { page: 0, slot: 0 },
+            offset,
+            table: table.to_owned(),
+            xid: 0,
+            snapshot: transaction::snapshot(),
+            changed: false,
+        };
+        // every page until the end of the file, there's always one
+        let pages = (len.saturating_sub(offset).div_ceil(PAGE as u64) as usize).max(1);
+        for page in 0..pages {
+            heap.heap.push(HeapBlock::open(heap.page(page))?);
+        }
+        heap.n = pages - 1;
+        Ok(heap)
     }
     fn create(table: &str, offset: u64) -> Result<Self, io::Error>
     where
         Self: Sized,
     {
-        Ok(Self {
-            heap: vec![Heap::create(table, offset)?],
+        // the pages it had aren't there anymore
+        buffer::with(|pool| {
+            pool.flush(table)?;
+            pool.forget(table);
+            Ok::<_, io::Error>(())
+        })?;
+        let record = Record::Create {
             table: table.to_owned(),
+        };
+        wal::with(|wal| wal.log(&record))?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // the pages before the offset stay
+            .truncate(false)
+            .open(data::path(table))?;
+        // whatever was after it isn't part of the heap anymore
+        file.set_len(offset + PAGE as u64)?;
+        let mut heap = Self {
+            heap: vec![],
             n: 0,
-        })
+            last: Tid { page: 0, slot: 0 },
+            offset,
+            table: table.to_owned(),
+            xid: 0,
+            snapshot: transaction::snapshot(),
+            changed: true,
+        };
+        heap.heap.push(HeapBlock::create(heap.page(0))?);
+        Ok(heap)
     }
 }
 
-struct HeapBlock {
-    ptr_lower: u16,
-    ptr_upper: u16,
-    free_space: u16,
-    // this determines which block in the file we're looking at
-    // each block has 8192 bytes, so to access page 3, you just
-    // need to multiply those
-    offset: u64,
-    // TODO: convert those two into one structure
-    writer: io::BufWriter<File>,
-    reader: io::BufReader<File>,
+fn read_u16(page: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([page[at], page[at + 1]])
 }
 
-impl Heap for HeapBlock {
-    fn create(table: &str, offset: u64) -> Result<Self, io::Error> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            // maybe change folder if cfg(test)?
-            .open(format!("./data/{table}"))?;
+fn write_u16(page: &mut [u8], at: usize, value: u16) {
+    page[at..at + 2].copy_from_slice(&value.to_be_bytes());
+}
 
-        file.seek(SeekFrom::Start(offset))?;
+// the changes the log has records of, to the bytes of a page
+// (also how recovery redoes them)
 
-        // 2 (16 bits) * 2
-        let ptr_lower: u16 = 4;
-        // end of block
-        let ptr_upper: u16 = 8192;
-        file.write_all(&ptr_lower.to_be_bytes())?;
-        file.write_all(&ptr_upper.to_be_bytes())?;
+pub(crate) fn init(page: &mut [u8], lsn: Lsn) {
+    page.fill(0);
+    set_page_lsn(page, lsn);
+    write_u16(page, LOWER, HEADER);
+    write_u16(page, UPPER, PAGE as u16);
+}
 
-        // fill whole block
-        file.write_all(&[0; 8192 - 4])?;
+// the tuple goes right before the last one, its line pointer after the others
+pub(crate) fn put(page: &mut [u8], tuple: &[u8]) {
+    let lower = read_u16(page, LOWER);
+    let upper = (read_u16(page, UPPER) as usize - tuple.len() - 2) as u16;
+    write_u16(page, upper as usize, tuple.len() as u16);
+    page[upper as usize + 2..upper as usize + 2 + tuple.len()].copy_from_slice(tuple);
+    write_u16(page, lower as usize, upper);
+    write_u16(page, LOWER, lower + 2);
+    write_u16(page, UPPER, upper);
+}
 
-        let used = 2 + 2;
+/// Line pointers in the page, 0 if it was never initialized.
+pub(crate) fn slots(page: &[u8]) -> usize {
+    (read_u16(page, LOWER).max(HEADER) - HEADER) as usize / 2
+}
 
-        Ok(Self {
-            ptr_lower,
-            ptr_upper,
-            free_space: ptr_upper - used,
-            // first block on ::create
-            offset: 0,
-            writer: io::BufWriter::new(file.try_clone()?),
-            reader: io::BufReader::new(file),
-        })
-    }
+pub(crate) fn kill(page: &mut [u8], slot: usize) {
+    let at = HEADER as usize + 2 * slot;
+    let line_ptr = read_u16(page, at);
+    write_u16(page, at, line_ptr | DEAD);
+}
 
-    /// TODO: deprecate
-    fn open(table: &str, offset: u64) -> Result<Self, io::Error> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(format!("./data/{table}"))?;
+// where the tuple's xmax is
+fn xmax_at(page: &[u8], slot: usize) -> usize {
+    let line_ptr = read_u16(page, HEADER as usize + 2 * slot) & !DEAD;
+    line_ptr as usize + 2 + XMAX
+}
 
-        file.seek(SeekFrom::Start(offset))?;
+pub(crate) fn xmax(page: &[u8], slot: usize) -> Xid {
+    let at = xmax_at(page, slot);
+    Xid::from_be_bytes(page[at..at + 8].try_into().unwrap())
+}
 
-        let mut ptr_lower = [0; 2];
-        file.read_exact(&mut ptr_lower)?;
+pub(crate) fn set_xmax(page: &mut [u8], slot: usize, xid: Xid) {
+    let at = xmax_at(page, slot);
+    page[at..at + 8].copy_from_slice(&xid.to_be_bytes());
+}
 
-        let mut ptr_upper = [0; 2];
-        file.read_exact(&mut ptr_upper)?;
+struct HeapBlock {
+    // which page of which file, the bytes are in the buffer pool (so
+    // is how full it is, another handle on the table can change it)
+    page: PageId,
+}
 
-        let ptr_lower = u16::from_be_bytes(ptr_lower);
-        let ptr_upper = u16::from_be_bytes(ptr_upper);
+impl HeapBlock {
+    fn create(page: PageId) -> Result<Self, io::Error> {
+        buffer::with(|pool| {
+            let frame = pool.allocate(&page)?;
+            let lsn = wal::with(|wal| wal.append(&Record::Init { page: page.clone() }))?;
+            init(pool.page_mut(frame), lsn);
+            pool.unpin(frame, true);
+            Ok::<_, io::Error>(())
+        })?;
+        Ok(Self { page })
+    }
 
-        let ptrs = (ptr_lower - 4) / 2;
-        let tuples = 8192 - ptr_upper;
-        let used = 2 + 2 + ptrs + tuples;
+    fn open(page: PageId) -> Result<Self, io::Error> {
+        buffer::with(|pool| {
+            let frame = pool.pin(&page)?;
+            // all zeroes, it was added but never written (a crash)
+            let empty = read_u16(pool.page(frame), LOWER) == 0;
+            if empty {
+                let lsn = wal::with(|wal| wal.append(&Record::Init { page: page.clone() }))?;
+                init(pool.page_mut(frame), lsn);
+            }
+            pool.unpin(frame, empty);
+            Ok::<_, io::Error>(())
+        })?;
+        Ok(Self { page })
+    }
 
-        Ok(Self {
-            ptr_lower,
-            ptr_upper,
-            free_space: 8192 - used,
-            offset,
-            writer: io::BufWriter::new(file.try_clone()?),
-            reader: io::BufReader::new(file),
+    // ptr_lower and ptr_upper as they are now
+    fn ptrs(&self) -> (u16, u16) {
+        buffer::with(|pool| {
+            let frame = pool.pin(&self.page)?;
+            let data = pool.page(frame);
+            let ptrs = (read_u16(data, LOWER), read_u16(data, UPPER));
+            pool.unpin(frame, false);
+            Ok::<_, io::Error>(ptrs)
         })
+        .unwrap()
     }
 
-    fn insert(&mut self, row: &Row) -> Result<(), io::Error> {
+    // returns its slot
+    fn insert(&self, row: &Row, xid: Xid) -> Result<usize, io::Error> {
         let mut buffer = vec![];
+        buffer.write_all(&xid.to_be_bytes())?;
+        buffer.write_all(&[0; 8])?;
         for column in row {
             buffer.write_all(&(column.len() as u16).to_be_bytes())?;
-            buffer.write_all(&column.as_bytes())?;
+            buffer.write_all(column.as_bytes())?;
         }
 
-        let buffer_len = buffer.len() as u16;
-        if !self.can_insert(buffer_len) {
-            return Err(io::Error::new(
-                io::ErrorKind::OutOfMemory,
-                "no more space in heap file",
-            ));
-        }
-
-        let new_upper = self.ptr_upper - buffer_len - 2;
-        // maybe use SeekFrom::End and set ptr_upper to the result of .seek()
-        // though in the future multiple pages might complicate things
-        self.writer.seek(SeekFrom::Start((new_upper) as u64 + self.offset))?;
-        self.writer.write_all(&buffer_len.to_be_bytes())?;
-        self.writer.write_all(&buffer)?;
-        self.update_ptrs(new_upper)?;
-        self.free_space -= 2 + buffer_len;
-        // we'll see if we should keep this
-        self.writer.flush()?;
-        Ok(())
+        buffer::with(|pool| {
+            let frame = pool.pin(&self.page)?;
+            let data = pool.page(frame);
+            // between the line pointers and the tuples, for the tuple,
+            // its length and its line pointer
+            let free_space = read_u16(data, UPPER) - read_u16(data, LOWER);
+            if buffer.len() + 2 + 2 > free_space as usize {
+                pool.unpin(frame, false);
+                return Err(io::Error::new(
+                    io::ErrorKind::OutOfMemory,
+                    "no more space in heap file",
+                ));
+            }
+            let slot = slots(data);
+            let record = Record::Insert {
+                page: self.page.clone(),
+                xid,
+                slot: slot as u16,
+                tuple: buffer.clone(),
+            };
+            let lsn = wal::with(|wal| wal.append(&record))?;
+            let data = pool.page_mut(frame);
+            put(data, &buffer);
+            set_page_lsn(data, lsn);
+            pool.unpin(frame, true);
+            Ok(slot)
+        })
     }
 
     // starts at 0
-    // needs to be mut because of the underlying file buffers (maybe FIXME?)
-    fn get(&mut self, n: usize) -> Result<Option<Row>, io::Error> {
-        let offset = 4 + 2 * n;
-        self.reader.seek(SeekFrom::Start(offset as u64 + self.offset))?;
-        let mut line_ptr = [0; 2];
-        self.reader.read_exact(&mut line_ptr)?;
-        let line_ptr = u16::from_be_bytes(line_ptr);
-        // we wrote all zeroes previously
-        if line_ptr == 0 {
+    fn get(&self, n: usize, snapshot: &Snapshot) -> Result<Option<Row>, io::Error> {
+        let raw_row = buffer::with(|pool| {
+            let frame = pool.pin(&self.page)?;
+            let data = pool.page(frame);
+            let line_ptr = match n < slots(data) {
+                true => read_u16(data, HEADER as usize + 2 * n),
+                false => 0,
+            };
+            // we wrote all zeroes previously
+            let raw_row = if line_ptr == 0 || line_ptr & DEAD != 0 {
+                None
+            } else {
+                // we can read up until this
+                let tuple_size = read_u16(data, line_ptr as usize) as usize;
+                let start = line_ptr as usize + 2;
+                Some(data[start..start + tuple_size].to_vec())
+            };
+            pool.unpin(frame, false);
+            Ok::<_, io::Error>(raw_row)
+        })?;
+        let Some(raw_row) = raw_row else {
+            return Ok(None);
+        };
+        let xid = |at: usize| Xid::from_be_bytes(raw_row[at..at + 8].try_into().unwrap());
+        if !snapshot.visible(xid(XMIN), xid(XMAX)) {
             return Ok(None);
         }
-        self.reader.seek(SeekFrom::Start(line_ptr as u64 + self.offset))?;
-        // we can read up until this
-        let mut tuple_size = [0; 2];
-        self.reader.read_exact(&mut tuple_size)?;
-        let tuple_size = u16::from_be_bytes(tuple_size);
-        let mut raw_row = vec![0; tuple_size as usize];
-        self.reader.read_exact(&mut raw_row)?;
         let mut row = vec![];
-        let mut curr = 0usize;
-        while curr < tuple_size as usize {
-            let field_len = u16::from_be_bytes(raw_row[curr..curr + 2].try_into().unwrap());
+        let mut curr = TUPLE_HEADER;
+        while curr < raw_row.len() {
+            let field_len = read_u16(&raw_row, curr);
             // TODO: maybe there's a way to leverage ptr & unsafe (from_raw_parts)
             // to avoid the .to_vec() allocation
             let field =
@@ -264,76 +517,190 @@ impl Heap for HeapBlock {
         }
         Ok(Some(row))
     }
-}
 
-impl HeapBlock {
-    // header & line ptrs shenanigans
-    fn update_ptrs(&mut self, new_upper: u16) -> Result<(), io::Error> {
-        // let's write the header
-        self.writer.seek(SeekFrom::Start(0 + self.offset))?;
-        // new line ptr
-        self.writer.write_all(&(self.ptr_lower + 2).to_be_bytes())?;
-        self.writer.write_all(&new_upper.to_be_bytes())?;
-        self.writer.seek(SeekFrom::Start(self.ptr_lower as u64 + self.offset))?;
-        // update local
-        self.ptr_upper = new_upper;
-        self.ptr_lower += 2;
-        // write new line ptr
-        self.writer.write_all(&new_upper.to_be_bytes())?;
-        Ok(())
+    // sets its xmax, or its line pointer as dead without a transaction
+    fn delete(&self, n: usize, xid: Xid) -> Result<bool, io::Error> {
+        buffer::with(|pool| {
+            let frame = pool.pin(&self.page)?;
+            let data = pool.page(frame);
+            if n >= slots(data) {
+                pool.unpin(frame, false);
+                return Ok(false);
+            }
+            let dead = read_u16(data, HEADER as usize + 2 * n) & DEAD != 0;
+            let other = xmax(data, n);
+            // the first one to delete it wins, the other one fails
+            let taken = other != 0 && other != xid && xact::status(other) != Status::Aborted;
+            if !dead && xid != 0 && taken {
+                pool.unpin(frame, false);
+                return Err(io::Error::other(format!(
+                    "could not serialize access to slot {n} of {:?}, \
+                     transaction {other} deleted (or updated) it",
+                    self.page
+                )));
+            }
+            let deleted = !dead && (xid == 0 || other != xid);
+            if deleted {
+                let record = Record::Delete {
+                    page: self.page.clone(),
+                    xid,
+                    slot: n as u16,
+                };
+                let lsn = wal::with(|wal| wal.append(&record))?;
+                let data = pool.page_mut(frame);
+                match xid {
+                    0 => kill(data, n),
+                    _ => set_xmax(data, n, xid),
+                }
+                set_page_lsn(data, lsn);
+            }
+            pool.unpin(frame, deleted);
+            Ok(deleted)
+        })
     }
 
-    fn can_insert(&self, buffer_len: u16) -> bool {
-        if buffer_len + 2 > self.free_space {
-            false
-        } else {
-            true
-        }
+    fn slots(&self) -> usize {
+        (self.ptrs().0 - HEADER) as usize / 2
     }
 }
 
-pub struct HeapBlockIterator {
-    n: usize,
-    heap: HeapBlock,
+/// Fills whole pages in memory and writes each one at once at the
+/// end of the file, instead of going through the buffer pool and
+/// the log like insert does. For loading a lot of rows into a table.
+/// The pages aren't logged, so they're only durable once it's
+/// finished (then they're synced). The rows are part of the
+/// transaction, nobody sees them unless it commits.
+pub struct BulkWriter {
+    file: io::BufWriter<File>,
+    xid: Xid,
+    page: Vec<u8>,
+    ptr_lower: u16,
+    ptr_upper: u16,
+    // where the first page goes, for the TIDs
+    first: usize,
+    pages: usize,
 }
 
-impl IntoIterator for HeapBlock {
-    type Item = Result<Row, io::Error>;
-    type IntoIter = HeapBlockIterator;
+impl BulkWriter {
+    pub fn open(table: &str, xid: Xid) -> Result<Self, io::Error> {
+        // it writes behind the pool's back
+        buffer::with(|pool| {
+            pool.flush(table)?;
+            pool.forget(table);
+            Ok::<_, io::Error>(())
+        })?;
+        // the pages don't say who wrote them, a crash would hand the xid out again
+        wal::with(|wal| wal.log(&Record::Load { xid }))?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(data::path(table))?;
+        let len = file.metadata()?.len();
+        let mut start = len.div_ceil(PAGE as u64) * PAGE as u64;
+        // a new table has an empty first page, no need to leave it there
+        if len >= PAGE as u64 {
+            let last = start - PAGE as u64;
+            file.seek(SeekFrom::Start(last))?;
+            let mut header = [0; HEADER as usize];
+            file.read_exact(&mut header)?;
+            if matches!(read_u16(&header, LOWER), 0 | HEADER) {
+                start = last;
+            }
+        }
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            file: io::BufWriter::new(file),
+            xid,
+            page: vec![0; PAGE],
+            ptr_lower: HEADER,
+            ptr_upper: PAGE as u16,
+            first: start as usize / PAGE,
+            pages: 0,
+        })
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        Self::IntoIter { n: 0, heap: self }
+    /// Returns where the row goes.
+    pub fn write(&mut self, row: &Row) -> Result<Tid, io::Error> {
+        // like insert, xmin and then xmax
+        let mut buffer = vec![];
+        buffer.write_all(&self.xid.to_be_bytes())?;
+        buffer.write_all(&[0; 8])?;
+        for column in row {
+            buffer.write_all(&(column.len() as u16).to_be_bytes())?;
+            buffer.write_all(column.as_bytes())?;
+        }
+        // the tuple, its length and its line pointer
+        let needed = buffer.len() + 2 + 2;
+        if needed > PAGE - HEADER as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "row doesn't fit in a page",
+            ));
+        }
+        if self.ptr_lower as usize + needed > self.ptr_upper as usize {
+            self.write_page()?;
+        }
+        let upper = self.ptr_upper as usize - buffer.len() - 2;
+        self.page[upper..upper + 2].copy_from_slice(&(buffer.len() as u16).to_be_bytes());
+        self.page[upper + 2..upper + 2 + buffer.len()].copy_from_slice(&buffer);
+        let lower = self.ptr_lower as usize;
+        self.page[lower..lower + 2].copy_from_slice(&(upper as u16).to_be_bytes());
+        self.ptr_lower += 2;
+        self.ptr_upper = upper as u16;
+        Ok(Tid {
+            page: self.first + self.pages,
+            slot: (lower - HEADER as usize) / 2,
+        })
     }
-}
 
-impl Iterator for HeapBlockIterator {
-    type Item = Result<Row, io::Error>;
-    fn next(&mut self) -> Option<Self::Item> {
-        let row = self.heap.get(self.n).transpose()?;
-        self.n += 1;
-        Some(row)
+    fn write_page(&mut self) -> Result<(), io::Error> {
+        // newer than anything the log has for them, the pages aren't logged
+        let lsn = wal::with(|wal| wal.lsn());
+        set_page_lsn(&mut self.page, lsn);
+        write_u16(&mut self.page, LOWER, self.ptr_lower);
+        write_u16(&mut self.page, UPPER, self.ptr_upper);
+        self.file.write_all(&self.page)?;
+        self.page.fill(0);
+        self.ptr_lower = HEADER;
+        self.ptr_upper = PAGE as u16;
+        self.pages += 1;
+        Ok(())
     }
-}
 
-// the tests below require a data/ folder
+    /// Writes whatever is left, returns how many pages were written.
+    pub fn finish(mut self) -> Result<usize, io::Error> {
+        if self.ptr_lower > HEADER {
+            self.write_page()?;
+        }
+        // the LSN the pages got is in the log too
+        wal::with(|wal| wal.flush(wal.lsn()))?;
+        // synced instead of logged
+        self.file.into_inner()?.sync_all()?;
+        Ok(self.pages)
+    }
+}
 
 #[test]
 fn test_heap_file() {
-    let heap = HeapFile::create("test_movies", 0).unwrap();
+    data::test_dir("test_heap_file");
+    let mut heap = HeapFile::create("test_movies", 0).unwrap();
+    heap.flush().unwrap();
 
-    let expected: [u8; 4] = [0, 4, 32, 0];
-    let mut header = [0; 4];
-    let mut f = File::open("./data/test_movies").unwrap();
+    // the LSN, then ptr_lower and ptr_upper
+    let mut header = [0; 12];
+    let mut f = File::open(data::path("test_movies")).unwrap();
     f.read_exact(&mut header).unwrap();
-    assert_eq!(header, expected);
-    assert_eq!(heap.free_space(), 8188);
+    assert_eq!(header[8..], [0, 12, 32, 0]);
+    let lsn = page_lsn(&header);
+    assert!(lsn > 0);
+    assert_eq!(heap.free_space(), 8180);
 
     let mut heap = HeapFile::open("test_movies", 0).unwrap();
 
-    assert_eq!(heap.ptr_lower(), 4);
+    assert_eq!(heap.ptr_lower(), 12);
     assert_eq!(heap.ptr_upper(), 8192);
     // remains the same with ::open()
-    assert_eq!(heap.free_space(), 8188);
+    assert_eq!(heap.free_space(), 8180);
 
     let movie = vec![
         // - length -  1
@@ -347,9 +714,13 @@ fn test_heap_file() {
         "Animation".into(),
     ];
     heap.insert(&movie).unwrap();
+    // it's only in the buffer pool until then
+    heap.flush().unwrap();
 
     let expected = [
-        0x00, 0x19, // upper length
+        0x00, 0x29, // upper length
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // xmin, no transaction
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // xmax, not deleted
         0x00, 0x01, // length
         0x31, // "1"
         0x00, 0x09, // length
@@ -360,27 +731,32 @@ fn test_heap_file() {
 
     let new_upper = 8192 - expected.len() as u16;
     assert_eq!(heap.ptr_upper(), new_upper);
-    assert_eq!(heap.ptr_lower(), 6);
-    assert_eq!(heap.free_space(), 8192 - 4 - expected.len() as u16);
+    assert_eq!(heap.ptr_lower(), 14);
+    // the line pointer takes 2 bytes too
+    assert_eq!(heap.free_space(), 8192 - 12 - 2 - expected.len() as u16);
 
     f.seek(SeekFrom::Start((8192 - expected.len()) as u64))
         .unwrap();
     // f.seek(SeekFrom::End(-(expected.len() as i64))).unwrap();
-    let mut found = [0; 27];
+    let mut found = [0; 43];
     f.read_exact(&mut found).unwrap();
     assert_eq!(found, expected);
 
-    let expected: [u8; 4] = [0, 6, 0x1f, 0xe5];
-    let mut header = [0; 4];
+    let mut header = [0; 12];
     f.seek(SeekFrom::Start(0)).unwrap();
     f.read_exact(&mut header).unwrap();
-    assert_eq!(header, expected);
+    assert_eq!(header[8..], [0, 14, 0x1f, 0xd5]);
+    // the insert's
+    assert!(page_lsn(&header) > lsn);
+    // and the log made it to disk first
+    assert!(wal::with(|wal| wal.flushed()) >= page_lsn(&header));
 
     assert_eq!(heap.get(0).unwrap(), Some(movie));
 }
 
 #[test]
 fn test_heap_file_iterator() {
+    data::test_dir("test_heap_file_iterator");
     let mut heap = HeapFile::create("test_it", 0).unwrap();
 
     let movies = vec![
@@ -412,7 +788,7 @@ fn test_heap_file_iterator() {
     ];
 
     for movie in &movies {
-        heap.insert(&movie).unwrap();
+        heap.insert(movie).unwrap();
     }
 
     assert_eq!(
@@ -421,48 +797,120 @@ fn test_heap_file_iterator() {
     );
 }
 
-// FIXME: this test no longer is needed
-// now we need to check that iteration/retrieval is working properly
-// with multiple pages
 #[test]
-#[should_panic(expected = "no more space in heap file")]
-fn test_heap_full() {
-    let movie = vec![
-        "1".into(),
-        "Toy Story (1995)".into(),
-        "Adventure|Animation|Children|Comedy|Fantasy".into(),
-    ];
+fn test_heap_pages() {
+    data::test_dir("test_heap_pages");
+    let movie = |i: usize| {
+        vec![
+            format!("{i:05}"),
+            "Toy Story (1995)".into(),
+            "Adventure|Animation|Children|Comedy|Fantasy".into(),
+        ]
+    };
+    // 90 bytes each with the header and the line pointer, 90 fit in a page, so a few hundred pages
+    let mut heap = HeapFile::create("test_pages", 0).unwrap();
+    for i in 0..30_000 {
+        heap.insert(&movie(i)).unwrap();
+    }
+    assert_eq!(heap.pages(), 334);
+
+    let mut heap = HeapFile::open("test_pages", 0).unwrap();
+    assert_eq!(heap.pages(), 334);
+    assert_eq!(heap.slots(), 30_000);
+    assert_eq!(heap.slots_in(333), 30);
+    assert_eq!(heap.get(0).unwrap(), Some(movie(0)));
+    assert_eq!(heap.get(12_345).unwrap(), Some(movie(12_345)));
+    assert_eq!(heap.get_slot(200, 7).unwrap(), Some(movie(200 * 90 + 7)));
+    assert_eq!(heap.get(30_000).unwrap(), None);
+
+    assert!(heap.delete(29_999).unwrap());
+    assert!(!heap.delete(29_999).unwrap());
+    assert!(heap.delete_slot(100, 0).unwrap());
+    // and it keeps going where it was
+    heap.insert(&movie(30_000)).unwrap();
+
+    let rows: Vec<Row> = HeapFile::open("test_pages", 0)
+        .unwrap()
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    let expected: Vec<Row> = (0..30_001)
+        .filter(|&i| i != 9_000 && i != 29_999)
+        .map(movie)
+        .collect();
+    assert_eq!(rows, expected);
+
+    // starting on a later page
+    let mut heap = HeapFile::open("test_pages", 8192 * 332).unwrap();
+    assert_eq!(heap.pages(), 2);
+    assert_eq!(heap.get(0).unwrap(), Some(movie(332 * 90)));
+}
 
-    // after 121 of the movie above, we have no more extra space to
-    // fit the same movie again
-    let movies = std::iter::repeat(movie).take(121);
+// same as first test, but with 8192 offset (second block)
+#[test]
+fn test_heap_zero_page() {
+    data::test_dir("test_heap_zero_page");
+    let mut heap = HeapFile::create("test_zero", 0).unwrap();
+    heap.insert(&vec!["1".into()]).unwrap();
+    heap.flush().unwrap();
+    drop(heap);
+    // the file grew, but the page never made it (a crash)
+    let f = OpenOptions::new()
+        .write(true)
+        .open(data::path("test_zero"))
+        .unwrap();
+    f.set_len(2 * PAGE as u64).unwrap();
 
-    let mut heap = HeapFile::create("test_full", 0).unwrap();
+    let mut heap = HeapFile::open("test_zero", 0).unwrap();
+    assert_eq!(heap.pages(), 2);
+    heap.insert(&vec!["2".into()]).unwrap();
+    assert_eq!(heap.last(), Tid { page: 1, slot: 0 });
+    assert_eq!(heap.fetch(heap.last()).unwrap(), Some(vec!["2".into()]));
+}
 
-    for movie in movies {
-        heap.insert(&movie).unwrap();
-    }
+#[test]
+fn test_heap_two_handles() {
+    data::test_dir("test_heap_two_handles");
+    let mut first = HeapFile::create("test_handles", 0).unwrap();
+    let mut second = HeapFile::open("test_handles", 0).unwrap();
+    first.insert(&vec!["1".into()]).unwrap();
+    // it sees the page as it is now, not as it was when it was opened
+    second.insert(&vec!["2".into()]).unwrap();
+    assert_eq!(second.last(), Tid { page: 0, slot: 1 });
+    assert_eq!(first.free_space(), second.free_space());
+    drop(first);
+    drop(second);
+
+    let rows: Vec<Row> = HeapFile::open("test_handles", 0)
+        .unwrap()
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(rows, vec![vec!["1".to_owned()], vec!["2".to_owned()]]);
 }
 
-// same as first test, but with 8192 offset (second block)
 #[test]
 fn test_heap_with_offset() {
-    let heap = HeapFile::create("test_blocks", 8192).unwrap();
+    data::test_dir("test_heap_with_offset");
+    let mut heap = HeapFile::create("test_blocks", 8192).unwrap();
+    heap.flush().unwrap();
 
-    let expected: [u8; 4] = [0, 4, 32, 0];
-    let mut header = [0; 4];
-    let mut f = File::open("./data/test_blocks").unwrap();
+    // the LSN, then ptr_lower and ptr_upper
+    let mut header = [0; 12];
+    let mut f = File::open(data::path("test_blocks")).unwrap();
     f.seek(SeekFrom::Start(8192)).unwrap();
     f.read_exact(&mut header).unwrap();
-    assert_eq!(header, expected);
-    assert_eq!(heap.free_space(), 8188);
+    assert_eq!(header[8..], [0, 12, 32, 0]);
+    let lsn = page_lsn(&header);
+    assert!(lsn > 0);
+    assert_eq!(heap.free_space(), 8180);
 
     let mut heap = HeapFile::open("test_blocks", 8192).unwrap();
 
-    assert_eq!(heap.ptr_lower(), 4);
+    assert_eq!(heap.ptr_lower(), 12);
     assert_eq!(heap.ptr_upper(), 8192);
     // remains the same with ::open()
-    assert_eq!(heap.free_space(), 8188);
+    assert_eq!(heap.free_space(), 8180);
 
     let movie = vec![
         // - length -  1
@@ -476,9 +924,13 @@ fn test_heap_with_offset() {
         "Animation".into(),
     ];
     heap.insert(&movie).unwrap();
+    // it's only in the buffer pool until then
+    heap.flush().unwrap();
 
     let expected = [
-        0x00, 0x19, // upper length
+        0x00, 0x29, // upper length
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // xmin, no transaction
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // xmax, not deleted
         0x00, 0x01, // length
         0x31, // "1"
         0x00, 0x09, // length
@@ -489,21 +941,23 @@ fn test_heap_with_offset() {
 
     let new_upper = 8192 - expected.len() as u16;
     assert_eq!(heap.ptr_upper(), new_upper);
-    assert_eq!(heap.ptr_lower(), 6);
-    assert_eq!(heap.free_space(), 8192 - 4 - expected.len() as u16);
+    assert_eq!(heap.ptr_lower(), 14);
+    // the line pointer takes 2 bytes too
+    assert_eq!(heap.free_space(), 8192 - 12 - 2 - expected.len() as u16);
 
     f.seek(SeekFrom::Start((8192 + 8192 - expected.len()) as u64))
         .unwrap();
     // f.seek(SeekFrom::End(-(expected.len() as i64))).unwrap();
-    let mut found = [0; 27];
+    let mut found = [0; 43];
     f.read_exact(&mut found).unwrap();
     assert_eq!(found, expected);
 
-    let expected: [u8; 4] = [0, 6, 0x1f, 0xe5];
-    let mut header = [0; 4];
+    let mut header = [0; 12];
     f.seek(SeekFrom::Start(8192)).unwrap();
     f.read_exact(&mut header).unwrap();
-    assert_eq!(header, expected);
+    assert_eq!(header[8..], [0, 14, 0x1f, 0xd5]);
+    // the insert's
+    assert!(page_lsn(&header) > lsn);
 
     assert_eq!(heap.get(0).unwrap(), Some(movie));
 }
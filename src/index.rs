@@ -1,11 +1,18 @@
-use std::io::{BufRead, Seek, SeekFrom};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::ops::Bound;
+use std::rc::Rc;
 
 use crate::btree::BTreeMap;
-use crate::fs::buf_reader;
-use crate::source::{Row, Schema, Source};
+use crate::fs::{buf_reader, Heap, HeapFile, Tid};
+use crate::source::{compare, join_keys, parse, Row, Rows, Schema, Source};
 
 pub struct IndexBuilder<'a> {
     source: &'a mut dyn Source,
+    field: String,
     idx: usize,
     ran: bool,
 }
@@ -14,15 +21,13 @@ impl<'a> IndexBuilder<'a> {
     pub fn new(field: &str, source: &'a mut dyn Source, schema: &Schema) -> Self {
         Self {
             idx: schema
-                .fields
-                .iter()
-                .position(|f| f == field)
+                .position(field)
                 // we can remove later if we want silent filter (if not found)
                 // which can be useful once we have multiple scanners (multi-table queries)
-                .expect(&format!(
-                    "'{}' field not found in table '{}'",
-                    field, schema.table
-                )),
+                .unwrap_or_else(|| {
+                    panic!("'{}' field not found in table '{}'", field, schema.table)
+                }),
+            field: field.into(),
             source,
             ran: false,
         }
@@ -39,51 +44,450 @@ impl<'a> Iterator for IndexBuilder<'a> {
         }
         self.ran = true;
 
-        let mut results = BTreeMap::new();
+        let mut results: BTreeMap<Key, Vec<Ptr>> = BTreeMap::new();
         loop {
             let offset = self.source.offset();
             match self.source.next() {
                 Some(row) => {
-                    results.insert(row[self.idx].clone(), offset);
+                    let key = Key(row[self.idx].clone());
+                    let ptr = match self.source.tid() {
+                        Some(tid) => Ptr::Tid(tid),
+                        None => Ptr::Offset(offset),
+                    };
+                    // not unique, ratings has a lot of rows per movie
+                    match results.get_mut(&key) {
+                        Some(ptrs) => ptrs.push(ptr),
+                        None => {
+                            results.insert(key, vec![ptr]);
+                        }
+                    }
                 }
                 None => break,
             }
         }
-        // this is a non sense "btree"
-        // results.sort();
-        Some(Index::new(results, self.source.table()))
+        Some(Index::new(results, self.source.table(), &self.field))
     }
 }
 
-type Field = String;
+/// A value of the indexed field, ordered like the rest of the rows
+/// are (numbers as numbers, before any text), so ranges make sense.
+#[derive(Debug)]
+struct Key(String);
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+/// Where an indexed row is, the byte offset of its line in a CSV,
+/// or its TID in a heap table.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ptr {
+    Offset(usize),
+    Tid(Tid),
+}
 
 pub struct Index {
-    ptrs: BTreeMap<Field, usize>,
+    // rows are added while the planner holds on to it
+    ptrs: RefCell<BTreeMap<Key, Vec<Ptr>>>,
     table: String,
+    field: String,
 }
 
 impl Index {
-    fn new(ptrs: BTreeMap<Field, usize>, table: &str) -> Self {
+    fn new(ptrs: BTreeMap<Key, Vec<Ptr>>, table: &str, field: &str) -> Self {
         Index {
-            ptrs,
+            ptrs: RefCell::new(ptrs),
             table: table.into(),
+            field: field.into(),
         }
     }
 
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
     pub fn search(&self, value: &str) -> Option<Row> {
-        let mut file = buf_reader(format!("./ml-20m/{}.csv", self.table)).unwrap();
-        file.seek(SeekFrom::Start(*self.ptrs.get(value).unwrap() as u64))
+        let ptrs = self.ptrs.borrow().get(&Key(value.into()))?.clone();
+        IndexScan::new(self, ptrs, None).next()
+    }
+
+    /// Where the rows with the field inside the bounds are, in the field's order.
+    pub fn ptrs(&self, low: Bound<&str>, high: Bound<&str>) -> Vec<Ptr> {
+        let key = |bound: Bound<&str>| bound.map(|value| Key(value.into()));
+        let (low, high) = (key(low), key(high));
+        self.ptrs
+            .borrow()
+            .range(low.as_ref(), high.as_ref())
+            .into_iter()
+            .flat_map(|(_, ptrs)| ptrs.iter().copied())
+            .collect()
+    }
+
+    /// Points at one more row with the value, one added after it was built.
+    pub fn add(&self, value: &str, ptr: Ptr) {
+        let mut ptrs = self.ptrs.borrow_mut();
+        match ptrs.get_mut(&Key(value.into())) {
+            Some(ptrs) => ptrs.push(ptr),
+            None => {
+                ptrs.insert(Key(value.into()), vec![ptr]);
+            }
+        }
+    }
+}
+
+// the map can be huge, and who wants to read it anyway
+impl fmt::Debug for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Index({}.{})", self.table, self.field)
+    }
+}
+
+// one index per field, so that's enough to tell them apart
+impl PartialEq for Index {
+    fn eq(&self, other: &Self) -> bool {
+        self.table == other.table && self.field == other.field
+    }
+}
+
+/// Reads the rows the pointers point to, seeking to each one
+/// instead of reading what's in between. Rows deleted since
+/// they were indexed are skipped.
+pub struct IndexScan {
+    table: String,
+    // opened on the first pointer into them
+    file: Option<BufReader<File>>,
+    heap: Option<HeapFile>,
+    ptrs: std::vec::IntoIter<Ptr>,
+    columns: Option<Vec<usize>>,
+}
+
+impl IndexScan {
+    pub fn new(index: &Index, ptrs: Vec<Ptr>, columns: Option<Vec<usize>>) -> Self {
+        Self {
+            table: index.table.clone(),
+            file: None,
+            heap: None,
+            ptrs: ptrs.into_iter(),
+            columns,
+        }
+    }
+
+    /// Starts over with other pointers, without opening the file again.
+    pub fn seek(&mut self, ptrs: Vec<Ptr>) {
+        self.ptrs = ptrs.into_iter();
+    }
+
+    fn fetch(&mut self, ptr: Ptr) -> Option<Row> {
+        match ptr {
+            Ptr::Offset(offset) => {
+                let file = self.file.get_or_insert_with(|| {
+                    buf_reader(format!("./ml-20m/{}.csv", self.table)).unwrap()
+                });
+                file.seek(SeekFrom::Start(offset as u64)).unwrap();
+                let mut raw = vec![];
+                let _ = file.read_until(b'\n', &mut raw).unwrap();
+                Some(parse(&raw, self.columns.as_deref()))
+            }
+            Ptr::Tid(tid) => {
+                let heap = self
+                    .heap
+                    .get_or_insert_with(|| HeapFile::open(&self.table, 0).unwrap());
+                let row = heap.fetch(tid).unwrap()?;
+                Some(match &self.columns {
+                    Some(columns) => columns.iter().map(|i| row[*i].clone()).collect(),
+                    None => row,
+                })
+            }
+        }
+    }
+}
+
+impl Iterator for IndexScan {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ptr = self.ptrs.next()?;
+            if let Some(row) = self.fetch(ptr) {
+                return Some(row);
+            }
+        }
+    }
+}
+
+/// Joins every outer row with the inner rows the index finds for
+/// it, instead of reading the whole inner table. The inner schema
+/// is the one of the indexed table (or its columns).
+pub struct IndexJoin<'a> {
+    outer: Rows<'a>,
+    index: &'a Index,
+    inner: IndexScan,
+    keys: Vec<(usize, usize)>,
+    // the outer field looked up in the index
+    probe: usize,
+    // the outer row we're returning the matches of
+    current: Option<Row>,
+}
+
+impl<'a> IndexJoin<'a> {
+    pub fn new(
+        outer: impl Iterator<Item = Row> + 'a,
+        index: &'a Index,
+        columns: Option<Vec<usize>>,
+        outer_schema: &Schema,
+        inner_schema: &Schema,
+        on: Vec<String>,
+    ) -> Self {
+        let keys = join_keys(&on, outer_schema, inner_schema);
+        let field = inner_schema.position(&index.field);
+        let probe = keys
+            .iter()
+            .find(|(_, inner)| Some(*inner) == field)
+            .unwrap_or_else(|| panic!("JOIN isn't on the indexed field '{}'", index.field))
+            .0;
+        Self {
+            outer: Box::new(outer),
+            index,
+            inner: IndexScan::new(index, vec![], columns),
+            keys,
+            probe,
+            current: None,
+        }
+    }
+}
+
+impl<'a> Iterator for IndexJoin<'a> {
+    type Item = Row;
+
+    // inner join
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let row = self.outer.next()?;
+                let value = Bound::Included(row[self.probe].as_str());
+                self.inner.seek(self.index.ptrs(value, value));
+                self.current = Some(row);
+            }
+            let outer_row = self.current.as_ref().unwrap();
+            match self.inner.next() {
+                // the index matches 1 and 1.0, the other joins don't
+                Some(mut inner_row) => {
+                    if self
+                        .keys
+                        .iter()
+                        .all(|(o, i)| outer_row[*o] == inner_row[*i])
+                    {
+                        let mut result = outer_row.clone();
+                        result.append(&mut inner_row);
+                        return Some(result);
+                    }
+                }
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+/// Every index we have, for the planner to pick from.
+#[derive(Default)]
+pub struct Indexes {
+    indexes: Vec<Rc<Index>>,
+}
+
+impl Indexes {
+    /// Reads the whole table (from the scan) to index one of its fields.
+    pub fn build(&mut self, scan: &mut dyn Source, schema: &Schema, field: &str) {
+        let index = IndexBuilder::new(field, scan, schema).next().unwrap();
+        self.insert(index);
+    }
+
+    pub fn insert(&mut self, index: Index) {
+        self.indexes.retain(|i| **i != index);
+        self.indexes.push(Rc::new(index));
+    }
+
+    /// Every index of the table.
+    pub fn remove(&mut self, table: &str) {
+        self.indexes.retain(|i| i.table != table);
+    }
+
+    /// A new row (or a new version of one) of a heap table, for every
+    /// index of the table. Whoever can't see it yet skips it, like the
+    /// rows deleted since.
+    pub fn add(&self, schema: &Schema, row: &Row, tid: Tid) {
+        for index in self.indexes.iter().filter(|i| i.table == schema.table) {
+            let i = schema.position(&index.field).unwrap();
+            index.add(&row[i], Ptr::Tid(tid));
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Index> {
+        self.indexes.iter().map(|i| i.as_ref())
+    }
+
+    pub fn get(&self, table: &str, field: &str) -> Option<Rc<Index>> {
+        self.indexes
+            .iter()
+            .find(|i| i.table == table && i.field == field)
+            .cloned()
+    }
+}
+
+#[test]
+fn test_ptrs() {
+    use crate::source::Buffered;
+
+    // movie 10 was rated twice, and sorts after 9 (not like text)
+    let rows = ["9", "10", "2", "10", "100"]
+        .iter()
+        .map(|m| vec![m.to_string()]);
+    let mut source = Buffered::new("ratings", rows);
+    let schema = Schema {
+        table: "ratings".into(),
+        fields: vec!["movieId".into()],
+    };
+    let index = IndexBuilder::new("movieId", &mut source, &schema)
+        .next()
+        .unwrap();
+    let offsets = |offsets: &[usize]| offsets.iter().copied().map(Ptr::Offset).collect::<Vec<_>>();
+    assert_eq!(
+        index.ptrs(Bound::Included("10"), Bound::Included("10")),
+        offsets(&[1, 3])
+    );
+    assert_eq!(
+        index.ptrs(Bound::Excluded("2"), Bound::Excluded("100")),
+        offsets(&[0, 1, 3])
+    );
+    assert_eq!(
+        index.ptrs(Bound::Unbounded, Bound::Unbounded),
+        offsets(&[2, 0, 1, 3, 4])
+    );
+    assert!(index
+        .ptrs(Bound::Included("11"), Bound::Included("99"))
+        .is_empty());
+
+    // numbers and text in the same field, the numbers go first
+    let rows = ["1a", "9", "10", "b", "2"]
+        .iter()
+        .map(|m| vec![m.to_string()]);
+    let mut source = Buffered::new("ratings", rows);
+    let index = IndexBuilder::new("movieId", &mut source, &schema)
+        .next()
+        .unwrap();
+    assert_eq!(
+        index.ptrs(Bound::Unbounded, Bound::Unbounded),
+        offsets(&[4, 1, 2, 0, 3])
+    );
+    assert_eq!(
+        index.ptrs(Bound::Included("9"), Bound::Unbounded),
+        offsets(&[1, 2, 0, 3])
+    );
+    assert_eq!(
+        index.ptrs(Bound::Included("1a"), Bound::Included("1a")),
+        offsets(&[0])
+    );
+}
+
+#[test]
+fn test_heap_index() {
+    use crate::fs::BulkWriter;
+    use crate::source::HeapScan;
+
+    crate::data::test_dir("test_heap_index");
+    HeapFile::create("test_heap_index", 0).unwrap();
+    let mut writer = BulkWriter::open("test_heap_index", 0).unwrap();
+    for i in 0..1000 {
+        writer
+            .write(&vec![(i % 100).to_string(), format!("Movie {i}")])
             .unwrap();
-        let mut row = vec![];
-        let _ = file.read_until(b'\n', &mut row).unwrap();
-        // TODO: abstract
-        Some(
-            row.split(|b| *b == b',')
-                .map(|bytes| std::str::from_utf8(bytes))
-                .map(Result::unwrap)
-                .map(|s| s.trim())
-                .map(|s| s.to_owned())
-                .collect(),
-        )
     }
+    assert!(writer.finish().unwrap() > 1);
+    let schema = Schema {
+        table: "test_heap_index".into(),
+        fields: vec!["movieId".into(), "title".into()],
+    };
+    let mut scan = HeapScan::new("test_heap_index", None);
+    let index = IndexBuilder::new("movieId", &mut scan, &schema)
+        .next()
+        .unwrap();
+
+    let value = Bound::Included("42");
+    let ptrs = index.ptrs(value, value);
+    assert_eq!(ptrs.len(), 10);
+    let Ptr::Tid(last) = ptrs[9] else {
+        panic!("heap tables are indexed by TID");
+    };
+    assert!(last.page > 0);
+    let mut heap = HeapFile::open("test_heap_index", 0).unwrap();
+    assert_eq!(
+        heap.fetch(last).unwrap(),
+        Some(vec!["42".into(), "Movie 942".into()])
+    );
+
+    // a deleted row is skipped
+    assert!(heap.delete_slot(last.page, last.slot).unwrap());
+    let titles: Vec<Row> = IndexScan::new(&index, ptrs, Some(vec![1])).collect();
+    assert_eq!(titles.len(), 9);
+    assert_eq!(titles[0], vec!["Movie 42"]);
+    assert_eq!(index.search("7"), Some(vec!["7".into(), "Movie 7".into()]));
+}
+
+#[test]
+fn test_index_after_dml() {
+    use crate::catalog::Table;
+    use crate::copy::{self, Options};
+    use crate::dml;
+    use crate::source::HeapScan;
+
+    let dir = crate::data::test_dir("test_index_after_dml");
+    let table = Table::heap(
+        "test_index_after_dml",
+        &[("movieId", "INTEGER"), ("title", "TEXT")],
+    );
+    let mut indexes = Indexes::default();
+    let mut scan = HeapScan::new(&table.name, None);
+    indexes.build(&mut scan, &table.schema(), "movieId");
+    let index = indexes.get(&table.name, "movieId").unwrap();
+    let row = |id: &str, title: &str| vec![id.to_owned(), title.to_owned()];
+
+    let rows = vec![row("1", "Toy Story"), row("2", "Jumanji")];
+    dml::insert(&table, rows, 0, &indexes).unwrap();
+    assert_eq!(index.search("2"), Some(row("2", "Jumanji")));
+
+    // the new version is somewhere else, the old one is skipped
+    let set = [("movieId".to_owned(), "3".to_owned())];
+    let two = ["movieId".to_owned(), "EQUALS".into(), "2".into()];
+    dml::update(&table, &set, Some(&two), 0, &indexes).unwrap();
+    assert_eq!(index.search("2"), None);
+    assert_eq!(index.search("3"), Some(row("3", "Jumanji")));
+
+    // after the pages that are already there
+    let path = format!("{dir}/movies.csv");
+    let csv: String = (10..1000).map(|i| format!("{i},Movie {i}\n")).collect();
+    std::fs::write(&path, csv).unwrap();
+    copy::copy(&table, &path, &Options::default(), 0, &indexes).unwrap();
+    assert_eq!(index.search("1"), Some(row("1", "Toy Story")));
+    assert_eq!(index.search("999"), Some(row("999", "Movie 999")));
+    let all = index.ptrs(Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(IndexScan::new(&index, all, None).count(), 992);
 }
@@ -1,11 +1,26 @@
+pub mod aggregate;
+pub mod analyze;
 // this is a copy/adaptation of one of the iterations of the
 // BTreeMap from the Rust standard library
 // commit in the compiler: b6edc59413f79016a1063c2ec6bc05516bc99cb6
 pub mod btree;
+pub mod buffer;
+pub mod catalog;
+pub mod copy;
+pub mod data;
+pub mod dml;
 // still doesn't abstract all fs operations
 // they are scattered across the code base
 // I'll fix that later
 pub mod fs;
 pub mod index;
+pub mod plan;
 pub mod query;
+pub mod recovery;
+pub mod set;
 pub mod source;
+pub mod subquery;
+pub mod transaction;
+pub mod wal;
+pub mod window;
+pub mod xact;
@@ -1,76 +1,271 @@
+use std::env;
 use std::fs::read_to_string;
 
-use daigrass::index::IndexBuilder;
+use daigrass::analyze;
+use daigrass::buffer;
+use daigrass::catalog::{Catalog, Column};
+use daigrass::copy::{self, Options};
+use daigrass::data;
+use daigrass::dml;
+use daigrass::plan::cost::Statistics;
+use daigrass::plan::physical::Physical;
+use daigrass::plan::{self, optimizer, physical, Plan};
 use daigrass::query::Query;
-use daigrass::source::{FileScan, HashJoin, Metadata, Projector, Row, Schema, Selector};
+use daigrass::recovery;
+use daigrass::source::Row;
+use daigrass::transaction::{self, Snapshot, Transaction};
 
 // const QUERY: &str = "queries/simple.json";
 // const QUERY: &str = "queries/multi-table.json";
+// const QUERY: &str = "queries/top-rated.json";
+// const QUERY: &str = "queries/average-rating.json";
+// const QUERY: &str = "queries/except.json";
+// const QUERY: &str = "queries/first-rating.json";
+// const QUERY: &str = "queries/subqueries.json";
+// const QUERY: &str = "queries/tagged-ratings.json";
+// const QUERY: &str = "queries/analyze.json";
+// const QUERY: &str = "queries/explain.json";
+// const QUERY: &str = "queries/index-scan.json";
+// const QUERY: &str = "queries/index-join.json";
+// const QUERY: &str = "queries/information-schema.json";
+// const QUERY: &str = "queries/create-table.json";
+// const QUERY: &str = "queries/drop-table.json";
+// const QUERY: &str = "queries/insert.json";
+// const QUERY: &str = "queries/insert-select.json";
+// const QUERY: &str = "queries/update.json";
+// const QUERY: &str = "queries/delete.json";
+// const QUERY: &str = "queries/create-heap-movies.json";
+// const QUERY: &str = "queries/copy.json";
+// const QUERY: &str = "queries/heap-scan.json";
+// const QUERY: &str = "queries/checkpoint.json";
+// const QUERY: &str = "queries/transaction.json";
 const QUERY: &str = "queries/join.json";
 
+fn physical(plan: Plan, catalog: &Catalog) -> Physical {
+    let statistics = Statistics::gather(&plan, catalog);
+    let optimized = optimizer::optimize(plan, &statistics);
+    physical::plan(&optimized, &statistics, catalog)
+}
+
+fn load(
+    catalog: &Catalog,
+    table: &str,
+    path: &str,
+    options: &Options,
+    transaction: Option<&Transaction>,
+) {
+    let table = catalog.table(table).expect("table to copy into");
+    let copied = transaction::autocommit(transaction, |xid| {
+        copy::copy(table, path, options, xid, catalog.indexes())
+    })
+    .unwrap();
+    println!(
+        "COPY {} ({} rejected, {} pages)",
+        copied.rows, copied.rejected, copied.pages
+    );
+}
+
 fn main() {
+    // ./data unless it says otherwise
+    if let Ok(dir) = env::var("DAIGRASS_DATA") {
+        data::use_dir(&dir);
+    }
+    // whatever didn't make it to the heap files before a crash
+    let redone = recovery::recover().unwrap();
+    if redone > 0 {
+        eprintln!("recovery: redid {redone} changes from the log");
+    }
+    // so the next one starts from here
+    recovery::checkpoint().unwrap();
+    let mut catalog = Catalog::load();
+
+    // daigrass load <table> <file>, a CSV with a header
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("load") {
+        let [_, _, table, path] = &args[..] else {
+            panic!("usage: daigrass load <table> <file>");
+        };
+        let options = Options {
+            header: true,
+            ..Default::default()
+        };
+        load(&catalog, table, path, &options, None);
+        return;
+    }
+
     let query = read_to_string(QUERY).unwrap();
     let json: serde_json::Value = serde_json::from_str(&query).unwrap();
-    let query = Query::from(json);
-
-    let scan = query
-        .scan
-        .expect("there should be at least one table in the scan list");
-    let mut scanners: Vec<FileScan> = scan.iter().map(|table| FileScan::new(&table)).collect();
+    // one statement, or a few of them one after the other
+    let statements: Vec<Query> = match json[0][0].is_array() {
+        true => json
+            .as_array()
+            .unwrap()
+            .iter()
+            .cloned()
+            .map(Query::from)
+            .collect(),
+        false => vec![Query::from(json)],
+    };
+    let mut transaction = None;
+    for query in &statements {
+        run(query, &mut catalog, &mut transaction);
+    }
+    if let Some(transaction) = transaction {
+        transaction.rollback().unwrap();
+        eprintln!("no COMMIT, rolled back");
+    }
+}
 
-    if let Some(join) = query.join {
-        assert_eq!(scan.len(), 2, "for now just JOINs w/ two tables");
-        let mut inner = scanners.remove(1);
-        let mut outer = scanners.remove(0);
-        let outer_schema = Schema::new(outer.table());
-        let inner_schema = Schema::new(inner.table());
-        // let join = NestedJoin::new(&mut outer, &mut inner, outer_schema, inner_schema, join);
-        let join = HashJoin::new(&mut outer, &mut inner, outer_schema, inner_schema, join);
-        let results: Vec<_> = join.into_iter().flatten().collect();
-        println!("results:");
-        println!("{results:?}");
-        // TODO: impl projection here
-    } else {
-        // single or multi-table queries (no JOINs)
-        for mut scanner in scanners {
-            let schema = Schema::new(scanner.table());
+// the statements that change something do it in the transaction if
+// there's one, or in their own (autocommit)
+fn run(query: &Query, catalog: &mut Catalog, transaction: &mut Option<Transaction>) {
+    if let Some(command) = &query.transaction {
+        match command.as_str() {
+            "BEGIN" => {
+                assert!(transaction.is_none(), "there's already a transaction");
+                *transaction = Some(Transaction::begin());
+                println!("BEGIN");
+            }
+            "COMMIT" => {
+                transaction
+                    .take()
+                    .expect("no transaction to COMMIT")
+                    .commit()
+                    .unwrap();
+                println!("COMMIT");
+            }
+            _ => {
+                let transaction = transaction.take().expect("no transaction to ROLLBACK");
+                transaction.rollback().unwrap();
+                println!("ROLLBACK");
+            }
+        }
+        return;
+    }
+    // the whole statement sees the same rows, the ones committed
+    // before it started (or its transaction did)
+    let snapshot = match transaction {
+        Some(transaction) => transaction.snapshot.clone(),
+        None => Snapshot::take(0),
+    };
+    transaction::use_snapshot(Some(snapshot));
+    if query.checkpoint {
+        let redo = recovery::checkpoint().unwrap();
+        println!("CHECKPOINT (redo from {redo})");
+        return;
+    }
+    if let Some(tables) = &query.analyze {
+        for table in tables {
+            let stats = analyze::analyze(catalog, table);
+            println!("analyzed {table}: {} rows", stats.rows);
+            catalog.set_statistics(table, stats);
+        }
+        catalog.save().unwrap();
+        return;
+    }
+    if let Some(definition) = &query.create_table {
+        let (table, columns) = definition.split_first().expect("a table to create");
+        assert!(
+            columns.len().is_multiple_of(2),
+            "CREATE_TABLE goes like [table, column, type, column, type...]"
+        );
+        let columns = columns
+            .chunks(2)
+            .map(|c| Column {
+                name: c[0].clone(),
+                data_type: c[1].to_uppercase(),
+            })
+            .collect();
+        catalog.create_table(table, columns).unwrap();
+        catalog.save().unwrap();
+        println!("created {table}");
+        return;
+    }
+    if let Some(copy) = &query.copy {
+        let (table, path) = match &copy[..] {
+            [table, from, path, ..] if from == "FROM" => (table, path),
+            _ => panic!("COPY goes like [table, FROM, file, WITH, options...]"),
+        };
+        let options = match copy.get(3).map(String::as_str) {
+            Some("WITH") => Options::from(&copy[4..]),
+            Some(other) => panic!("expected WITH, found '{other}'"),
+            None => Options::default(),
+        };
+        load(catalog, table, path, &options, transaction.as_ref());
+        return;
+    }
+    if let Some(tables) = &query.drop_table {
+        for table in tables {
+            catalog.drop_table(table).unwrap();
+            println!("dropped {table}");
+        }
+        catalog.save().unwrap();
+        return;
+    }
 
-            let results: Vec<Row> = match (query.selection.clone(), query.projection.clone()) {
-                (Some(selection), Some(projection)) => {
-                    let mut selector = Selector::new(selection, &mut scanner, &schema)
-                        .into_iter()
-                        .flatten();
-                    let projector = Projector::new(projection, &mut selector, &schema);
-                    projector.into_iter().collect()
-                }
-                (Some(selection), None) => {
-                    let selector = Selector::new(selection, &mut scanner, &schema);
-                    selector.into_iter().flatten().collect()
-                }
-                (None, Some(projection)) => {
-                    let projector = Projector::new(projection, &mut scanner, &schema);
-                    projector.into_iter().collect()
-                }
-                (None, None) => scanner.into_iter().collect(),
-            };
+    if let Some(table) = &query.insert {
+        let table = catalog.table(&table[0]).expect("table to insert into");
+        let rows: Vec<Row> = match &query.values {
+            Some(values) => values
+                .chunks(table.columns.len())
+                .map(|row| row.to_vec())
+                .collect(),
+            // INSERT ... SELECT, the rest of the query is the SELECT
+            None => plan::plan(query, catalog)
+                .into_iter()
+                .flat_map(|plan| physical(plan, catalog).execute().collect::<Vec<_>>())
+                .collect(),
+        };
+        let inserted = transaction::autocommit(transaction.as_ref(), |xid| {
+            dml::insert(table, rows, xid, catalog.indexes())
+        });
+        println!("INSERT {}", inserted.unwrap());
+        return;
+    }
+    if let Some(table) = &query.update {
+        let table = catalog.table(&table[0]).expect("table to update");
+        let assignments: Vec<(String, String)> = query
+            .assignments
+            .as_ref()
+            .expect("SET with the new values")
+            .chunks(2)
+            .map(|a| (a[0].clone(), a[1].clone()))
+            .collect();
+        let condition = query.selection.as_deref();
+        let updated = transaction::autocommit(transaction.as_ref(), |xid| {
+            dml::update(table, &assignments, condition, xid, catalog.indexes())
+        });
+        println!("UPDATE {}", updated.unwrap());
+        return;
+    }
+    if let Some(table) = &query.delete {
+        let table = catalog.table(&table[0]).expect("table to delete from");
+        let condition = query.selection.as_deref();
+        let deleted = transaction::autocommit(transaction.as_ref(), |xid| {
+            dml::delete(table, condition, xid)
+        });
+        println!("DELETE {}", deleted.unwrap());
+        return;
+    }
 
-            println!("results:");
-            println!("{results:?}");
+    // single or multi-table queries (no JOINs) have one plan per table
+    for plan in plan::plan(query, catalog) {
+        let physical = physical(plan, catalog);
+        if let Some(options) = &query.explain {
+            let analyze = options.iter().any(|o| o == "ANALYZE");
+            if analyze {
+                physical.execute().for_each(drop);
+            }
+            println!("{}", physical.explain(analyze));
+            if analyze {
+                let (hits, misses) = buffer::with(|pool| (pool.hits(), pool.misses()));
+                println!("buffers: hit={hits} read={misses}");
+            }
+            continue;
         }
-    }
+        let results: Vec<Row> = physical.execute().collect();
 
-    // index example/tests
-    let schema = Schema::new("movies");
-    let mut scanner = FileScan::new("movies");
-    // meh, this is a vec... bleeping FromIterator
-    let index = IndexBuilder::new("movieId", &mut scanner, &schema)
-        .into_iter()
-        // uhhh I don't know about this...
-        // maybe I don't need an iterator for the
-        // IndexBuilder? :thinking:
-        .collect::<Vec<_>>()
-        .pop()
-        .unwrap();
-    println!("bin search:");
-    println!("{:?}", index.search("5000"));
+        println!("results:");
+        println!("{results:?}");
+    }
 }
@@ -0,0 +1,487 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::catalog::{Catalog, Storage};
+use crate::fs::{Heap, HeapFile};
+use crate::plan::{and, connects, Node, Plan};
+use crate::set::Operation;
+use crate::source::{compare, conditions, limit_bounds, satisfies, FileScan, Offset};
+
+// when nothing is known about a table
+const DEFAULT_ROWS: f64 = 1000.0;
+// of `field = value` when the distinct values of the field aren't known
+const DEFAULT_EQUALS: f64 = 0.005;
+// of `<`, `>` and friends, for now
+const DEFAULT_RANGE: f64 = 1.0 / 3.0;
+
+/// What the planner knows about a single column, see ANALYZE.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStats {
+    // empty values
+    pub null_fraction: f64,
+    pub distinct: f64,
+    // and the fraction of the rows that have each one
+    pub most_common: Vec<(String, f64)>,
+    // bounds of buckets with the same number of (not most common) values
+    pub histogram: Vec<String>,
+}
+
+impl ColumnStats {
+    /// The fraction of the rows where `field <op> value`.
+    pub fn selectivity(&self, op: &str, value: &str) -> f64 {
+        let common: f64 = self.most_common.iter().map(|(_, f)| f).sum();
+        // what's left for the values that aren't most common
+        let rest = (1.0 - self.null_fraction - common).max(0.0);
+        let equals = match self.most_common.iter().find(|(v, _)| v == value) {
+            Some((_, frequency)) => *frequency,
+            None => {
+                let others = (self.distinct - self.most_common.len() as f64).max(1.0);
+                rest / others
+            }
+        };
+        match op {
+            "EQUALS" => equals,
+            "NOT_EQUALS" => (1.0 - self.null_fraction - equals).max(0.0),
+            _ => {
+                let common: f64 = self
+                    .most_common
+                    .iter()
+                    .filter(|(v, _)| satisfies(v, op, value))
+                    .map(|(_, f)| f)
+                    .sum();
+                let below = match self.histogram.is_empty() {
+                    true => return common + rest * DEFAULT_RANGE,
+                    false => self.below(value),
+                };
+                let histogram = match op {
+                    "LESS" | "LESS_EQUALS" => below,
+                    _ => 1.0 - below,
+                };
+                common + rest * histogram
+            }
+        }
+    }
+
+    // the fraction of the histogram below the value, interpolating
+    // inside the bucket if both bounds are numbers
+    fn below(&self, value: &str) -> f64 {
+        let bounds = &self.histogram;
+        let buckets = (bounds.len() - 1) as f64;
+        if compare(value, &bounds[0]).is_lt() {
+            return 0.0;
+        }
+        match bounds.iter().rposition(|b| compare(b, value).is_le()) {
+            Some(i) if i + 1 < bounds.len() => {
+                let inside = match (
+                    bounds[i].parse::<f64>(),
+                    bounds[i + 1].parse::<f64>(),
+                    value.parse::<f64>(),
+                ) {
+                    (Ok(low), Ok(high), Ok(value)) if high > low => (value - low) / (high - low),
+                    _ => 0.5,
+                };
+                (i as f64 + inside) / buckets
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+/// What the planner knows about a single table.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TableStats {
+    pub rows: f64,
+    pub columns: HashMap<String, ColumnStats>,
+}
+
+impl TableStats {
+    /// Guesses the number of rows from the size of the CSV and
+    /// how long the first lines are, nothing about the columns.
+    pub fn estimate(table: &str) -> Self {
+        let size = std::fs::metadata(format!("./ml-20m/{table}.csv"))
+            .unwrap()
+            .len() as f64;
+        let mut scan = FileScan::new(table);
+        let header = scan.offset() as f64;
+        let sampled = scan.by_ref().take(1000).count() as f64;
+        let bytes = scan.offset() as f64 - header;
+        let rows = match sampled {
+            0.0 => 0.0,
+            _ => ((size - header) / (bytes / sampled)).round(),
+        };
+        Self {
+            rows,
+            columns: HashMap::new(),
+        }
+    }
+
+    /// The line pointers of every page, the deleted rows are
+    /// counted too (only the headers are read).
+    pub fn estimate_heap(table: &str) -> Self {
+        let slots = HeapFile::open(table, 0).unwrap().slots();
+        Self {
+            rows: slots as f64,
+            columns: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Statistics {
+    tables: HashMap<String, TableStats>,
+}
+
+impl Statistics {
+    /// Every table the plan scans, from the last ANALYZE (in the
+    /// catalog) if there was one, otherwise just estimated.
+    pub fn gather(plan: &Plan, catalog: &Catalog) -> Self {
+        let mut statistics = Self::default();
+        let mut plans = vec![plan];
+        while let Some(plan) = plans.pop() {
+            if let Node::Scan { table, .. } = &plan.node {
+                if statistics.table(table).is_none() {
+                    let stats = match catalog.statistics(table) {
+                        Some(stats) => stats.clone(),
+                        None if catalog.table(table).map(|t| t.storage) == Some(Storage::Heap) => {
+                            TableStats::estimate_heap(table)
+                        }
+                        None => TableStats::estimate(table),
+                    };
+                    statistics.insert(table, stats);
+                }
+            }
+            plans.extend(plan.inputs.iter());
+        }
+        statistics
+    }
+
+    pub fn insert(&mut self, table: &str, stats: TableStats) {
+        self.tables.insert(table.to_owned(), stats);
+    }
+
+    pub fn table(&self, table: &str) -> Option<&TableStats> {
+        self.tables.get(table)
+    }
+
+    // finds the scan the field comes from, as long as nothing renamed it on the way
+    fn column(&self, plan: &Plan, field: &str) -> Option<&ColumnStats> {
+        match &plan.node {
+            Node::Scan { table, .. } => {
+                let i = plan.schema.position(field)?;
+                self.table(table)?.columns.get(&plan.schema.fields[i])
+            }
+            Node::Filter { .. }
+            | Node::SemiJoin { .. }
+            | Node::Exists { .. }
+            | Node::Project { .. }
+            | Node::Join { .. }
+            | Node::Sort { .. }
+            | Node::Limit { .. }
+            | Node::Distinct
+            | Node::Scalars { .. }
+            | Node::Reorder { .. } => plan
+                .inputs
+                .iter()
+                .find(|input| input.schema.position(field).is_some())
+                .and_then(|input| self.column(input, field)),
+            _ => None,
+        }
+    }
+
+    fn distinct(&self, plan: &Plan, field: &str) -> Option<f64> {
+        self.column(plan, field).map(|c| c.distinct.max(1.0))
+    }
+
+    /// How many rows the plan is expected to return.
+    pub fn rows(&self, plan: &Plan) -> f64 {
+        let input = |i: usize| self.rows(&plan.inputs[i]);
+        match &plan.node {
+            Node::Scan { table, .. } => self.table(table).map_or(DEFAULT_ROWS, |t| t.rows),
+            Node::Empty => 0.0,
+            Node::Values { rows } => rows.len() as f64,
+            Node::Filter { condition } => input(0) * self.selectivity(condition, &plan.inputs[0]),
+            // no idea, half of them
+            Node::SemiJoin { .. } | Node::Exists { .. } => input(0) / 2.0,
+            Node::Project { .. }
+            | Node::Window { .. }
+            | Node::Sort { .. }
+            | Node::Distinct
+            | Node::Scalars { .. }
+            | Node::Alias { .. }
+            | Node::Reorder { .. } => input(0),
+            Node::Join { on } => {
+                let (outer, inner) = (&plan.inputs[0], &plan.inputs[1]);
+                let (outer_rows, inner_rows) = (input(0), input(1));
+                conditions(on)
+                    .iter()
+                    .fold(outer_rows * inner_rows, |rows, c| {
+                        // without stats assume each side is a key (so the
+                        // bigger one decides), like a foreign key would
+                        let distinct = |field: &str, plan: &Plan, rows: f64| {
+                            self.distinct(plan, field).unwrap_or(rows)
+                        };
+                        let (a, b) = match outer.schema.position(&c[0]) {
+                            Some(_) => (&c[0], &c[2]),
+                            None => (&c[2], &c[0]),
+                        };
+                        let largest =
+                            distinct(a, outer, outer_rows).max(distinct(b, inner, inner_rows));
+                        rows / largest.max(1.0)
+                    })
+            }
+            Node::Aggregate { group, .. } => {
+                if group.is_empty() {
+                    return 1.0;
+                }
+                let rows = input(0);
+                let groups = group.iter().fold(1.0, |groups, field| {
+                    groups * self.distinct(&plan.inputs[0], field).unwrap_or(rows / 10.0)
+                });
+                groups.min(rows).max(1.0)
+            }
+            Node::Limit { limit } => input(0).min(limit_bounds(limit).0 as f64),
+            Node::SetOperation { op } => match op {
+                Operation::Union | Operation::UnionAll => input(0) + input(1),
+                Operation::Intersect => input(0).min(input(1)),
+                Operation::Except => input(0),
+            },
+        }
+    }
+
+    /// The fraction of the rows of `input` that pass the condition.
+    pub fn selectivity(&self, condition: &[String], input: &Plan) -> f64 {
+        if input.schema.position(&condition[0]).is_none() {
+            return 1.0;
+        }
+        let column = self.column(input, &condition[0]);
+        if condition[1] == "BETWEEN" {
+            // both ends, minus the rows counted twice (everything but the nulls)
+            return match column {
+                Some(column) => {
+                    let low = column.selectivity("GREATER_EQUALS", &condition[2]);
+                    let high = column.selectivity("LESS_EQUALS", &condition[4]);
+                    (low + high - (1.0 - column.null_fraction)).max(0.0)
+                }
+                None => DEFAULT_RANGE * DEFAULT_RANGE,
+            };
+        }
+        if let Some(column) = column {
+            return column.selectivity(&condition[1], &condition[2]);
+        }
+        let equals = DEFAULT_EQUALS;
+        match condition[1].as_str() {
+            "EQUALS" => equals,
+            "NOT_EQUALS" => 1.0 - equals,
+            _ => DEFAULT_RANGE,
+        }
+    }
+}
+
+/// Picks the order of a tree of joins (the join graph being its tables and
+/// the conditions between them) with the fewest intermediate rows, trying
+/// every way of splitting each subset of the tables in two.
+pub fn order_joins(plan: Plan, statistics: &Statistics) -> Plan {
+    let mut relations = vec![];
+    let mut on = vec![];
+    if let Node::Join { .. } = plan.node {
+        flatten(&plan, &mut relations, &mut on);
+    }
+    // two tables is just which side gets hashed, see physical
+    if relations.len() < 3 {
+        let inputs = plan
+            .inputs
+            .iter()
+            .cloned()
+            .map(|input| order_joins(input, statistics))
+            .collect();
+        return plan.with_inputs(inputs);
+    }
+    let relations: Vec<Plan> = relations
+        .into_iter()
+        .map(|relation| order_joins(relation, statistics))
+        .collect();
+    assert!(relations.len() <= 16, "for now JOINs w/ up to 16 tables");
+
+    let full = (1usize << relations.len()) - 1;
+    // only cross products if there's no other way around it
+    let mut best = dp(&relations, &on, statistics, false);
+    if best[full].is_none() {
+        best = dp(&relations, &on, statistics, true);
+    }
+    let (_, ordered) = best[full].take().unwrap();
+    match ordered.schema.fields == plan.schema.fields {
+        true => ordered,
+        false => ordered.reorder(plan.schema.fields.clone()),
+    }
+}
+
+// the relations under a tree of joins, and all of their conditions
+fn flatten(plan: &Plan, relations: &mut Vec<Plan>, on: &mut Vec<Vec<String>>) {
+    match &plan.node {
+        Node::Join { on: clause } => {
+            on.extend(conditions(clause).into_iter().map(<[String]>::to_vec));
+            for input in &plan.inputs {
+                flatten(input, relations, on);
+            }
+        }
+        _ => relations.push(plan.clone()),
+    }
+}
+
+// best[set of relations (bits)] = (cost, plan), the cost being
+// all the rows that come out of every join on the way
+fn dp(
+    relations: &[Plan],
+    on: &[Vec<String>],
+    statistics: &Statistics,
+    cross: bool,
+) -> Vec<Option<(f64, Plan)>> {
+    let full = (1usize << relations.len()) - 1;
+    let mut best: Vec<Option<(f64, Plan)>> = vec![None; full + 1];
+    for (i, relation) in relations.iter().enumerate() {
+        best[1 << i] = Some((0.0, relation.clone()));
+    }
+    // subsets are always smaller numbers, so they're done first
+    for set in 1..=full {
+        if set.count_ones() < 2 {
+            continue;
+        }
+        // every split once, the left side having the lowest table
+        let lowest = set & set.wrapping_neg();
+        let mut left = (set - 1) & set;
+        while left > 0 {
+            let right = set ^ left;
+            if left & lowest != 0 {
+                if let (Some((left_cost, outer)), Some((right_cost, inner))) =
+                    (&best[left], &best[right])
+                {
+                    let now: Vec<&[String]> = on
+                        .iter()
+                        .map(Vec::as_slice)
+                        .filter(|c| connects(c, &outer.schema, &inner.schema))
+                        .collect();
+                    if !now.is_empty() || cross {
+                        let joined = outer.clone().join(inner.clone(), and(&now));
+                        let cost = left_cost + right_cost + statistics.rows(&joined);
+                        if best[set].as_ref().is_none_or(|(c, _)| cost < *c) {
+                            best[set] = Some((cost, joined));
+                        }
+                    }
+                }
+            }
+            left = (left - 1) & set;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+fn scan(table: &str, fields: &[&str]) -> Plan {
+    Plan {
+        node: Node::Scan {
+            table: table.to_owned(),
+            columns: None,
+        },
+        schema: crate::source::Schema {
+            table: table.to_owned(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+        },
+        inputs: vec![],
+    }
+}
+
+#[test]
+fn test_estimates_rows() {
+    let mut statistics = Statistics::default();
+    statistics.insert(
+        "ratings",
+        TableStats {
+            rows: 20_000.0,
+            columns: HashMap::from([(
+                "movieId".to_owned(),
+                ColumnStats {
+                    distinct: 200.0,
+                    ..Default::default()
+                },
+            )]),
+        },
+    );
+    let ratings = scan("ratings", &["userId", "movieId", "rating"]);
+    let condition = |parts: &[&str]| parts.iter().map(|p| p.to_string()).collect();
+
+    let movie = ratings
+        .clone()
+        .filter(condition(&["movieId", "EQUALS", "5"]));
+    assert_eq!(statistics.rows(&movie), 100.0);
+    let user = ratings
+        .clone()
+        .filter(condition(&["userId", "EQUALS", "5"]));
+    assert_eq!(statistics.rows(&user), 20_000.0 * DEFAULT_EQUALS);
+
+    let per_movie = ratings.aggregate(vec!["movieId".into()], vec!["COUNT(*)".into()]);
+    assert_eq!(statistics.rows(&per_movie), 200.0);
+    // not in the stats at all
+    assert_eq!(statistics.rows(&scan("tags", &["movieId"])), DEFAULT_ROWS);
+}
+
+#[test]
+fn test_orders_joins() {
+    let mut statistics = Statistics::default();
+    for (table, rows) in [("ratings", 1_000_000.0), ("movies", 100.0), ("links", 10.0)] {
+        statistics.insert(
+            table,
+            TableStats {
+                rows,
+                columns: HashMap::new(),
+            },
+        );
+    }
+    let ratings = scan("ratings", &["userId", "movieId"]);
+    let movies = scan("movies", &["movieId", "title"]);
+    let links = scan("links", &["movieId", "imdbId"]);
+    let on = |parts: &[&str]| -> Vec<String> { parts.iter().map(|p| p.to_string()).collect() };
+    // the big one in the middle, in the SCAN order
+    let plan = movies
+        .join(
+            ratings,
+            on(&["movies.movieId", "EQUALS", "ratings.movieId"]),
+        )
+        .join(links, on(&["movies.movieId", "EQUALS", "links.movieId"]));
+    let fields = plan.schema.fields.clone();
+
+    let ordered = order_joins(plan, &statistics);
+    // the two small ones first, then the big one
+    assert!(matches!(ordered.node, Node::Reorder { .. }));
+    let join = &ordered.inputs[0];
+    let tables: Vec<&str> = join
+        .inputs
+        .iter()
+        .map(|i| i.schema.table.as_str())
+        .collect();
+    assert_eq!(tables, vec!["movies+links", "ratings"]);
+    // but still the same fields, in the same order
+    assert_eq!(ordered.schema.fields, fields);
+}
+
+#[test]
+fn test_selectivity_from_analyze() {
+    let rating = ColumnStats {
+        null_fraction: 0.1,
+        distinct: 12.0,
+        // 4.0 shows up a lot more than the rest
+        most_common: vec![("4.0".into(), 0.3)],
+        histogram: ["0.5", "1.0", "2.0", "3.0", "5.0"]
+            .iter()
+            .map(|b| b.to_string())
+            .collect(),
+    };
+    assert_eq!(rating.selectivity("EQUALS", "4.0"), 0.3);
+    // the other 60% split between the other 11 values
+    assert!((rating.selectivity("EQUALS", "2.5") - 0.6 / 11.0).abs() < 1e-9);
+    assert!((rating.selectivity("NOT_EQUALS", "4.0") - 0.6).abs() < 1e-9);
+    // half of the histogram, plus 4.0
+    assert!((rating.selectivity("GREATER", "2.0") - (0.3 + 0.6 * 0.5)).abs() < 1e-9);
+    // half way through the first bucket, nothing of 4.0
+    assert!((rating.selectivity("LESS", "0.75") - 0.6 * 0.125).abs() < 1e-9);
+    assert_eq!(rating.selectivity("LESS", "0.1"), 0.0);
+}
@@ -0,0 +1,436 @@
+use crate::aggregate;
+use crate::catalog::Catalog;
+use crate::query::Query;
+use crate::set::Operation;
+use crate::source::{conditions, projected, Row, Schema};
+use crate::subquery;
+use crate::window;
+
+// estimates how many rows each step returns, and orders joins with it
+pub mod cost;
+// rewrites a plan into an equivalent (hopefully cheaper) one
+pub mod optimizer;
+// picks the operators that actually run a plan
+pub mod physical;
+
+/// What each step of the logical plan does, without saying how.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    // only reads `columns` (positions in the table) if there are any
+    Scan {
+        table: String,
+        columns: Option<Vec<usize>>,
+    },
+    // known to have no rows, without even looking
+    Empty,
+    // rows that are already there, like information_schema's
+    Values {
+        rows: Vec<Row>,
+    },
+    Filter {
+        condition: Vec<String>,
+    },
+    // `field IN/NOT_IN` whatever the second input returns
+    SemiJoin {
+        field: String,
+        anti: bool,
+    },
+    // uncorrelated EXISTS/NOT_EXISTS, the second input decides for every row
+    Exists {
+        anti: bool,
+    },
+    Project {
+        fields: Vec<String>,
+    },
+    Join {
+        on: Vec<String>,
+    },
+    Aggregate {
+        group: Vec<String>,
+        aggregates: Vec<String>,
+    },
+    Window {
+        functions: Vec<String>,
+    },
+    Sort {
+        order: Vec<String>,
+    },
+    Limit {
+        limit: Vec<String>,
+    },
+    Distinct,
+    SetOperation {
+        op: Operation,
+    },
+    // appends the value of each scalar subquery (the inputs after the first)
+    Scalars {
+        names: Vec<String>,
+    },
+    // a derived table, only renames whatever is below it
+    Alias {
+        table: String,
+    },
+    // same fields in another order (unlike Project, which keeps the
+    // schema's), to undo what reordering the joins did to them
+    Reorder {
+        fields: Vec<String>,
+    },
+}
+
+/// A tree of relational operations, each one knows the
+/// schema of what it returns.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    pub node: Node,
+    pub schema: Schema,
+    pub inputs: Vec<Plan>,
+}
+
+impl Plan {
+    pub fn scan(schema: Schema) -> Self {
+        Self {
+            node: Node::Scan {
+                table: schema.table.clone(),
+                columns: None,
+            },
+            schema,
+            inputs: vec![],
+        }
+    }
+
+    pub fn values(schema: Schema, rows: Vec<Row>) -> Self {
+        Self {
+            node: Node::Values { rows },
+            schema,
+            inputs: vec![],
+        }
+    }
+
+    pub fn empty(schema: Schema) -> Self {
+        Self {
+            node: Node::Empty,
+            schema,
+            inputs: vec![],
+        }
+    }
+
+    // for the nodes that don't change the schema
+    fn over(self, node: Node) -> Self {
+        Self {
+            node,
+            schema: self.schema.clone(),
+            inputs: vec![self],
+        }
+    }
+
+    pub fn filter(self, condition: Vec<String>) -> Self {
+        self.over(Node::Filter { condition })
+    }
+
+    pub fn semi_join(self, field: String, anti: bool, subquery: Plan) -> Self {
+        let mut plan = self.over(Node::SemiJoin { field, anti });
+        plan.inputs.push(subquery);
+        plan
+    }
+
+    pub fn exists(self, anti: bool, subquery: Plan) -> Self {
+        let mut plan = self.over(Node::Exists { anti });
+        plan.inputs.push(subquery);
+        plan
+    }
+
+    pub fn project(self, fields: Vec<String>) -> Self {
+        Self {
+            schema: projected(&fields, &self.schema),
+            node: Node::Project { fields },
+            inputs: vec![self],
+        }
+    }
+
+    pub fn join(self, inner: Plan, on: Vec<String>) -> Self {
+        Self {
+            schema: Schema::join(&self.schema, &inner.schema),
+            node: Node::Join { on },
+            inputs: vec![self, inner],
+        }
+    }
+
+    pub fn aggregate(self, group: Vec<String>, aggregates: Vec<String>) -> Self {
+        let parsed = aggregate::aggregates(&aggregates, &self.schema);
+        Self {
+            schema: aggregate::schema(&group, &parsed, &self.schema),
+            node: Node::Aggregate { group, aggregates },
+            inputs: vec![self],
+        }
+    }
+
+    pub fn window(self, functions: Vec<String>) -> Self {
+        Self {
+            schema: window::schema(&window::windows(&functions), &self.schema),
+            node: Node::Window { functions },
+            inputs: vec![self],
+        }
+    }
+
+    pub fn sort(self, order: Vec<String>) -> Self {
+        self.over(Node::Sort { order })
+    }
+
+    pub fn limit(self, limit: Vec<String>) -> Self {
+        self.over(Node::Limit { limit })
+    }
+
+    pub fn distinct(self) -> Self {
+        self.over(Node::Distinct)
+    }
+
+    pub fn set_operation(self, op: Operation, right: Plan) -> Self {
+        let mut plan = self.over(Node::SetOperation { op });
+        plan.inputs.push(right);
+        plan
+    }
+
+    pub fn scalars(self, subqueries: Vec<(String, Plan)>) -> Self {
+        let mut schema = self.schema.clone();
+        let mut inputs = vec![self];
+        let mut names = vec![];
+        for (name, subquery) in subqueries {
+            schema.fields.push(name.clone());
+            names.push(name);
+            inputs.push(subquery);
+        }
+        Self {
+            node: Node::Scalars { names },
+            schema,
+            inputs,
+        }
+    }
+
+    /// The same node over other inputs, which might have different
+    /// schemas by now, so the schema is worked out again.
+    pub fn with_inputs(self, inputs: Vec<Plan>) -> Self {
+        let mut inputs = inputs.into_iter();
+        let mut next = || inputs.next().expect("one more input");
+        match self.node {
+            Node::Scan { .. } | Node::Empty | Node::Values { .. } => self,
+            Node::Filter { condition } => next().filter(condition),
+            Node::SemiJoin { field, anti } => {
+                let (input, subquery) = (next(), next());
+                input.semi_join(field, anti, subquery)
+            }
+            Node::Exists { anti } => {
+                let (input, subquery) = (next(), next());
+                input.exists(anti, subquery)
+            }
+            Node::Project { fields } => next().project(fields),
+            Node::Join { on } => {
+                let (outer, inner) = (next(), next());
+                outer.join(inner, on)
+            }
+            Node::Aggregate { group, aggregates } => next().aggregate(group, aggregates),
+            Node::Window { functions } => next().window(functions),
+            Node::Sort { order } => next().sort(order),
+            Node::Limit { limit } => next().limit(limit),
+            Node::Distinct => next().distinct(),
+            Node::SetOperation { op } => {
+                let (left, right) = (next(), next());
+                left.set_operation(op, right)
+            }
+            Node::Scalars { names } => {
+                let input = next();
+                input.scalars(names.into_iter().zip(inputs).collect())
+            }
+            Node::Alias { table } => next().alias(&table),
+            Node::Reorder { fields } => next().reorder(fields),
+        }
+    }
+
+    // the fields that aren't there anymore (pruned) are just skipped
+    pub fn reorder(self, fields: Vec<String>) -> Self {
+        let fields: Vec<String> = fields
+            .into_iter()
+            .filter(|f| self.schema.position(f).is_some())
+            .collect();
+        Self {
+            schema: Schema {
+                table: self.schema.table.clone(),
+                fields: fields.clone(),
+            },
+            node: Node::Reorder { fields },
+            inputs: vec![self],
+        }
+    }
+
+    pub fn alias(self, table: &str) -> Self {
+        Self {
+            schema: Schema {
+                table: table.to_owned(),
+                fields: self.schema.fields.clone(),
+            },
+            node: Node::Alias {
+                table: table.to_owned(),
+            },
+            inputs: vec![self],
+        }
+    }
+}
+
+/// Plans the query, a multi-table SCAN without a JOIN
+/// runs the whole query once per table (so one plan each).
+pub fn plan(query: &Query, catalog: &Catalog) -> Vec<Plan> {
+    let scan = query
+        .scan
+        .as_ref()
+        .expect("there should be at least one table in the scan list");
+
+    match &query.join {
+        // in the SCAN order, the optimizer picks a better one
+        Some(on) => {
+            let mut tables = scan.iter().map(|table| from(query, table, catalog));
+            let mut join = tables.next().unwrap();
+            let mut pending = conditions(on);
+            for table in tables {
+                // whatever can be checked once this table is in
+                let (now, later): (Vec<&[String]>, Vec<&[String]>) = pending
+                    .into_iter()
+                    .partition(|c| connects(c, &join.schema, &table.schema));
+                join = join.join(table, and(&now));
+                pending = later;
+            }
+            assert!(
+                pending.is_empty(),
+                "JOIN condition on a table that isn't in the SCAN list"
+            );
+            vec![block(query, join, catalog)]
+        }
+        None => scan
+            .iter()
+            .map(|table| block(query, from(query, table, catalog), catalog))
+            .collect(),
+    }
+}
+
+/// Whether a JOIN condition compares a field of one side with one of the other.
+pub fn connects(condition: &[String], left: &Schema, right: &Schema) -> bool {
+    let (a, b) = (&condition[0], &condition[2]);
+    (left.position(a).is_some() && right.position(b).is_some())
+        || (left.position(b).is_some() && right.position(a).is_some())
+}
+
+/// Back into a single JOIN clause.
+pub fn and(conditions: &[&[String]]) -> Vec<String> {
+    conditions.join(&"AND".to_owned())
+}
+
+// a table from the SCAN list, which can also be a derived table (a subquery)
+fn from(query: &Query, table: &str, catalog: &Catalog) -> Plan {
+    if let Some(n) = subquery::reference(table) {
+        return nested(&query.subqueries[n], catalog).alias(table);
+    }
+    match catalog.information_schema(table) {
+        Some((schema, rows)) => Plan::values(schema, rows),
+        None => Plan::scan(catalog.schema(table)),
+    }
+}
+
+/// Query blocks inside another query or on the other
+/// side of a set operation have to come out as a single plan.
+fn nested(query: &Query, catalog: &Catalog) -> Plan {
+    let mut plans = plan(query, catalog);
+    assert_eq!(
+        plans.len(),
+        1,
+        "nested query blocks read from a single table"
+    );
+    plans.pop().unwrap()
+}
+
+/// Everything after the SCAN/JOIN, in SQL's order.
+fn block(query: &Query, source: Plan, catalog: &Catalog) -> Plan {
+    let mut plan = source;
+
+    if let Some(selection) = query.selection.clone() {
+        plan = select(query, plan, selection, catalog);
+    }
+
+    let projection = query.projection.clone().unwrap_or_default();
+    let group = query.group.clone().unwrap_or_default();
+    let aggregates: Vec<String> = aggregate::aggregates(&projection, &plan.schema)
+        .iter()
+        .map(|a| a.name().to_owned())
+        .collect();
+    if !group.is_empty() || !aggregates.is_empty() {
+        plan = plan.aggregate(group, aggregates);
+    }
+    if let Some(having) = query.having.clone() {
+        plan = plan.filter(having);
+    }
+
+    let functions: Vec<String> = window::windows(&projection)
+        .iter()
+        .map(|w| w.name().to_owned())
+        .collect();
+    if !functions.is_empty() {
+        plan = plan.window(functions);
+    }
+    if let Some(qualify) = query.qualify.clone() {
+        plan = plan.filter(qualify);
+    }
+
+    let scalars: Vec<(String, Plan)> = projection
+        .iter()
+        .filter_map(|p| {
+            subquery::reference(p).map(|n| (p.clone(), nested(&query.subqueries[n], catalog)))
+        })
+        .collect();
+    // DISTINCT and the set operation happen on what's projected, and ORDER/LIMIT
+    // after them, otherwise ORDER can use fields that aren't projected, so it goes first
+    let order_and_limit = |mut plan: Plan| {
+        if let Some(order) = query.order.clone() {
+            plan = plan.sort(order);
+        }
+        if let Some(limit) = query.limit.clone() {
+            plan = plan.limit(limit);
+        }
+        plan
+    };
+    let project = |mut plan: Plan| {
+        if !scalars.is_empty() {
+            plan = plan.scalars(scalars.clone());
+        }
+        if query.projection.is_some() {
+            plan = plan.project(projection.clone());
+        }
+        plan
+    };
+    if !query.distinct && query.set.is_none() {
+        return project(order_and_limit(plan));
+    }
+    plan = project(plan);
+    if query.distinct {
+        plan = plan.distinct();
+    }
+    if let Some((op, other)) = &query.set {
+        plan = plan.set_operation(op.as_str().into(), nested(other, catalog));
+    }
+    order_and_limit(plan)
+}
+
+// a SELECTION, which can have a subquery in it
+fn select(query: &Query, plan: Plan, mut selection: Vec<String>, catalog: &Catalog) -> Plan {
+    let Some(n) = selection.iter().find_map(|p| subquery::reference(p)) else {
+        return plan.filter(selection);
+    };
+    let mut inner = query.subqueries[n].clone();
+    // correlated EXISTS run once as an IN instead of once per row
+    if let Some((rewritten, subquery)) = subquery::decorrelate(&selection, &inner, &plan.schema) {
+        selection = rewritten;
+        inner = subquery;
+    }
+    let inner = nested(&inner, catalog);
+    match (selection[0].as_str(), selection[1].as_str()) {
+        (_, op @ ("IN" | "NOT_IN")) => plan.semi_join(selection[0].clone(), op == "NOT_IN", inner),
+        (op @ ("EXISTS" | "NOT_EXISTS"), _) => plan.exists(op == "NOT_EXISTS", inner),
+        _ => panic!("unsupported subquery in SELECTION {selection:?}"),
+    }
+}
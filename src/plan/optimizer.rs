@@ -0,0 +1,423 @@
+use crate::aggregate;
+use crate::plan::cost::{order_joins, Statistics};
+use crate::plan::{Node, Plan};
+use crate::set::Operation;
+use crate::source::{compare, holds, limit_bounds, Schema};
+use crate::window;
+
+// a rule rewrites a single node, its inputs have already been rewritten
+type Rule = fn(Plan) -> Plan;
+
+const RULES: [Rule; 3] = [fold_constants, push_down_filters, remove_redundant_filters];
+
+/// Rewrites the plan with the rules until none of them changes
+/// anything, orders the joins, then drops every column nobody reads.
+pub fn optimize(plan: Plan, statistics: &Statistics) -> Plan {
+    let mut plan = plan;
+    // every rule makes the plan "smaller", but just in case
+    for _ in 0..32 {
+        let rewritten = RULES
+            .iter()
+            .fold(plan.clone(), |plan, rule| apply(plan, *rule));
+        if rewritten == plan {
+            break;
+        }
+        plan = rewritten;
+    }
+    let plan = order_joins(plan, statistics);
+    let all = plan.schema.fields.clone();
+    prune_columns(plan, &all)
+}
+
+// bottom-up, so a rule always sees inputs that are already rewritten
+fn apply(plan: Plan, rule: Rule) -> Plan {
+    let inputs = plan
+        .inputs
+        .iter()
+        .cloned()
+        .map(|input| apply(input, rule))
+        .collect();
+    rule(plan.with_inputs(inputs))
+}
+
+/// Filters that compare two literals are either always true (gone)
+/// or always false (nothing to read), a `LIMIT 0` reads nothing too,
+/// and most things over nothing are also nothing.
+pub fn fold_constants(plan: Plan) -> Plan {
+    let empty = |i: usize| plan.inputs[i].node == Node::Empty;
+    match &plan.node {
+        Node::Filter { condition } if plan.schema.position(&condition[0]).is_none() => {
+            // a field that isn't there (a typo), the filter says so when it runs
+            let Some(value) = literal(&condition[0]) else {
+                return plan;
+            };
+            if holds(value, condition) {
+                plan.inputs.into_iter().next().unwrap()
+            } else {
+                Plan::empty(plan.schema)
+            }
+        }
+        Node::Limit { limit } if limit_bounds(limit).0 == 0 => Plan::empty(plan.schema),
+        // without a GROUP there's still a row (COUNT(*) = 0 and friends)
+        Node::Aggregate { group, .. } if group.is_empty() => plan,
+        Node::Join { .. } if empty(0) || empty(1) => Plan::empty(plan.schema),
+        Node::SetOperation { op } => match op {
+            Operation::Intersect if empty(0) || empty(1) => Plan::empty(plan.schema),
+            Operation::Except if empty(0) => Plan::empty(plan.schema),
+            _ => plan,
+        },
+        // the ones that return (a subset of) what comes from the first input
+        Node::Filter { .. }
+        | Node::SemiJoin { .. }
+        | Node::Exists { .. }
+        | Node::Project { .. }
+        | Node::Aggregate { .. }
+        | Node::Window { .. }
+        | Node::Sort { .. }
+        | Node::Limit { .. }
+        | Node::Distinct
+        | Node::Scalars { .. }
+        | Node::Alias { .. }
+        | Node::Reorder { .. }
+            if empty(0) =>
+        {
+            Plan::empty(plan.schema)
+        }
+        _ => plan,
+    }
+}
+
+// a number, or a string in quotes ('like this'), without the quotes
+fn literal(value: &str) -> Option<&str> {
+    match value.parse::<f64>() {
+        Ok(_) => Some(value),
+        Err(_) => value.strip_prefix('\'')?.strip_suffix('\''),
+    }
+}
+
+/// A filter over a join only needs the rows of the side that has
+/// the column, so filter that side before joining instead.
+pub fn push_down_filters(plan: Plan) -> Plan {
+    let condition = match (&plan.node, plan.inputs.first()) {
+        (Node::Filter { condition }, Some(input)) if matches!(input.node, Node::Join { .. }) => {
+            condition.clone()
+        }
+        _ => return plan,
+    };
+    let join = plan.inputs.into_iter().next().unwrap();
+    let owners: Vec<bool> = join
+        .inputs
+        .iter()
+        .map(|side| side.schema.position(&condition[0]).is_some())
+        .collect();
+    let side = match owners[..] {
+        [true, false] => 0,
+        [false, true] => 1,
+        // ambiguous (or not there at all), leave it where it was
+        _ => return join.filter(condition),
+    };
+    let mut inputs = join.inputs.clone();
+    inputs[side] = inputs[side].clone().filter(condition);
+    join.with_inputs(inputs)
+}
+
+/// Drops a filter when one right below it already guarantees it
+/// (e.g. `rating > 3` under `rating > 4`), and if they can't
+/// both hold (`rating = 3` and `rating = 4`) there's nothing to read.
+pub fn remove_redundant_filters(plan: Plan) -> Plan {
+    let outer = match &plan.node {
+        Node::Filter { condition } => condition.clone(),
+        _ => return plan,
+    };
+    let mut below = &plan.inputs[0];
+    while let Node::Filter { condition: inner } = &below.node {
+        let same = plan.schema.position(&inner[0]) == plan.schema.position(&outer[0]);
+        if same && implies(inner, &outer) {
+            return plan.inputs.into_iter().next().unwrap();
+        }
+        if same && inner[1] == "EQUALS" {
+            // implies() already checked if the value passes
+            return Plan::empty(plan.schema);
+        }
+        below = &below.inputs[0];
+    }
+    plan
+}
+
+// whether every value that passes `inner` also passes `outer` (same field)
+fn implies(inner: &[String], outer: &[String]) -> bool {
+    if inner[1..] == outer[1..] {
+        return true;
+    }
+    let (a, b) = (&inner[2], &outer[2]);
+    match (inner[1].as_str(), outer[1].as_str()) {
+        ("EQUALS", _) => holds(a, outer),
+        ("GREATER", "GREATER" | "GREATER_EQUALS")
+        | ("GREATER_EQUALS", "GREATER_EQUALS")
+        | ("LESS", "LESS" | "LESS_EQUALS")
+        | ("LESS_EQUALS", "LESS_EQUALS") => {
+            let ord = compare(a, b);
+            if inner[1].starts_with("GREATER") {
+                ord.is_ge()
+            } else {
+                ord.is_le()
+            }
+        }
+        // x >= 5 implies x > 4 but not x > 5
+        ("GREATER_EQUALS", "GREATER") => compare(a, b).is_gt(),
+        ("LESS_EQUALS", "LESS") => compare(a, b).is_lt(),
+        _ => false,
+    }
+}
+
+/// Works out, top-down, which columns each node needs from its inputs,
+/// so the scans only turn those into Strings. `required` are the fields
+/// (of this plan's schema) that whoever is above actually reads.
+pub fn prune_columns(plan: Plan, required: &[String]) -> Plan {
+    let schema = &plan.schema;
+    let required: Vec<String> = required
+        .iter()
+        .filter_map(|field| schema.position(field))
+        .map(|i| schema.fields[i].clone())
+        .collect();
+    let all = |i: usize| plan.inputs[i].schema.fields.clone();
+    let with =
+        |extra: &[String]| -> Vec<String> { required.iter().chain(extra).cloned().collect() };
+
+    let needs: Vec<Vec<String>> = match &plan.node {
+        Node::Scan { .. } => return prune_scan(plan, &required),
+        Node::Empty | Node::Values { .. } => return plan,
+        Node::Filter { condition } => vec![with(&condition[..1])],
+        Node::SemiJoin { field, .. } => vec![with(std::slice::from_ref(field)), all(1)],
+        Node::Exists { .. } => vec![required.clone(), all(1)],
+        Node::Project { fields } => vec![fields.clone()],
+        Node::Join { on } => {
+            let needed = with(&[on[0].clone(), on[2].clone()]);
+            // each side keeps whatever it can resolve
+            vec![needed.clone(), needed]
+        }
+        Node::Aggregate { group, aggregates } => {
+            let input = &plan.inputs[0].schema;
+            let mut needed = group.clone();
+            needed.extend(
+                aggregate::aggregates(aggregates, input)
+                    .iter()
+                    .filter_map(|a| a.input())
+                    .map(|i| input.fields[i].clone()),
+            );
+            vec![needed]
+        }
+        Node::Window { functions } => {
+            let mut needed = required.clone();
+            for function in window::windows(functions) {
+                needed.extend(function.fields().into_iter().map(str::to_owned));
+            }
+            vec![needed]
+        }
+        Node::Sort { order } => vec![with(order)],
+        Node::Limit { .. } | Node::Alias { .. } | Node::Reorder { .. } => {
+            vec![required.clone()]
+        }
+        // whether a row is a duplicate depends on all of it
+        Node::Distinct => vec![all(0)],
+        Node::SetOperation { .. } => vec![all(0), all(1)],
+        Node::Scalars { .. } => {
+            let mut needs = vec![required.clone()];
+            needs.extend((1..plan.inputs.len()).map(all));
+            needs
+        }
+    };
+    let inputs = plan
+        .inputs
+        .iter()
+        .cloned()
+        .zip(needs)
+        .map(|(input, needed)| prune_columns(input, &needed))
+        .collect();
+    plan.with_inputs(inputs)
+}
+
+fn prune_scan(plan: Plan, required: &[String]) -> Plan {
+    let (table, columns) = match plan.node {
+        Node::Scan { table, columns } => (table, columns),
+        _ => unreachable!(),
+    };
+    let keep: Vec<usize> = (0..plan.schema.fields.len())
+        .filter(|i| required.contains(&plan.schema.fields[*i]))
+        .collect();
+    let columns = keep
+        .iter()
+        .map(|i| columns.as_ref().map_or(*i, |columns| columns[*i]))
+        .collect();
+    Plan {
+        node: Node::Scan {
+            table,
+            columns: Some(columns),
+        },
+        schema: Schema {
+            table: plan.schema.table.clone(),
+            fields: keep
+                .iter()
+                .map(|i| plan.schema.fields[*i].clone())
+                .collect(),
+        },
+        inputs: vec![],
+    }
+}
+
+#[cfg(test)]
+fn scan(table: &str, fields: &[&str]) -> Plan {
+    Plan {
+        node: Node::Scan {
+            table: table.to_owned(),
+            columns: None,
+        },
+        schema: Schema {
+            table: table.to_owned(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+        },
+        inputs: vec![],
+    }
+}
+
+#[cfg(test)]
+fn strings(parts: &[&str]) -> Vec<String> {
+    parts.iter().map(|p| p.to_string()).collect()
+}
+
+#[test]
+fn test_push_down_filters() {
+    let movies = scan("movies", &["movieId", "title"]);
+    let ratings = scan("ratings", &["userId", "movieId", "rating"]);
+    let on = strings(&["movies.movieId", "EQUALS", "ratings.movieId"]);
+    let plan = movies
+        .clone()
+        .join(ratings.clone(), on.clone())
+        .filter(strings(&["rating", "GREATER", "4"]));
+
+    let expected = movies
+        .clone()
+        .join(ratings.filter(strings(&["rating", "GREATER", "4"])), on);
+    assert_eq!(apply(plan.clone(), push_down_filters), expected);
+    assert_eq!(
+        apply(plan, push_down_filters).schema.fields,
+        vec![
+            "movies.movieId",
+            "movies.title",
+            "ratings.userId",
+            "ratings.movieId",
+            "ratings.rating"
+        ]
+    );
+
+    // movieId is on both sides, so it stays above the join
+    let ambiguous = expected.filter(strings(&["movieId", "EQUALS", "1"]));
+    assert_eq!(apply(ambiguous.clone(), push_down_filters), ambiguous);
+}
+
+#[test]
+fn test_fold_constants() {
+    let ratings = scan("ratings", &["movieId", "rating"]);
+    let always = ratings.clone().filter(strings(&["1", "EQUALS", "1"]));
+    assert_eq!(apply(always, fold_constants), ratings);
+
+    let never = ratings
+        .clone()
+        .filter(strings(&["1", "EQUALS", "2"]))
+        .sort(strings(&["rating"]));
+    let folded = apply(never, fold_constants);
+    assert_eq!(folded.node, Node::Empty);
+    assert_eq!(folded.schema, ratings.schema);
+
+    let nothing = ratings.clone().limit(strings(&["0"]));
+    assert_eq!(apply(nothing, fold_constants).node, Node::Empty);
+
+    let quoted = ratings
+        .clone()
+        .filter(strings(&["'Heat'", "EQUALS", "Heat"]));
+    assert_eq!(apply(quoted, fold_constants), ratings);
+
+    // COUNT(*) of nothing is still a row
+    let count = Plan::empty(ratings.schema.clone()).aggregate(vec![], strings(&["COUNT(*)"]));
+    assert_eq!(apply(count.clone(), fold_constants), count);
+}
+
+#[test]
+#[should_panic(expected = "'ratting' field not found in table 'ratings'")]
+fn test_fold_constants_unknown_field() {
+    use crate::catalog::Catalog;
+    use crate::plan::physical;
+
+    let ratings = Plan::values(
+        scan("ratings", &["movieId", "rating"]).schema,
+        vec![strings(&["1", "4"])],
+    );
+    // not a literal, so it isn't always false
+    let typo = ratings.filter(strings(&["ratting", "EQUALS", "4"]));
+    assert_eq!(apply(typo.clone(), fold_constants), typo);
+    let physical = physical::plan(&typo, &Statistics::default(), &Catalog::new("test"));
+    physical.execute().for_each(drop);
+}
+
+#[test]
+fn test_remove_redundant_filters() {
+    let ratings = scan("ratings", &["movieId", "rating"]);
+    let above_four = ratings.clone().filter(strings(&["rating", "GREATER", "4"]));
+
+    let implied = above_four
+        .clone()
+        .filter(strings(&["rating", "GREATER_EQUALS", "3"]));
+    assert_eq!(apply(implied, remove_redundant_filters), above_four);
+
+    let twice = above_four
+        .clone()
+        .filter(strings(&["rating", "GREATER", "4"]));
+    assert_eq!(apply(twice, remove_redundant_filters), above_four);
+
+    // the other way around it is actually needed
+    let narrower = ratings
+        .clone()
+        .filter(strings(&["rating", "GREATER", "3"]))
+        .filter(strings(&["rating", "GREATER", "4"]));
+    assert_eq!(apply(narrower.clone(), remove_redundant_filters), narrower);
+
+    let contradiction = ratings
+        .filter(strings(&["rating", "EQUALS", "3"]))
+        .filter(strings(&["rating", "EQUALS", "4"]));
+    assert_eq!(
+        apply(contradiction, remove_redundant_filters).node,
+        Node::Empty
+    );
+}
+
+#[test]
+fn test_prune_columns() {
+    let movies = scan("movies", &["movieId", "title", "genres"]);
+    let ratings = scan("ratings", &["userId", "movieId", "rating", "timestamp"]);
+    let plan = movies
+        .join(
+            ratings,
+            strings(&["movies.movieId", "EQUALS", "ratings.movieId"]),
+        )
+        .filter(strings(&["rating", "GREATER", "4"]))
+        .project(strings(&["movies.title"]));
+
+    let optimized = optimize(plan, &Statistics::default());
+    assert_eq!(optimized.schema.fields, vec!["movies.title"]);
+    let join = &optimized.inputs[0];
+    assert!(matches!(join.node, Node::Join { .. }));
+    let columns = |plan: &Plan| -> Option<Vec<usize>> {
+        let mut curr = plan;
+        while !curr.inputs.is_empty() {
+            curr = &curr.inputs[0];
+        }
+        match &curr.node {
+            Node::Scan { columns, .. } => columns.clone(),
+            _ => None,
+        }
+    };
+    // no genres, and no userId nor timestamp
+    assert_eq!(columns(&join.inputs[0]), Some(vec![0, 1]));
+    assert_eq!(columns(&join.inputs[1]), Some(vec![1, 2]));
+    assert!(matches!(join.inputs[1].node, Node::Filter { .. }));
+}
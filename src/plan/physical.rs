@@ -0,0 +1,996 @@
+use crate::aggregate::{self, HashAggregate, StreamAggregate};
+use std::cell::Cell;
+use std::ops::Bound;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::catalog::{Catalog, Storage};
+use crate::index::{Index, IndexJoin, IndexScan, Indexes};
+use crate::plan::cost::Statistics;
+use crate::plan::{Node, Plan};
+use crate::set::{Distinct, Operation, SetOperation};
+use crate::source::{
+    conditions, Buffered, FileScan, HashJoin, HeapScan, Limit, MergeJoin, NestedJoin, Offset,
+    Projector, Row, Rows, Schema, Selector, Sort, TopN,
+};
+use crate::subquery::{self, Scalars, SemiJoin};
+use crate::window::{self, Window};
+
+/// The operator that runs each step, see the structs
+/// with the same names for what they do.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operator {
+    FileScan {
+        table: String,
+        columns: Option<Vec<usize>>,
+    },
+    HeapScan {
+        table: String,
+        columns: Option<Vec<usize>>,
+    },
+    // seeks to the rows where the condition holds instead of reading them all
+    IndexScan {
+        index: Rc<Index>,
+        condition: Vec<String>,
+        columns: Option<Vec<usize>>,
+    },
+    Empty,
+    Values {
+        rows: Vec<Row>,
+    },
+    Selector {
+        condition: Vec<String>,
+    },
+    SemiJoin {
+        field: String,
+        anti: bool,
+    },
+    Exists {
+        anti: bool,
+    },
+    Projector {
+        fields: Vec<String>,
+    },
+    HashJoin {
+        on: Vec<String>,
+        build_outer: bool,
+    },
+    // over a Buffered inner side
+    NestedJoin {
+        on: Vec<String>,
+    },
+    // expects both inputs sorted by the JOIN fields
+    MergeJoin {
+        on: Vec<String>,
+    },
+    // the only input is the outer side, the inner rows are
+    // read from the indexed table as they're needed
+    IndexJoin {
+        on: Vec<String>,
+        index: Rc<Index>,
+        inner: Schema,
+        columns: Option<Vec<usize>>,
+    },
+    HashAggregate {
+        group: Vec<String>,
+        aggregates: Vec<String>,
+    },
+    // expects its input sorted by the group
+    StreamAggregate {
+        group: Vec<String>,
+        aggregates: Vec<String>,
+    },
+    Window {
+        functions: Vec<String>,
+    },
+    Sort {
+        order: Vec<String>,
+    },
+    TopN {
+        order: Vec<String>,
+        limit: Vec<String>,
+    },
+    Limit {
+        limit: Vec<String>,
+    },
+    Distinct,
+    SetOperation {
+        op: Operation,
+    },
+    Scalars {
+        names: Vec<String>,
+    },
+    Reorder {
+        fields: Vec<String>,
+    },
+}
+
+/// What EXPLAIN ANALYZE reports, filled in while the operator runs.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    rows: Cell<usize>,
+    // how many times it was executed
+    loops: Cell<usize>,
+    // including its inputs'
+    time: Cell<Duration>,
+    // of the file, 8192 bytes each like the heap's
+    pages: Cell<usize>,
+    // bytes of the rows it holds on to
+    memory: Cell<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Physical {
+    pub operator: Operator,
+    pub schema: Schema,
+    pub inputs: Vec<Physical>,
+    // estimated rows it returns
+    pub rows: f64,
+    // estimated, its own and everything below it
+    pub cost: f64,
+    pub metrics: Metrics,
+}
+
+impl Physical {
+    fn new(operator: Operator, schema: &Schema, inputs: Vec<Physical>, rows: f64) -> Self {
+        let below: Vec<f64> = inputs.iter().map(|input| input.rows).collect();
+        let cost = cost(&operator, &below, rows) + inputs.iter().map(|i| i.cost).sum::<f64>();
+        Self {
+            operator,
+            schema: schema.clone(),
+            inputs,
+            rows,
+            cost,
+            metrics: Metrics::default(),
+        }
+    }
+}
+
+/// Picks the operators for a logical plan.
+pub fn plan(logical: &Plan, statistics: &Statistics, catalog: &Catalog) -> Physical {
+    let plan = |logical: &Plan| plan(logical, statistics, catalog);
+    let inputs = || logical.inputs.iter().map(plan).collect();
+    let rows = statistics.rows(logical);
+    let operator = match &logical.node {
+        Node::Scan { table, columns } => {
+            let (table, columns) = (table.clone(), columns.clone());
+            match catalog.table(&table).map(|t| t.storage) {
+                Some(Storage::Heap) => Operator::HeapScan { table, columns },
+                _ => Operator::FileScan { table, columns },
+            }
+        }
+        Node::Empty => Operator::Empty,
+        Node::Values { rows } => Operator::Values { rows: rows.clone() },
+        Node::Filter { condition } => {
+            let operator = Operator::Selector {
+                condition: condition.clone(),
+            };
+            let filter = Physical::new(operator, &logical.schema, inputs(), rows);
+            return match index_scan(logical, rows, catalog.indexes()) {
+                Some(scan) if scan.cost < filter.cost => scan,
+                _ => filter,
+            };
+        }
+        Node::SemiJoin { field, anti } => Operator::SemiJoin {
+            field: field.clone(),
+            anti: *anti,
+        },
+        Node::Exists { anti } => Operator::Exists { anti: *anti },
+        Node::Project { fields } => Operator::Projector {
+            fields: fields.clone(),
+        },
+        Node::Join { .. } => return joins(logical, statistics, catalog).remove(0),
+        Node::Aggregate { group, aggregates } => Operator::HashAggregate {
+            group: group.clone(),
+            aggregates: aggregates.clone(),
+        },
+        Node::Window { functions } => Operator::Window {
+            functions: functions.clone(),
+        },
+        Node::Sort { order } => {
+            let input = &logical.inputs[0];
+            match &input.node {
+                // the groups have to come out sorted anyway, so sort the input
+                // and aggregate one group at a time instead of hashing all of them
+                Node::Aggregate { group, aggregates } if group == order => {
+                    let below = &input.inputs[0];
+                    let sort = Physical::new(
+                        Operator::Sort {
+                            order: group.clone(),
+                        },
+                        &below.schema,
+                        vec![plan(below)],
+                        statistics.rows(below),
+                    );
+                    let operator = Operator::StreamAggregate {
+                        group: group.clone(),
+                        aggregates: aggregates.clone(),
+                    };
+                    return Physical::new(operator, &logical.schema, vec![sort], rows);
+                }
+                // a merge join already returns them sorted by the outer fields
+                Node::Join { on } if sorts_by_outer(order, on, input) => {
+                    let mut joins = joins(input, statistics, catalog);
+                    let best = joins.remove(0);
+                    let sort = Operator::Sort {
+                        order: order.clone(),
+                    };
+                    if matches!(best.operator, Operator::MergeJoin { .. }) {
+                        return best;
+                    }
+                    let sorted = Physical::new(sort, &logical.schema, vec![best], rows);
+                    return match joins
+                        .into_iter()
+                        .find(|j| matches!(j.operator, Operator::MergeJoin { .. }))
+                    {
+                        Some(merge) if merge.cost <= sorted.cost => merge,
+                        _ => sorted,
+                    };
+                }
+                _ => Operator::Sort {
+                    order: order.clone(),
+                },
+            }
+        }
+        Node::Limit { limit } => {
+            let input = &logical.inputs[0];
+            match &input.node {
+                // no need to sort everything if we only want the first few
+                Node::Sort { order } => {
+                    let top = Physical::new(
+                        Operator::TopN {
+                            order: order.clone(),
+                            limit: limit.clone(),
+                        },
+                        &input.schema,
+                        input.inputs.iter().map(plan).collect(),
+                        rows,
+                    );
+                    let operator = Operator::Limit {
+                        limit: limit.clone(),
+                    };
+                    return Physical::new(operator, &logical.schema, vec![top], rows);
+                }
+                _ => Operator::Limit {
+                    limit: limit.clone(),
+                },
+            }
+        }
+        Node::Distinct => Operator::Distinct,
+        Node::SetOperation { op } => Operator::SetOperation { op: *op },
+        Node::Scalars { names } => Operator::Scalars {
+            names: names.clone(),
+        },
+        Node::Reorder { fields } => Operator::Reorder {
+            fields: fields.clone(),
+        },
+        // nothing to run, it's the same rows under another name
+        Node::Alias { .. } => {
+            let mut input = plan(&logical.inputs[0]);
+            input.schema = logical.schema.clone();
+            return input;
+        }
+    };
+    Physical::new(operator, &logical.schema, inputs(), rows)
+}
+
+// a FILTER right over the scan of an indexed field
+fn index_scan(filter: &Plan, rows: f64, indexes: &Indexes) -> Option<Physical> {
+    let condition = match &filter.node {
+        Node::Filter { condition } => condition,
+        _ => return None,
+    };
+    let scan = &filter.inputs[0];
+    let (table, columns) = match &scan.node {
+        Node::Scan { table, columns } => (table, columns),
+        _ => return None,
+    };
+    bounds(condition)?;
+    let field = &scan.schema.fields[scan.schema.position(&condition[0])?];
+    let operator = Operator::IndexScan {
+        index: indexes.get(table, field)?,
+        condition: condition.clone(),
+        columns: columns.clone(),
+    };
+    Some(Physical::new(operator, &filter.schema, vec![], rows))
+}
+
+// when the inner side is the scan of a table with an index on one of the JOIN fields
+fn index_join(on: Vec<String>, inner: &Plan, indexes: &Indexes) -> Option<Operator> {
+    let (table, columns) = match &inner.node {
+        Node::Scan { table, columns } => (table, columns),
+        _ => return None,
+    };
+    let index = conditions(&on).iter().find_map(|c| {
+        let i = inner
+            .schema
+            .position(&c[2])
+            .or_else(|| inner.schema.position(&c[0]))?;
+        indexes.get(table, &inner.schema.fields[i])
+    })?;
+    Some(Operator::IndexJoin {
+        on,
+        index,
+        inner: inner.schema.clone(),
+        columns: columns.clone(),
+    })
+}
+
+// which keys of the index a condition wants, None if it can't use one
+fn bounds(condition: &[String]) -> Option<(Bound<&str>, Bound<&str>)> {
+    let value = condition.get(2).map(String::as_str)?;
+    Some(match condition[1].as_str() {
+        "EQUALS" => (Bound::Included(value), Bound::Included(value)),
+        "GREATER" => (Bound::Excluded(value), Bound::Unbounded),
+        "GREATER_EQUALS" => (Bound::Included(value), Bound::Unbounded),
+        "LESS" => (Bound::Unbounded, Bound::Excluded(value)),
+        "LESS_EQUALS" => (Bound::Unbounded, Bound::Included(value)),
+        "BETWEEN" => (Bound::Included(value), Bound::Included(&condition[4])),
+        _ => return None,
+    })
+}
+
+// a seek costs about as much as reading a few rows in order
+const SEEK: f64 = 4.0;
+
+// comparing rows, more or less
+fn sort_cost(rows: f64) -> f64 {
+    rows * rows.max(2.0).log2()
+}
+
+/// What running the operator itself costs, counting more or
+/// less every row it touches, given the rows of its inputs.
+fn cost(operator: &Operator, inputs: &[f64], rows: f64) -> f64 {
+    let input = |i: usize| inputs.get(i).copied().unwrap_or(0.0);
+    match operator {
+        Operator::FileScan { .. } | Operator::HeapScan { .. } => rows,
+        Operator::IndexScan { .. } => SEEK * rows,
+        Operator::Empty => 0.0,
+        Operator::Values { .. } => rows,
+        // building the hash table is the expensive part
+        Operator::HashJoin { build_outer, .. } => match build_outer {
+            true => 2.0 * input(0) + input(1),
+            false => input(0) + 2.0 * input(1),
+        },
+        Operator::NestedJoin { .. } => input(0) * input(1) + input(1),
+        // the sorting is done by the Sorts below it
+        Operator::MergeJoin { .. } => input(0) + input(1),
+        // going down the tree for every outer row, then seeking to each match
+        Operator::IndexJoin { .. } => SEEK * (input(0) + rows),
+        Operator::Sort { .. } | Operator::Window { .. } => sort_cost(input(0)),
+        Operator::TopN { .. } => input(0) * rows.max(2.0).log2(),
+        _ => inputs.iter().sum(),
+    }
+}
+
+// whether ORDER is exactly the outer fields of the JOIN (ascending)
+fn sorts_by_outer(order: &[String], on: &[String], join: &Plan) -> bool {
+    let outer = &join.inputs[0].schema;
+    let keys: Vec<Option<usize>> = conditions(on)
+        .iter()
+        .map(|c| outer.position(&c[0]).or_else(|| outer.position(&c[2])))
+        .collect();
+    let order: Vec<Option<usize>> = order
+        .iter()
+        .filter(|o| *o != "ASC")
+        .map(|o| match o.as_str() {
+            "DESC" => None,
+            field => join.schema.position(field),
+        })
+        .collect();
+    !keys.is_empty() && keys == order
+}
+
+/// Every way of running a join, the cheapest first.
+fn joins(logical: &Plan, statistics: &Statistics, catalog: &Catalog) -> Vec<Physical> {
+    let on = match &logical.node {
+        Node::Join { on } => on.clone(),
+        _ => unreachable!(),
+    };
+    let rows = statistics.rows(logical);
+    let (outer, inner) = (&logical.inputs[0], &logical.inputs[1]);
+    let inputs = vec![
+        plan(outer, statistics, catalog),
+        plan(inner, statistics, catalog),
+    ];
+    let join = |operator: Operator, inputs: Vec<Physical>| {
+        Physical::new(operator, &logical.schema, inputs, rows)
+    };
+
+    let sorted = |side: &Plan, input: Physical| {
+        let order = conditions(&on)
+            .iter()
+            .map(|c| match side.schema.position(&c[0]) {
+                Some(i) => side.schema.fields[i].clone(),
+                None => c[2].clone(),
+            })
+            .collect();
+        let rows = input.rows;
+        Physical::new(Operator::Sort { order }, &side.schema, vec![input], rows)
+    };
+    let merge = vec![
+        sorted(outer, inputs[0].clone()),
+        sorted(inner, inputs[1].clone()),
+    ];
+    let build_outer = inputs[0].rows < inputs[1].rows;
+    let mut joins = vec![
+        join(
+            Operator::HashJoin {
+                on: on.clone(),
+                build_outer,
+            },
+            inputs.clone(),
+        ),
+        join(Operator::NestedJoin { on: on.clone() }, inputs.clone()),
+        join(Operator::MergeJoin { on: on.clone() }, merge),
+    ];
+    if let Some(operator) = index_join(on, inner, catalog.indexes()) {
+        joins.push(join(operator, vec![inputs[0].clone()]));
+    }
+    joins.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+    joins
+}
+
+impl Physical {
+    /// Builds the operators, nothing is read until the rows are pulled.
+    pub fn execute(&self) -> Rows<'_> {
+        let metrics = &self.metrics;
+        metrics.loops.set(metrics.loops.get() + 1);
+        let mut rows = self.operator();
+        Box::new(std::iter::from_fn(move || {
+            let start = Instant::now();
+            let row = rows.next();
+            metrics.time.set(metrics.time.get() + start.elapsed());
+            if row.is_some() {
+                metrics.rows.set(metrics.rows.get() + 1);
+            }
+            row
+        }))
+    }
+
+    /// The plan as a tree, one operator per line with what the planner
+    /// expected and, with `analyze` (after running it), what happened.
+    pub fn explain(&self, analyze: bool) -> String {
+        let mut lines = vec![];
+        self.explain_into(analyze, 0, &mut lines);
+        lines.join("\n")
+    }
+
+    fn explain_into(&self, analyze: bool, depth: usize, lines: &mut Vec<String>) {
+        let indent = match depth {
+            0 => String::new(),
+            _ => format!("{}->  ", "      ".repeat(depth - 1)),
+        };
+        let mut line = format!(
+            "{indent}{}  (cost={:.2} rows={:.0})",
+            self.describe(),
+            self.cost,
+            self.rows
+        );
+        if analyze {
+            let metrics = &self.metrics;
+            line += &format!(
+                " (actual time={:.3} ms rows={} loops={}",
+                metrics.time.get().as_secs_f64() * 1000.0,
+                metrics.rows.get(),
+                metrics.loops.get()
+            );
+            if metrics.pages.get() > 0 {
+                line += &format!(" pages={}", metrics.pages.get());
+            }
+            if metrics.memory.get() > 0 {
+                line += &format!(" memory={}kB", metrics.memory.get().div_ceil(1024));
+            }
+            line += ")";
+        }
+        lines.push(line);
+        for input in &self.inputs {
+            input.explain_into(analyze, depth + 1, lines);
+        }
+    }
+
+    fn describe(&self) -> String {
+        let parts = |parts: &[String]| parts.join(" ");
+        let fields = |fields: &[String]| fields.join(", ");
+        match &self.operator {
+            Operator::FileScan { table, columns } => match columns {
+                Some(_) => format!("FileScan on {table} ({})", fields(&self.schema.fields)),
+                None => format!("FileScan on {table}"),
+            },
+            Operator::HeapScan { table, columns } => match columns {
+                Some(_) => format!("HeapScan on {table} ({})", fields(&self.schema.fields)),
+                None => format!("HeapScan on {table}"),
+            },
+            Operator::IndexScan {
+                index, condition, ..
+            } => format!(
+                "IndexScan on {} using {} ({})",
+                index.table(),
+                index.field(),
+                parts(condition)
+            ),
+            Operator::Empty => "Empty".to_owned(),
+            Operator::Values { rows } => format!("Values ({} rows)", rows.len()),
+            Operator::Selector { condition } => format!("Selector ({})", parts(condition)),
+            Operator::SemiJoin { field, anti } => match anti {
+                true => format!("SemiJoin ({field} NOT_IN)"),
+                false => format!("SemiJoin ({field} IN)"),
+            },
+            Operator::Exists { anti } => match anti {
+                true => "Exists (NOT_EXISTS)".to_owned(),
+                false => "Exists".to_owned(),
+            },
+            Operator::Projector { fields: projected } => {
+                format!("Projector ({})", fields(projected))
+            }
+            Operator::HashJoin { on, build_outer } => match build_outer {
+                true => format!("HashJoin ({}) building outer", parts(on)),
+                false => format!("HashJoin ({}) building inner", parts(on)),
+            },
+            Operator::NestedJoin { on } => format!("NestedJoin ({})", parts(on)),
+            Operator::MergeJoin { on } => format!("MergeJoin ({})", parts(on)),
+            Operator::IndexJoin { on, index, .. } => format!(
+                "IndexJoin ({}) using {}.{}",
+                parts(on),
+                index.table(),
+                index.field()
+            ),
+            Operator::HashAggregate { group, aggregates } => format!(
+                "HashAggregate ({}) by ({})",
+                fields(aggregates),
+                fields(group)
+            ),
+            Operator::StreamAggregate { group, aggregates } => format!(
+                "StreamAggregate ({}) by ({})",
+                fields(aggregates),
+                fields(group)
+            ),
+            Operator::Window { functions } => format!("Window ({})", fields(functions)),
+            Operator::Sort { order } => format!("Sort ({})", parts(order)),
+            Operator::TopN { order, limit } => {
+                format!("TopN ({}) LIMIT {}", parts(order), parts(limit))
+            }
+            Operator::Limit { limit } => format!("Limit ({})", parts(limit)),
+            Operator::Distinct => "Distinct".to_owned(),
+            Operator::SetOperation { op } => format!("SetOperation ({op:?})"),
+            Operator::Scalars { names } => format!("Scalars ({})", fields(names)),
+            Operator::Reorder { fields: reordered } => {
+                format!("Reorder ({})", fields(reordered))
+            }
+        }
+    }
+
+    // counts the bytes of rows that end up kept in memory
+    fn held<'a>(&'a self, rows: Rows<'a>) -> Rows<'a> {
+        let memory = &self.metrics.memory;
+        Box::new(rows.inspect(move |row| {
+            let bytes: usize = row.iter().map(|f| f.len() + size_of::<String>()).sum();
+            memory.set(memory.get() + bytes + size_of::<Row>());
+        }))
+    }
+
+    fn operator(&self) -> Rows<'_> {
+        let input = |i: usize| self.inputs[i].execute();
+        let held = |i: usize| self.held(input(i));
+        let schema = |i: usize| &self.inputs[i].schema;
+        match &self.operator {
+            Operator::FileScan { table, columns } => {
+                let mut scan = match columns {
+                    Some(columns) => FileScan::with_columns(table, columns.clone()),
+                    None => FileScan::new(table),
+                };
+                let pages = &self.metrics.pages;
+                Box::new(std::iter::from_fn(move || {
+                    let row = scan.next();
+                    pages.set(scan.offset().div_ceil(8192));
+                    row
+                }))
+            }
+            Operator::HeapScan { table, columns } => {
+                let mut scan = HeapScan::new(table, columns.clone());
+                let pages = &self.metrics.pages;
+                Box::new(std::iter::from_fn(move || {
+                    let row = scan.next();
+                    pages.set(scan.pages_read());
+                    row
+                }))
+            }
+            Operator::IndexScan {
+                index,
+                condition,
+                columns,
+            } => {
+                let (low, high) = bounds(condition).unwrap();
+                let ptrs = index.ptrs(low, high);
+                // every row is a seek, so (about) a page each
+                self.metrics.pages.set(ptrs.len());
+                Box::new(IndexScan::new(index, ptrs, columns.clone()))
+            }
+            Operator::Empty => Box::new(std::iter::empty()),
+            Operator::Values { rows } => Box::new(rows.iter().cloned()),
+            Operator::Selector { condition } => {
+                Box::new(Selector::new(condition.clone(), input(0), schema(0)))
+            }
+            Operator::SemiJoin { field, anti } => {
+                Box::new(SemiJoin::new(field, *anti, input(0), input(1), schema(0)))
+            }
+            Operator::Exists { anti } => {
+                if input(1).next().is_some() != *anti {
+                    input(0)
+                } else {
+                    Box::new(std::iter::empty())
+                }
+            }
+            Operator::Projector { fields } => {
+                Box::new(Projector::new(fields.clone(), input(0), schema(0)))
+            }
+            Operator::HashJoin { on, build_outer } => {
+                let on = on.clone();
+                match build_outer {
+                    true => Box::new(
+                        HashJoin::new(held(0), input(1), schema(0), schema(1), on).build_outer(),
+                    ),
+                    false => Box::new(HashJoin::new(input(0), held(1), schema(0), schema(1), on)),
+                }
+            }
+            Operator::NestedJoin { on } => Box::new(NestedJoin::new(
+                input(0),
+                Buffered::new(&schema(1).table, held(1)),
+                schema(0),
+                schema(1),
+                on.clone(),
+            )),
+            Operator::MergeJoin { on } => Box::new(MergeJoin::new(
+                input(0),
+                input(1),
+                schema(0),
+                schema(1),
+                on.clone(),
+            )),
+            Operator::IndexJoin {
+                on,
+                index,
+                inner,
+                columns,
+            } => Box::new(IndexJoin::new(
+                input(0),
+                index,
+                columns.clone(),
+                schema(0),
+                inner,
+                on.clone(),
+            )),
+            // all the groups are kept until the end
+            Operator::HashAggregate { group, aggregates } => {
+                self.held(Box::new(HashAggregate::new(
+                    group,
+                    aggregate::aggregates(aggregates, schema(0)),
+                    input(0),
+                    schema(0),
+                )))
+            }
+            Operator::StreamAggregate { group, aggregates } => Box::new(StreamAggregate::new(
+                group,
+                aggregate::aggregates(aggregates, schema(0)),
+                input(0),
+                schema(0),
+            )),
+            Operator::Window { functions } => {
+                Box::new(Window::new(window::windows(functions), held(0), schema(0)))
+            }
+            Operator::Sort { order } => Box::new(Sort::new(order.clone(), held(0), schema(0))),
+            Operator::TopN { order, limit } => {
+                Box::new(TopN::new(order.clone(), limit, input(0), schema(0)))
+            }
+            Operator::Limit { limit } => Box::new(Limit::new(limit.clone(), input(0))),
+            // the rows it already returned are kept to spot duplicates
+            Operator::Distinct => self.held(Box::new(Distinct::new(input(0)))),
+            Operator::SetOperation { op } => Box::new(SetOperation::new(*op, input(0), held(1))),
+            Operator::Scalars { .. } => {
+                let values = self.inputs[1..]
+                    .iter()
+                    .map(|subquery| subquery::scalar(subquery.execute().collect()))
+                    .collect();
+                Box::new(Scalars::new(values, input(0)))
+            }
+            Operator::Reorder { fields } => {
+                let idxs: Vec<usize> = fields
+                    .iter()
+                    .map(|f| schema(0).position(f).unwrap())
+                    .collect();
+                Box::new(input(0).map(move |mut row| {
+                    idxs.iter().map(|i| std::mem::take(&mut row[*i])).collect()
+                }))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_picks_top_n_and_stream_aggregate() {
+    let scan = Plan {
+        node: Node::Scan {
+            table: "ratings".into(),
+            columns: None,
+        },
+        schema: Schema {
+            table: "ratings".into(),
+            fields: vec!["movieId".into(), "rating".into()],
+        },
+        inputs: vec![],
+    };
+    let movie = vec!["movieId".to_owned()];
+    let logical = scan
+        .aggregate(movie.clone(), vec!["AVG(rating)".into()])
+        .sort(movie.clone())
+        .limit(vec!["10".into()]);
+
+    let statistics = Statistics::default();
+    let physical = plan(&logical, &statistics, &Catalog::new("test"));
+    let operators = |p: &Physical| -> Vec<Operator> {
+        let mut operators = vec![];
+        let mut curr = p;
+        loop {
+            operators.push(curr.operator.clone());
+            match curr.inputs.first() {
+                Some(input) => curr = input,
+                None => return operators,
+            }
+        }
+    };
+    assert!(matches!(
+        operators(&physical)[..],
+        [
+            Operator::Limit { .. },
+            Operator::TopN { .. },
+            Operator::HashAggregate { .. },
+            Operator::FileScan { .. }
+        ]
+    ));
+
+    // without the LIMIT the sort over the groups becomes a streaming aggregate
+    let physical = plan(&logical.inputs[0], &statistics, &Catalog::new("test"));
+    assert!(matches!(
+        operators(&physical)[..],
+        [
+            Operator::StreamAggregate { .. },
+            Operator::Sort { .. },
+            Operator::FileScan { .. }
+        ]
+    ));
+    assert_eq!(physical.schema.fields, vec!["movieId", "AVG(rating)"]);
+}
+
+#[test]
+fn test_picks_join_algorithm() {
+    use crate::plan::cost::{ColumnStats, TableStats};
+
+    let scan = |table: &str| Plan {
+        node: Node::Scan {
+            table: table.into(),
+            columns: None,
+        },
+        schema: Schema {
+            table: table.into(),
+            fields: vec!["movieId".into(), "title".into()],
+        },
+        inputs: vec![],
+    };
+    let mut statistics = Statistics::default();
+    for (table, rows) in [
+        ("movies", 100_000.0),
+        ("ratings", 1_000_000.0),
+        ("one", 1.0),
+    ] {
+        let stats = TableStats {
+            rows,
+            ..Default::default()
+        };
+        statistics.insert(table, stats);
+    }
+    let on = |outer: &str, inner: &str| -> Vec<String> {
+        vec![
+            format!("{outer}.movieId"),
+            "EQUALS".into(),
+            format!("{inner}.movieId"),
+        ]
+    };
+
+    // hashes the smaller side, even if it's the outer one
+    let join = scan("movies").join(scan("ratings"), on("movies", "ratings"));
+    assert_eq!(
+        plan(&join, &statistics, &Catalog::new("test")).operator,
+        Operator::HashJoin {
+            on: on("movies", "ratings"),
+            build_outer: true
+        }
+    );
+
+    // a single row isn't worth hashing
+    let join = scan("movies").join(scan("one"), on("movies", "one"));
+    assert!(matches!(
+        plan(&join, &statistics, &Catalog::new("test")).operator,
+        Operator::NestedJoin { .. }
+    ));
+
+    // sorting a lot of joined rows by the join field anyway, so merge it
+    for table in ["genres", "tags"] {
+        let stats = TableStats {
+            rows: 10_000.0,
+            columns: [(
+                "movieId".to_owned(),
+                ColumnStats {
+                    distinct: 10.0,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        };
+        statistics.insert(table, stats);
+    }
+    let sorted = scan("genres")
+        .join(scan("tags"), on("genres", "tags"))
+        .sort(vec!["genres.movieId".into()]);
+    let physical = plan(&sorted, &statistics, &Catalog::new("test"));
+    assert!(matches!(physical.operator, Operator::MergeJoin { .. }));
+    assert!(physical
+        .inputs
+        .iter()
+        .all(|input| matches!(input.operator, Operator::Sort { .. })));
+}
+
+#[test]
+fn test_explain_analyze() {
+    let schema = Schema {
+        table: "ratings".into(),
+        fields: vec!["movieId".into(), "rating".into()],
+    };
+    let logical = Plan::empty(schema)
+        .filter(vec!["rating".into(), "GREATER".into(), "4".into()])
+        .sort(vec!["movieId".into()]);
+    let physical = plan(&logical, &Statistics::default(), &Catalog::new("test"));
+
+    let explained = physical.explain(false);
+    let lines: Vec<&str> = explained.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("Sort (movieId)  (cost="));
+    assert!(lines[1].starts_with("->  Selector (rating GREATER 4)"));
+    assert!(lines[2].starts_with("      ->  Empty  (cost=0.00 rows=0)"));
+
+    assert_eq!(physical.execute().count(), 0);
+    assert_eq!(physical.execute().count(), 0);
+    let explained = physical.explain(true);
+    assert!(explained
+        .lines()
+        .all(|line| line.contains("rows=0 loops=2")));
+}
+
+#[test]
+fn test_picks_index_scan() {
+    use crate::index::IndexBuilder;
+    use crate::plan::cost::{ColumnStats, TableStats};
+    use crate::source::Buffered;
+
+    let schema = Schema {
+        table: "movies".into(),
+        fields: vec!["movieId".into(), "title".into()],
+    };
+    let scan = Plan {
+        node: Node::Scan {
+            table: "movies".into(),
+            columns: None,
+        },
+        schema: schema.clone(),
+        inputs: vec![],
+    };
+    let mut statistics = Statistics::default();
+    let id = ColumnStats {
+        distinct: 100_000.0,
+        histogram: vec!["1".into(), "100000".into()],
+        ..Default::default()
+    };
+    let stats = TableStats {
+        rows: 100_000.0,
+        columns: [("movieId".to_owned(), id)].into(),
+    };
+    statistics.insert("movies", stats);
+    let mut source = Buffered::new("movies", std::iter::empty());
+    let mut catalog = Catalog::new("test");
+    catalog.add_index(
+        IndexBuilder::new("movieId", &mut source, &schema)
+            .next()
+            .unwrap(),
+    );
+
+    let filter = |condition: &[&str]| {
+        let condition = condition.iter().map(|c| c.to_string()).collect();
+        plan(&scan.clone().filter(condition), &statistics, &catalog)
+    };
+    let equals = filter(&["movieId", "EQUALS", "5000"]);
+    assert!(matches!(equals.operator, Operator::IndexScan { .. }));
+    assert!(equals.inputs.is_empty());
+    let between = filter(&["movies.movieId", "BETWEEN", "100", "AND", "200"]);
+    assert!(matches!(between.operator, Operator::IndexScan { .. }));
+    assert!((between.rows - 100.0).abs() < 1.0);
+
+    // reading (almost) everything in order beats seeking to each row
+    let most = filter(&["movieId", "GREATER", "100"]);
+    assert!(matches!(most.operator, Operator::Selector { .. }));
+    // and there's no index on the title
+    let title = filter(&["title", "EQUALS", "Heat (1995)"]);
+    assert!(matches!(title.operator, Operator::Selector { .. }));
+}
+
+#[test]
+fn test_picks_index_join() {
+    use crate::index::IndexBuilder;
+    use crate::plan::cost::{ColumnStats, TableStats};
+    use crate::source::Buffered;
+
+    let scan = |table: &str, fields: &[&str]| Plan {
+        node: Node::Scan {
+            table: table.into(),
+            columns: None,
+        },
+        schema: Schema {
+            table: table.into(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+        },
+        inputs: vec![],
+    };
+    let ratings = scan("ratings", &["userId", "movieId", "rating"]);
+    let movies = scan("movies", &["movieId", "title"]);
+    let mut statistics = Statistics::default();
+    let distinct = |field: &str, distinct: f64| {
+        let stats = ColumnStats {
+            distinct,
+            ..Default::default()
+        };
+        (field.to_owned(), stats)
+    };
+    statistics.insert(
+        "ratings",
+        TableStats {
+            rows: 1_000_000.0,
+            columns: [distinct("userId", 10_000.0), distinct("movieId", 100_000.0)].into(),
+        },
+    );
+    statistics.insert(
+        "movies",
+        TableStats {
+            rows: 100_000.0,
+            columns: [distinct("movieId", 100_000.0)].into(),
+        },
+    );
+    let mut source = Buffered::new("movies", std::iter::empty());
+    let mut catalog = Catalog::new("test");
+    catalog.add_index(
+        IndexBuilder::new("movieId", &mut source, &movies.schema)
+            .next()
+            .unwrap(),
+    );
+    let on = vec![
+        "ratings.movieId".to_owned(),
+        "EQUALS".into(),
+        "movies.movieId".into(),
+    ];
+
+    // the ratings of one user are only a few lookups
+    let user = vec!["userId".to_owned(), "EQUALS".into(), "1".into()];
+    let join = ratings
+        .clone()
+        .filter(user)
+        .join(movies.clone(), on.clone());
+    let physical = plan(&join, &statistics, &catalog);
+    assert!(matches!(physical.operator, Operator::IndexJoin { .. }));
+    assert_eq!(physical.inputs.len(), 1);
+    assert!(matches!(
+        physical.inputs[0].operator,
+        Operator::Selector { .. }
+    ));
+
+    // but seeking for every rating is worse than hashing the movies
+    let join = ratings.join(movies, on);
+    let physical = plan(&join, &statistics, &catalog);
+    assert!(matches!(physical.operator, Operator::HashJoin { .. }));
+}
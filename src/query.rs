@@ -1,13 +1,57 @@
 use serde_json::Value;
 
+use crate::subquery;
+
 type Parts = Vec<String>;
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct Query {
     pub projection: Option<Parts>, // fields/attributes
     pub selection: Option<Parts>,  // conditions
     pub scan: Option<Parts>,       // tables
     pub join: Option<Parts>,       // conditions
+    pub group: Option<Parts>,      // fields
+    pub having: Option<Parts>,     // conditions (over the groups)
+    pub qualify: Option<Parts>,    // conditions (over the window functions)
+    pub order: Option<Parts>,      // fields w/ optional ASC/DESC
+    pub limit: Option<Parts>,      // count w/ optional OFFSET
+    pub distinct: bool,
+    pub checkpoint: bool,
+    pub analyze: Option<Parts>,      // tables to gather statistics for
+    pub explain: Option<Parts>,      // optionally ANALYZE
+    pub create_table: Option<Parts>, // table, then each column's name and type
+    pub drop_table: Option<Parts>,   // tables
+    pub insert: Option<Parts>,       // table, the rows are VALUES or the rest of the query
+    pub values: Option<Parts>,       // one row after the other
+    pub update: Option<Parts>,       // table, only the rows in SELECTION
+    pub assignments: Option<Parts>,  // SET, each field and its new value
+    pub delete: Option<Parts>,       // table, only the rows in SELECTION
+    pub copy: Option<Parts>,         // table FROM file, optionally WITH options
+    pub transaction: Option<String>, // BEGIN, COMMIT or ROLLBACK
+    // UNION, UNION_ALL, INTERSECT or EXCEPT with another query block
+    pub set: Option<(String, Box<Query>)>,
+    // nested query blocks, referred to in the parts (see subquery::marker)
+    pub subqueries: Vec<Query>,
+}
+
+fn parts(clause: &Value, subqueries: &mut Vec<Query>) -> Parts {
+    // lol
+    clause[1]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|a| match a.as_str() {
+            Some(part) => {
+                subquery::check(part);
+                part.to_owned()
+            }
+            // a whole query block in place of a part (a subquery)
+            None => {
+                subqueries.push(Query::from(a.clone()));
+                subquery::marker(subqueries.len() - 1)
+            }
+        })
+        .collect()
 }
 
 impl From<Value> for Query {
@@ -15,48 +59,78 @@ impl From<Value> for Query {
         let mut query = Query::default();
         for clause in json.as_array().unwrap() {
             if clause[0] == "PROJECTION" {
-                // lol
-                query.projection = Some(
-                    clause[1]
-                        .as_array()
-                        .unwrap()
-                        .into_iter()
-                        .map(|a| a.as_str().unwrap().to_owned())
-                        .collect(),
-                );
+                query.projection = Some(parts(clause, &mut query.subqueries));
             }
             if clause[0] == "SELECTION" {
-                // lol
-                query.selection = Some(
-                    clause[1]
-                        .as_array()
-                        .unwrap()
-                        .into_iter()
-                        .map(|a| a.as_str().unwrap().to_owned())
-                        .collect(),
-                );
+                query.selection = Some(parts(clause, &mut query.subqueries));
             }
             if clause[0] == "SCAN" {
-                // lol
-                query.scan = Some(
-                    clause[1]
-                        .as_array()
-                        .unwrap()
-                        .into_iter()
-                        .map(|a| a.as_str().unwrap().to_owned())
-                        .collect(),
-                );
+                query.scan = Some(parts(clause, &mut query.subqueries));
             }
             if clause[0] == "JOIN" {
-                // lol
-                query.join = Some(
-                    clause[1]
-                        .as_array()
-                        .unwrap()
-                        .into_iter()
-                        .map(|a| a.as_str().unwrap().to_owned())
-                        .collect(),
-                );
+                query.join = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "GROUP" {
+                query.group = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "HAVING" {
+                query.having = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "QUALIFY" {
+                query.qualify = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "ORDER" {
+                query.order = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "LIMIT" {
+                query.limit = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "EXPLAIN" {
+                query.explain = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "ANALYZE" {
+                query.analyze = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "CREATE_TABLE" {
+                query.create_table = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "DROP_TABLE" {
+                query.drop_table = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "INSERT" {
+                query.insert = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "VALUES" {
+                query.values = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "UPDATE" {
+                query.update = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "SET" {
+                query.assignments = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "DELETE" {
+                query.delete = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "COPY" {
+                query.copy = Some(parts(clause, &mut query.subqueries));
+            }
+            if clause[0] == "DISTINCT" {
+                query.distinct = true;
+            }
+            if clause[0] == "CHECKPOINT" {
+                query.checkpoint = true;
+            }
+            for command in ["BEGIN", "COMMIT", "ROLLBACK"] {
+                if clause[0] == command {
+                    query.transaction = Some(command.to_owned());
+                }
+            }
+            for op in ["UNION", "UNION_ALL", "INTERSECT", "EXCEPT"] {
+                if clause[0] == op {
+                    // the other side is a whole query, clauses and all
+                    query.set = Some((op.to_owned(), Box::new(Query::from(clause[1].clone()))));
+                }
             }
         }
         query
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::buffer;
+use crate::data;
+use crate::fs::{init, kill, page_lsn, put, set_page_lsn, set_xmax, slots};
+use crate::wal::{self, Lsn, Record};
+use crate::xact::{self, Status};
+
+/// Brings the heap files up to date with the log after a crash, and
+/// the status of every transaction in it, the ones that didn't get
+/// to finish aborted. Has to run before anything else opens the log.
+/// What's after the last complete record is cut off, new records go
+/// right after it. Returns how many changes were redone.
+pub fn recover() -> Result<usize, io::Error> {
+    let path = wal::path();
+    if !Path::new(&path).exists() {
+        return Ok(0);
+    }
+    // the pages (and statuses) have everything before the last checkpoint
+    let from = wal::redo_point(&path)?;
+    let records = wal::read(&path, from)?;
+    let end = records.last().map_or(from, |(lsn, _)| *lsn);
+    wal::truncate(&path, end)?;
+    let redone = redo(&records, None)?;
+    for (_, record) in &records {
+        match record {
+            Record::Commit { xid } => xact::set(*xid, Status::Committed)?,
+            Record::Abort { xid } => xact::set(*xid, Status::Aborted)?,
+            _ => {}
+        }
+    }
+    if let Some(xid) = records.iter().filter_map(|(_, r)| r.xid()).max() {
+        wal::with(|wal| wal.seen(xid));
+    }
+    // the ones that didn't commit never will, even if they began
+    // before the checkpoint (and their records aren't read again).
+    // Their changes stay where they are, nobody sees them
+    let (next, _) = wal::with(|wal| wal.running());
+    xact::abort_unfinished(next)?;
+    Ok(redone)
+}
+
+/// Writes every page changed until now (and the transaction
+/// statuses), so recovery can start from here, and gets rid of the
+/// log before it.
+pub fn checkpoint() -> Result<Lsn, io::Error> {
+    let redo = wal::with(|wal| wal.lsn());
+    buffer::with(|pool| pool.sync())?;
+    xact::sync()?;
+    wal::with(|wal| wal.checkpoint(redo))?;
+    Ok(redo)
+}
+
+/// Redoes (redo only, there's no undo) every change a page doesn't
+/// have yet, the ones with an LSN newer than the page's. Only the
+/// table's if there's one. Returns how many were redone.
+pub fn redo(records: &[(Lsn, Record)], table: Option<&str>) -> Result<usize, io::Error> {
+    // whatever the log has from before a table was created or removed is gone
+    let mut reset: HashMap<&str, Lsn> = HashMap::new();
+    for (lsn, record) in records {
+        if let Record::Create { table } | Record::Remove { table } = record {
+            reset.insert(table, *lsn);
+        }
+    }
+    let mut exists: HashMap<String, bool> = HashMap::new();
+    let mut redone = 0;
+    buffer::with(|pool| {
+        for (lsn, record) in records {
+            let page = match record {
+                Record::Init { page } => page,
+                Record::Insert { page, .. } => page,
+                Record::Delete { page, .. } => page,
+                _ => continue,
+            };
+            if table.is_some_and(|t| t != page.table)
+                || reset.get(page.table.as_str()).is_some_and(|r| r >= lsn)
+            {
+                continue;
+            }
+            // removed without the log knowing
+            let there = exists
+                .entry(page.table.clone())
+                .or_insert_with(|| Path::new(&data::path(&page.table)).exists());
+            if !*there {
+                continue;
+            }
+            let frame = pool.pin(page)?;
+            let data = pool.page_mut(frame);
+            let stale = page_lsn(data) < *lsn;
+            if stale {
+                match record {
+                    Record::Init { .. } => init(data, *lsn),
+                    Record::Insert { slot, tuple, .. } => {
+                        assert_eq!(slots(data), *slot as usize, "redo of insert into {page:?}");
+                        put(data, tuple);
+                    }
+                    Record::Delete { xid: 0, slot, .. } => kill(data, *slot as usize),
+                    Record::Delete { xid, slot, .. } => set_xmax(data, *slot as usize, *xid),
+                    _ => unreachable!(),
+                }
+                set_page_lsn(data, *lsn);
+                redone += 1;
+            }
+            pool.unpin(frame, stale);
+        }
+        for table in exists.keys() {
+            pool.flush(table)?;
+        }
+        Ok::<_, io::Error>(())
+    })?;
+    Ok(redone)
+}
+
+#[test]
+fn test_recovery() {
+    use crate::buffer::PageId;
+    use crate::fs::{Heap, HeapFile, Tid, PAGE};
+    use crate::source::Row;
+
+    data::test_dir("test_recovery");
+    let table = "test_recovery";
+    let path = data::path(table);
+    let lsn_of = |page: usize| {
+        buffer::with(|pool| {
+            let frame = pool.pin(&PageId::new(table, page)).unwrap();
+            let lsn = page_lsn(pool.page(frame));
+            pool.unpin(frame, false);
+            lsn
+        })
+    };
+    // not random, but all over the place
+    let mut seed = 42u64;
+    let mut next = move |n: usize| {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 33) as usize % n
+    };
+
+    HeapFile::remove(table).unwrap();
+    let mut heap = HeapFile::create(table, 0).unwrap();
+    heap.flush().unwrap();
+    let start = lsn_of(0);
+    // the file whenever it's flushed (what a crash can leave), and
+    // every change with the LSN of its record
+    let mut files = vec![(start, std::fs::read(&path).unwrap())];
+    let mut changes: Vec<(Lsn, Tid, Option<Row>)> = vec![];
+    let mut live: Vec<Tid> = vec![];
+    for i in 0..300 {
+        if live.is_empty() || next(5) > 0 {
+            let row = vec![i.to_string(), "x".repeat(100)];
+            heap.insert(&row).unwrap();
+            let page = heap.pages() - 1;
+            let tid = Tid {
+                page,
+                slot: heap.slots_in(page) - 1,
+            };
+            live.push(tid);
+            changes.push((lsn_of(page), tid, Some(row)));
+        } else {
+            let tid = live.remove(next(live.len()));
+            assert!(heap.delete_slot(tid.page, tid.slot).unwrap());
+            changes.push((lsn_of(tid.page), tid, None));
+        }
+        if i % 25 == 24 {
+            heap.flush().unwrap();
+            let lsn = (0..heap.pages()).map(lsn_of).max().unwrap();
+            files.push((lsn, std::fs::read(&path).unwrap()));
+        }
+    }
+    drop(heap);
+    let end = changes.last().unwrap().0;
+    assert!(files.last().unwrap().1.len() >= 4 * PAGE);
+    let records = wal::read(&wal::path(), start).unwrap();
+
+    let mut redone = 0;
+    for _ in 0..30 {
+        // the log made it up to somewhere, and every page is one of
+        // the versions that were written before that
+        let crash = start + next((end - start) as usize + 1) as Lsn;
+        let written: Vec<&Vec<u8>> = files
+            .iter()
+            .filter(|(lsn, _)| *lsn <= crash)
+            .map(|(_, file)| file)
+            .collect();
+        let pages = written.last().unwrap().len() / PAGE;
+        let mut file = vec![];
+        for page in 0..pages {
+            let version = written[next(written.len())];
+            match version.get(page * PAGE..(page + 1) * PAGE) {
+                Some(bytes) => file.extend(bytes),
+                None => file.extend([0; PAGE]),
+            }
+        }
+        buffer::with(|pool| pool.forget(table));
+        std::fs::write(&path, file).unwrap();
+
+        let logged: Vec<_> = records
+            .iter()
+            .filter(|(lsn, _)| *lsn <= crash)
+            .cloned()
+            .collect();
+        redone += redo(&logged, Some(table)).unwrap();
+        // from the file, not the pool
+        buffer::with(|pool| pool.forget(table));
+
+        let mut expected: Vec<(Tid, Row)> = vec![];
+        for (_, tid, row) in changes.iter().filter(|(lsn, ..)| *lsn <= crash) {
+            match row {
+                Some(row) => expected.push((*tid, row.clone())),
+                None => expected.retain(|(t, _)| t != tid),
+            }
+        }
+        let rows: Vec<Row> = HeapFile::open(table, 0)
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        let expected: Vec<Row> = expected.into_iter().map(|(_, row)| row).collect();
+        assert_eq!(rows, expected, "crashed at {crash}");
+    }
+    assert!(redone > 0);
+}
+
+#[test]
+fn test_recover_unfinished() {
+    use crate::catalog::Table;
+    use crate::dml;
+    use crate::index::Indexes;
+    use crate::transaction::{self, Transaction};
+
+    data::test_dir("test_recover_unfinished");
+    let table = Table::heap("test_unfinished", &[("movieId", "INTEGER")]);
+    transaction::autocommit(None, |xid| {
+        dml::insert(&table, vec![vec!["1".into()]], xid, &Indexes::default())
+    })
+    .unwrap();
+    let transaction = Transaction::begin();
+    dml::delete(&table, None, transaction.xid).unwrap();
+    // the delete is in the pages now, recovery doesn't read its record
+    checkpoint().unwrap();
+
+    buffer::crash();
+    wal::crash();
+    xact::crash();
+    recover().unwrap();
+    assert_eq!(xact::status(transaction.xid), Status::Aborted);
+    // the row is still there, and another transaction can delete it
+    let deleted = transaction::autocommit(None, |xid| dml::delete(&table, None, xid));
+    assert_eq!(deleted.unwrap(), 1);
+}
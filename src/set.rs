@@ -0,0 +1,143 @@
+use std::collections::HashSet;
+
+use crate::source::{Row, Rows};
+
+/// SELECT DISTINCT, streams and drops every row it has already returned.
+pub struct Distinct<'a> {
+    source: Rows<'a>,
+    seen: HashSet<Row>,
+}
+
+impl<'a> Distinct<'a> {
+    pub fn new(source: impl Iterator<Item = Row> + 'a) -> Self {
+        Self {
+            source: Box::new(source),
+            seen: HashSet::new(),
+        }
+    }
+}
+
+impl<'a> Iterator for Distinct<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let seen = &mut self.seen;
+        Iterator::find(&mut self.source, |row| seen.insert(row.clone()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+
+impl From<&str> for Operation {
+    fn from(op: &str) -> Self {
+        match op {
+            "UNION" => Operation::Union,
+            "UNION_ALL" => Operation::UnionAll,
+            "INTERSECT" => Operation::Intersect,
+            "EXCEPT" => Operation::Except,
+            _ => panic!("unknown set operation '{op}'"),
+        }
+    }
+}
+
+/// Combines two query blocks (which should have the same fields),
+/// everything but UNION ALL removes duplicates like SQL does.
+/// INTERSECT and EXCEPT hash the right side first and then
+/// stream the left one through it.
+pub struct SetOperation<'a> {
+    op: Operation,
+    left: Rows<'a>,
+    right: Rows<'a>,
+    // rows from the right side, only for INTERSECT/EXCEPT
+    built: Option<HashSet<Row>>,
+    seen: HashSet<Row>,
+    left_done: bool,
+}
+
+impl<'a> SetOperation<'a> {
+    pub fn new(
+        op: Operation,
+        left: impl Iterator<Item = Row> + 'a,
+        right: impl Iterator<Item = Row> + 'a,
+    ) -> Self {
+        Self {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+            built: None,
+            seen: HashSet::new(),
+            left_done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for SetOperation<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.op {
+            Operation::UnionAll | Operation::Union => loop {
+                let row = if self.left_done {
+                    self.right.next()?
+                } else {
+                    match self.left.next() {
+                        Some(row) => row,
+                        None => {
+                            self.left_done = true;
+                            continue;
+                        }
+                    }
+                };
+                if self.op == Operation::UnionAll || self.seen.insert(row.clone()) {
+                    return Some(row);
+                }
+            },
+            Operation::Intersect | Operation::Except => {
+                if self.built.is_none() {
+                    self.built = Some((&mut *self.right).collect());
+                }
+                let built = self.built.as_ref()?;
+                let keep = self.op == Operation::Intersect;
+                let seen = &mut self.seen;
+                Iterator::find(&mut self.left, |row| {
+                    built.contains(row) == keep && seen.insert(row.clone())
+                })
+            }
+        }
+    }
+}
+
+#[test]
+fn test_set_operations() {
+    let rows = |ids: &[&str]| -> Vec<Row> { ids.iter().map(|id| vec![id.to_string()]).collect() };
+    let run = |op, left: &[&str], right: &[&str]| -> Vec<Row> {
+        let mut left = rows(left).into_iter();
+        let mut right = rows(right).into_iter();
+        SetOperation::new(op, &mut left, &mut right).collect()
+    };
+    let left = ["1", "2", "2", "3"];
+    let right = ["3", "4", "4"];
+
+    assert_eq!(
+        run(Operation::UnionAll, &left, &right),
+        rows(&["1", "2", "2", "3", "3", "4", "4"])
+    );
+    assert_eq!(
+        run(Operation::Union, &left, &right),
+        rows(&["1", "2", "3", "4"])
+    );
+    assert_eq!(run(Operation::Intersect, &left, &right), rows(&["3"]));
+    assert_eq!(run(Operation::Except, &left, &right), rows(&["1", "2"]));
+
+    let mut source = rows(&left).into_iter();
+    assert_eq!(
+        Distinct::new(&mut source).collect::<Vec<_>>(),
+        rows(&["1", "2", "3"])
+    );
+}
@@ -1,9 +1,17 @@
-use crate::fs::{buf_reader, read_lines};
+use crate::fs::{buf_reader, read_lines, Heap, HeapFile, Tid};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::fs::File;
 use std::io::{self, BufRead, Seek, SeekFrom};
+use std::iter::Peekable;
 
 // this is lame IMO
-pub trait Source: Offset + Metadata + Reset {}
+pub trait Source: Offset + Metadata + Reset {
+    /// Where the last row returned is, only heap tables have one.
+    fn tid(&self) -> Option<Tid> {
+        None
+    }
+}
 
 pub trait Offset: Iterator<Item = Row> {
     fn offset(&self) -> usize;
@@ -21,6 +29,8 @@ pub struct FileScan {
     offset: usize,
     table: String,
     file: io::BufReader<File>,
+    // the positions to keep, all of them if None
+    columns: Option<Vec<usize>>,
 }
 
 impl FileScan {
@@ -33,6 +43,16 @@ impl FileScan {
             offset,
             table: table.to_owned(),
             file,
+            columns: None,
+        }
+    }
+
+    /// Only turns the given columns (positions in the file, ascending)
+    /// into Strings, the rest of each line is just skipped over.
+    pub fn with_columns(table: &str, columns: Vec<usize>) -> Self {
+        Self {
+            columns: Some(columns),
+            ..Self::new(table)
         }
     }
 }
@@ -59,9 +79,107 @@ impl Reset for FileScan {
 
 impl Source for FileScan {}
 
+/// Reads every page of a heap table (see fs::HeapFile), skipping
+/// the deleted rows. Its offset is the next slot it reads, as
+/// `page * 8192 + slot`.
+pub struct HeapScan {
+    table: String,
+    // all of its pages, None once they were all read
+    heap: Option<HeapFile>,
+    page: usize,
+    slot: usize,
+    // of the last row returned
+    tid: Option<Tid>,
+    // the positions to keep, all of them if None
+    columns: Option<Vec<usize>>,
+}
+
+impl HeapScan {
+    pub fn new(table: &str, columns: Option<Vec<usize>>) -> Self {
+        let mut scan = Self {
+            table: table.to_owned(),
+            heap: None,
+            page: 0,
+            slot: 0,
+            tid: None,
+            columns,
+        };
+        scan.reset();
+        scan
+    }
+
+    /// Pages read so far.
+    pub fn pages_read(&self) -> usize {
+        self.page + self.heap.is_some() as usize
+    }
+}
+
+impl Iterator for HeapScan {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let heap = self.heap.as_mut()?;
+            if self.slot >= heap.slots_in(self.page) {
+                if self.page + 1 >= heap.pages() {
+                    self.heap = None;
+                    self.page += 1;
+                    self.slot = 0;
+                    return None;
+                }
+                self.page += 1;
+                self.slot = 0;
+                continue;
+            }
+            let tid = Tid {
+                page: self.page,
+                slot: self.slot,
+            };
+            self.slot += 1;
+            if let Some(row) = heap.fetch(tid).unwrap() {
+                self.tid = Some(tid);
+                return Some(match &self.columns {
+                    Some(columns) => columns.iter().map(|i| row[*i].clone()).collect(),
+                    None => row,
+                });
+            }
+        }
+    }
+}
+
+impl Offset for HeapScan {
+    fn offset(&self) -> usize {
+        8192 * self.page + self.slot
+    }
+}
+
+impl Metadata for HeapScan {
+    fn table(&self) -> &str {
+        &self.table
+    }
+}
+
+impl Reset for HeapScan {
+    fn reset(&mut self) {
+        self.heap = Some(HeapFile::open(&self.table, 0).unwrap());
+        self.page = 0;
+        self.slot = 0;
+        self.tid = None;
+    }
+}
+
+impl Source for HeapScan {
+    fn tid(&self) -> Option<Tid> {
+        self.tid
+    }
+}
+
 // Tuple
 pub type Row = Vec<String>;
 
+// what every operator pulls from
+pub type Rows<'a> = Box<dyn Iterator<Item = Row> + 'a>;
+
 impl Iterator for FileScan {
     type Item = Row;
 
@@ -73,19 +191,23 @@ impl Iterator for FileScan {
         }
         self.offset += read;
 
-        // TODO: abstract
-        Some(
-            raw.split(|b| *b == b',')
-                .map(|bytes| std::str::from_utf8(bytes))
-                .map(Result::unwrap)
-                .map(|s| s.trim())
-                .map(|s| s.to_owned())
-                .collect(),
-        )
+        Some(parse(&raw, self.columns.as_deref()))
     }
 }
 
-#[derive(Clone, Debug)]
+/// A CSV line into a row, only with the given columns (if any).
+pub fn parse(raw: &[u8], columns: Option<&[usize]>) -> Row {
+    raw.split(|b| *b == b',')
+        .enumerate()
+        .filter(|(i, _)| columns.is_none_or(|c| c.contains(i)))
+        .map(|(_, bytes)| std::str::from_utf8(bytes))
+        .map(Result::unwrap)
+        .map(|s| s.trim())
+        .map(|s| s.to_owned())
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Schema {
     pub table: String,
     pub fields: Vec<String>,
@@ -106,12 +228,60 @@ impl Schema {
             table: table.to_owned(),
         }
     }
+
+    /// Where a field is, it can be qualified with the table (`ratings.rating`),
+    /// and joined schemas (whose fields are all qualified) also find it
+    /// unqualified if only one of the tables has it.
+    pub fn position(&self, field: &str) -> Option<usize> {
+        if let Some(i) = self.fields.iter().position(|f| f == field) {
+            return Some(i);
+        }
+        if let Some(unqualified) = field
+            .strip_prefix(self.table.as_str())
+            .and_then(|f| f.strip_prefix('.'))
+        {
+            if let Some(i) = self.fields.iter().position(|f| f == unqualified) {
+                return Some(i);
+            }
+        }
+        let suffix = format!(".{field}");
+        let mut found = self
+            .fields
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.ends_with(&suffix));
+        match (found.next(), found.next()) {
+            (Some((i, _)), None) => Some(i),
+            _ => None,
+        }
+    }
+
+    /// What comes out of a JOIN, the fields of both sides qualified by their tables.
+    pub fn join(outer: &Schema, inner: &Schema) -> Schema {
+        let qualified = |schema: &Schema| -> Vec<String> {
+            schema
+                .fields
+                .iter()
+                .map(|f| match f.contains('.') {
+                    true => f.clone(),
+                    false => format!("{}.{f}", schema.table),
+                })
+                .collect()
+        };
+        Schema {
+            table: format!("{}+{}", outer.table, inner.table),
+            fields: qualified(outer)
+                .into_iter()
+                .chain(qualified(inner))
+                .collect(),
+        }
+    }
 }
 
 /// For now the projection retrieves the fields
 /// in the order of the schema (first line in csv).
 pub struct Projector<'a> {
-    source: &'a mut dyn Iterator<Item = Row>,
+    source: Rows<'a>,
     projection: Vec<String>,
     idxs: Vec<usize>,
 }
@@ -119,13 +289,13 @@ pub struct Projector<'a> {
 impl<'a> Projector<'a> {
     pub fn new(
         projection: Vec<String>,
-        source: &'a mut dyn Iterator<Item = Row>,
+        source: impl Iterator<Item = Row> + 'a,
         schema: &Schema,
     ) -> Self {
         let idxs = projection
             .iter()
             .filter_map(|p| {
-                let opt = schema.fields.iter().position(|f| f == p);
+                let opt = schema.position(p);
 
                 if opt.is_none() {
                     // for now, comment to disable warning
@@ -138,12 +308,32 @@ impl<'a> Projector<'a> {
 
         Self {
             idxs,
-            source,
+            source: Box::new(source),
             projection,
         }
     }
 }
 
+/// The schema of what comes out of a Projector,
+/// the projected fields in the order of the schema.
+pub fn projected(projection: &[String], schema: &Schema) -> Schema {
+    // found like the Projector finds them, "rating" is "ratings.rating"
+    let idxs: Vec<usize> = projection
+        .iter()
+        .filter_map(|p| schema.position(p))
+        .collect();
+    Schema {
+        table: schema.table.clone(),
+        fields: schema
+            .fields
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| projection.is_empty() || idxs.contains(i))
+            .map(|(_, f)| f.clone())
+            .collect(),
+    }
+}
+
 impl<'a> Iterator for Projector<'a> {
     type Item = Row;
 
@@ -171,218 +361,779 @@ impl<'a> Iterator for Projector<'a> {
 
 pub struct Selector<'a> {
     selection: Vec<String>,
-    source: &'a mut dyn Iterator<Item = Row>,
+    source: Rows<'a>,
     idx: usize,
-    // ugly but it works
-    ran: bool,
 }
 
 impl<'a> Selector<'a> {
     pub fn new(
         selection: Vec<String>,
-        source: &'a mut dyn Iterator<Item = Row>,
+        source: impl Iterator<Item = Row> + 'a,
         schema: &Schema,
     ) -> Self {
-        assert_eq!(
-            selection[1], "EQUALS",
-            "SELECTION clause only supports EQUALS"
+        assert!(
+            COMPARISONS.contains(&selection[1].as_str()),
+            "SELECTION clause only supports {COMPARISONS:?}"
         );
 
         Self {
             idx: schema
-                .fields
-                .iter()
-                .position(|f| f == &selection[0])
+                .position(&selection[0])
                 // we can remove later if we want silent filter (if not found)
                 // which can be useful once we have multiple scanners (multi-table queries)
-                .expect(&format!(
-                    "'{}' field not found in table '{}'",
-                    selection[0], schema.table
-                )),
+                .unwrap_or_else(|| {
+                    panic!(
+                        "'{}' field not found in table '{}'",
+                        selection[0], schema.table
+                    )
+                }),
             selection,
-            source,
-            ran: false,
+            source: Box::new(source),
         }
     }
 }
 
 impl<'a> Iterator for Selector<'a> {
-    // look at me, I'm different o-o
-    type Item = Vec<Row>;
+    type Item = Row;
+
+    // streams, so whoever is on top (e.g. a LIMIT) can stop pulling early
+    fn next(&mut self) -> Option<Self::Item> {
+        Iterator::find(&mut self.source, |row| {
+            holds(&row[self.idx], &self.selection)
+        })
+    }
+}
+
+const COMPARISONS: [&str; 7] = [
+    "EQUALS",
+    "NOT_EQUALS",
+    "LESS",
+    "LESS_EQUALS",
+    "GREATER",
+    "GREATER_EQUALS",
+    "BETWEEN",
+];
+
+/// Whether a value passes a condition like `["rating", "GREATER", "4"]`
+/// or `["movieId", "BETWEEN", "100", "AND", "200"]` (both ends included).
+pub fn holds(value: &str, condition: &[String]) -> bool {
+    match condition[1].as_str() {
+        "BETWEEN" => {
+            assert_eq!(
+                condition.get(3).map(String::as_str),
+                Some("AND"),
+                "BETWEEN goes like [field, BETWEEN, low, AND, high]"
+            );
+            satisfies(value, "GREATER_EQUALS", &condition[2])
+                && satisfies(value, "LESS_EQUALS", &condition[4])
+        }
+        op => satisfies(value, op, &condition[2]),
+    }
+}
+
+/// `value <op> operand`, with numbers compared as numbers (see [`compare`]).
+/// EQUALS is the same string, "1.0" isn't "1".
+pub fn satisfies(value: &str, op: &str, operand: &str) -> bool {
+    let ord = compare(value, operand);
+    match op {
+        "EQUALS" => value == operand,
+        "NOT_EQUALS" => value != operand,
+        "LESS" => ord.is_lt(),
+        "LESS_EQUALS" => ord.is_le(),
+        "GREATER" => ord.is_gt(),
+        "GREATER_EQUALS" => ord.is_ge(),
+        _ => panic!("unknown comparison '{op}'"),
+    }
+}
+
+/// Compares two fields, numerically if both of them parse as numbers
+/// (so "10" comes after "9"), otherwise as plain strings, with every
+/// number before any text so a column with both still sorts. Only the
+/// same string is equal, "1" comes right before "1.0", like EQUALS (and
+/// the joins, the groups...) has it.
+pub fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(x), Ok(y)) => x.total_cmp(&y).then_with(|| a.cmp(b)),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        _ => a.cmp(b),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SortKey {
+    idx: usize,
+    desc: bool,
+}
+
+/// Reads an ORDER clause like `["rating", "DESC", "movieId"]`,
+/// each field can be followed by ASC (default) or DESC.
+pub(crate) fn sort_keys(order: &[String], schema: &Schema) -> Vec<SortKey> {
+    let mut keys: Vec<SortKey> = vec![];
+    for part in order {
+        match part.as_str() {
+            "ASC" => keys.last_mut().expect("ASC without a field").desc = false,
+            "DESC" => keys.last_mut().expect("DESC without a field").desc = true,
+            field => keys.push(SortKey {
+                idx: schema.position(field).unwrap_or_else(|| {
+                    panic!("'{}' field not found in table '{}'", field, schema.table)
+                }),
+                desc: false,
+            }),
+        }
+    }
+    keys
+}
+
+pub(crate) fn compare_rows(keys: &[SortKey], a: &Row, b: &Row) -> Ordering {
+    for key in keys {
+        let ord = compare(&a[key.idx], &b[key.idx]);
+        let ord = if key.desc { ord.reverse() } else { ord };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Reads a LIMIT clause like `["10"]` or `["10", "OFFSET", "20"]`
+/// into (count, offset).
+pub(crate) fn limit_bounds(limit: &[String]) -> (usize, usize) {
+    let count = limit[0].parse().expect("LIMIT should be a number");
+    let offset = match limit.get(1).map(|s| s.as_str()) {
+        Some("OFFSET") => limit[2].parse().expect("OFFSET should be a number"),
+        Some(other) => panic!("unexpected '{other}' in LIMIT clause"),
+        None => 0,
+    };
+    (count, offset)
+}
+
+/// Full sort, it has to see every row before returning the first one.
+pub struct Sort<'a> {
+    source: Rows<'a>,
+    keys: Vec<SortKey>,
+    sorted: Option<std::vec::IntoIter<Row>>,
+}
+
+impl<'a> Sort<'a> {
+    pub fn new(
+        order: Vec<String>,
+        source: impl Iterator<Item = Row> + 'a,
+        schema: &Schema,
+    ) -> Self {
+        Self {
+            keys: sort_keys(&order, schema),
+            source: Box::new(source),
+            sorted: None,
+        }
+    }
+}
+
+impl<'a> Iterator for Sort<'a> {
+    type Item = Row;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.ran {
+        if self.sorted.is_none() {
+            let mut rows: Vec<Row> = self.source.by_ref().collect();
+            // stable, so ties keep the order they came in
+            rows.sort_by(|a, b| compare_rows(&self.keys, a, b));
+            self.sorted = Some(rows.into_iter());
+        }
+        self.sorted.as_mut()?.next()
+    }
+}
+
+// a row that orders itself by the sort keys, so it can live in a BinaryHeap
+struct Ranked<'k> {
+    row: Row,
+    keys: &'k [SortKey],
+}
+
+impl<'k> Ord for Ranked<'k> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_rows(self.keys, &self.row, &other.row)
+    }
+}
+
+impl<'k> PartialOrd for Ranked<'k> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'k> PartialEq for Ranked<'k> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'k> Eq for Ranked<'k> {}
+
+/// ORDER + LIMIT together, only keeps the first `count + offset` rows
+/// in a max-heap instead of sorting everything. The heap's top is the
+/// row that would come last, so it's the one that gets evicted.
+pub struct TopN<'a> {
+    source: Rows<'a>,
+    keys: Vec<SortKey>,
+    n: usize,
+    sorted: Option<std::vec::IntoIter<Row>>,
+}
+
+impl<'a> TopN<'a> {
+    pub fn new(
+        order: Vec<String>,
+        limit: &[String],
+        source: impl Iterator<Item = Row> + 'a,
+        schema: &Schema,
+    ) -> Self {
+        let (count, offset) = limit_bounds(limit);
+        Self {
+            keys: sort_keys(&order, schema),
+            n: count + offset,
+            source: Box::new(source),
+            sorted: None,
+        }
+    }
+}
+
+impl<'a> Iterator for TopN<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.sorted.is_none() {
+            let mut heap = BinaryHeap::with_capacity(self.n + 1);
+            for row in &mut *self.source {
+                heap.push(Ranked {
+                    row,
+                    keys: &self.keys,
+                });
+                if heap.len() > self.n {
+                    heap.pop();
+                }
+            }
+            let rows: Vec<Row> = heap.into_sorted_vec().into_iter().map(|r| r.row).collect();
+            self.sorted = Some(rows.into_iter());
+        }
+        self.sorted.as_mut()?.next()
+    }
+}
+
+/// Skips `offset` rows and then returns at most `count`,
+/// it doesn't touch the source after that.
+pub struct Limit<'a> {
+    source: Rows<'a>,
+    count: usize,
+    offset: usize,
+}
+
+impl<'a> Limit<'a> {
+    pub fn new(limit: Vec<String>, source: impl Iterator<Item = Row> + 'a) -> Self {
+        let (count, offset) = limit_bounds(&limit);
+        Self {
+            source: Box::new(source),
+            count,
+            offset,
+        }
+    }
+}
+
+impl<'a> Iterator for Limit<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.count == 0 {
             return None;
         }
-        self.ran = true;
+        while self.offset > 0 {
+            self.source.next()?;
+            self.offset -= 1;
+        }
+        self.count -= 1;
+        self.source.next()
+    }
+}
 
-        let mut results = vec![];
-        while let Some(row) = self.source.next() {
-            if row[self.idx] == self.selection[2] {
-                results.push(row);
+/// Splits a JOIN clause like `["a.x", "EQUALS", "b.x", "AND", "b.y", "EQUALS", "c.y"]`
+/// into its conditions, no conditions at all being a cross product.
+pub fn conditions(on: &[String]) -> Vec<&[String]> {
+    on.split(|part| part == "AND")
+        .filter(|condition| !condition.is_empty())
+        .collect()
+}
+
+/// Resolves which field of each side every JOIN condition like
+/// `["movies.movieId", "EQUALS", "ratings.movieId"]` refers to,
+/// each condition can be written in either order.
+pub(crate) fn join_keys(on: &[String], outer: &Schema, inner: &Schema) -> Vec<(usize, usize)> {
+    conditions(on)
+        .into_iter()
+        .map(|on| {
+            assert_eq!(on[1], "EQUALS", "JOIN clauses only supports EQUALS");
+            match (outer.position(&on[0]), inner.position(&on[2])) {
+                (Some(outer), Some(inner)) => (outer, inner),
+                _ => (
+                    outer
+                        .position(&on[2])
+                        .expect("unrecognized field for outer table in JOIN"),
+                    inner
+                        .position(&on[0])
+                        .expect("unrecognized field for inner table in JOIN"),
+                ),
             }
+        })
+        .collect()
+}
+
+fn key(row: &Row, idxs: impl Iterator<Item = usize>) -> Vec<String> {
+    idxs.map(|i| row[i].clone()).collect()
+}
+
+/// Rows kept in memory so they can be read again and again,
+/// like the inner side of a NestedJoin.
+pub struct Buffered {
+    table: String,
+    rows: Vec<Row>,
+    offset: usize,
+}
+
+impl Buffered {
+    pub fn new(table: &str, rows: impl Iterator<Item = Row>) -> Self {
+        Self {
+            table: table.to_owned(),
+            rows: rows.collect(),
+            offset: 0,
         }
-        Some(results)
     }
 }
 
+impl Iterator for Buffered {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.rows.get(self.offset)?.clone();
+        self.offset += 1;
+        Some(row)
+    }
+}
+
+impl Offset for Buffered {
+    fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl Metadata for Buffered {
+    fn table(&self) -> &str {
+        &self.table
+    }
+}
+
+impl Reset for Buffered {
+    fn reset(&mut self) {
+        self.offset = 0;
+    }
+}
+
+impl Source for Buffered {}
+
 /// Don't ever use this, it just runs forever ;-;
 /// I'll see if optimizing the file accesses makes
-/// this usable.
+/// this usable. (The planner only picks it when
+/// the inner side is tiny, over a Buffered one.)
 pub struct NestedJoin<'a> {
-    outer: &'a mut dyn Source,
-    inner: &'a mut dyn Source,
-    outer_idx: usize,
-    inner_idx: usize,
-    ran: bool,
+    outer: Rows<'a>,
+    inner: Box<dyn Source + 'a>,
+    keys: Vec<(usize, usize)>,
+    // the outer row we're scanning the inner side for
+    current: Option<Row>,
 }
 
 impl<'a> NestedJoin<'a> {
     pub fn new(
-        outer: &'a mut dyn Source,
-        inner: &'a mut dyn Source,
-        outer_schema: Schema,
-        inner_schema: Schema,
+        outer: impl Iterator<Item = Row> + 'a,
+        inner: impl Source + 'a,
+        outer_schema: &Schema,
+        inner_schema: &Schema,
         on: Vec<String>,
     ) -> Self {
-        assert_eq!(on[1], "EQUALS", "JOIN clauses only supports EQUALS");
-        let outer_field = &on[0].split('.').skip(1).next().unwrap();
-        let inner_field = &on[2].split('.').skip(1).next().unwrap();
-        let outer_idx = outer_schema
-            .fields
-            .iter()
-            .position(|f| f == outer_field)
-            .expect("unrecognized field for outer table in JOIN");
-        let inner_idx = inner_schema
-            .fields
-            .iter()
-            .position(|f| f == inner_field)
-            .expect("unrecognized field for inner table in JOIN");
         Self {
-            outer,
-            inner,
-            ran: false,
-            outer_idx,
-            inner_idx,
+            outer: Box::new(outer),
+            inner: Box::new(inner),
+            current: None,
+            keys: join_keys(&on, outer_schema, inner_schema),
         }
     }
 }
 
 impl<'a> Iterator for NestedJoin<'a> {
-    // here we go again
-    type Item = Vec<Row>;
+    type Item = Row;
 
     // inner join
     fn next(&mut self) -> Option<Self::Item> {
-        if self.ran {
-            return None;
-        }
-        self.ran = true;
-
-        let mut results = vec![];
         loop {
-            let outer_row = match self.outer.next() {
-                Some(row) => row,
-                None => break,
-            };
-            loop {
-                let mut inner_row = match self.inner.next() {
-                    Some(row) => row,
-                    None => break,
-                };
-                if outer_row[self.outer_idx] == inner_row[self.inner_idx] {
-                    let mut result = outer_row.clone();
-                    result.append(&mut inner_row);
-                    results.push(result);
+            if self.current.is_none() {
+                self.current = Some(self.outer.next()?);
+            }
+            let outer_row = self.current.as_ref().unwrap();
+            match self.inner.next() {
+                Some(mut inner_row) => {
+                    if self
+                        .keys
+                        .iter()
+                        .all(|(o, i)| outer_row[*o] == inner_row[*i])
+                    {
+                        let mut result = outer_row.clone();
+                        result.append(&mut inner_row);
+                        return Some(result);
+                    }
+                }
+                None => {
+                    self.inner.reset();
+                    self.current = None;
                 }
             }
-            self.inner.reset();
         }
-
-        Some(results)
     }
 }
 
+/// Builds a hash table with the inner side (or the outer one, see
+/// `build_outer`) and streams the other one through it, so the
+/// built side should be the smaller one.
 pub struct HashJoin<'a> {
-    outer: &'a mut dyn Source,
-    inner: &'a mut dyn Source,
-    outer_idx: usize,
-    inner_idx: usize,
-    ran: bool,
+    outer: Rows<'a>,
+    inner: Rows<'a>,
+    keys: Vec<(usize, usize)>,
+    build_outer: bool,
+    table: Option<HashMap<Vec<String>, Vec<Row>>>,
+    // joined rows for the current probing row that weren't returned yet
+    pending: Vec<Row>,
 }
 
 impl<'a> HashJoin<'a> {
     pub fn new(
-        outer: &'a mut dyn Source,
-        inner: &'a mut dyn Source,
-        outer_schema: Schema,
-        inner_schema: Schema,
+        outer: impl Iterator<Item = Row> + 'a,
+        inner: impl Iterator<Item = Row> + 'a,
+        outer_schema: &Schema,
+        inner_schema: &Schema,
         on: Vec<String>,
     ) -> Self {
-        assert_eq!(on[1], "EQUALS", "JOIN clauses only supports EQUALS");
-        let outer_field = &on[0].split('.').skip(1).next().unwrap();
-        let inner_field = &on[2].split('.').skip(1).next().unwrap();
-        let outer_idx = outer_schema
-            .fields
-            .iter()
-            .position(|f| f == outer_field)
-            .expect("unrecognized field for outer table in JOIN");
-        let inner_idx = inner_schema
-            .fields
-            .iter()
-            .position(|f| f == inner_field)
-            .expect("unrecognized field for inner table in JOIN");
         Self {
-            outer,
-            inner,
-            ran: false,
-            outer_idx,
-            inner_idx,
+            outer: Box::new(outer),
+            inner: Box::new(inner),
+            keys: join_keys(&on, outer_schema, inner_schema),
+            build_outer: false,
+            table: None,
+            pending: vec![],
         }
     }
-}
 
-// std's is faster than mine, and it has iterators
-use std::collections::BTreeMap;
+    /// Hashes the outer side instead, the rows still
+    /// come out with the outer fields first.
+    pub fn build_outer(mut self) -> Self {
+        self.build_outer = true;
+        self
+    }
+}
 
 impl<'a> Iterator for HashJoin<'a> {
-    // here we go again
-    type Item = Vec<Row>;
+    type Item = Row;
 
     // inner join
     fn next(&mut self) -> Option<Self::Item> {
-        if self.ran {
-            return None;
+        let outer_keys = || self.keys.iter().map(|(o, _)| *o);
+        let inner_keys = || self.keys.iter().map(|(_, i)| *i);
+        if self.table.is_none() {
+            let mut table: HashMap<Vec<String>, Vec<Row>> = HashMap::new();
+            let build = if self.build_outer {
+                &mut *self.outer
+            } else {
+                &mut *self.inner
+            };
+            for row in build {
+                let key = match self.build_outer {
+                    true => key(&row, outer_keys()),
+                    false => key(&row, inner_keys()),
+                };
+                table.entry(key).or_default().push(row);
+            }
+            self.table = Some(table);
         }
-        self.ran = true;
-
-        let outer_table = self
-            .outer
-            .into_iter()
-            .fold(BTreeMap::new(), |mut acc, row| {
-                let outer_column = row[self.outer_idx].clone();
-                acc.insert(outer_column, row);
-                acc
-            });
-        let inner_table = self
-            .inner
-            .into_iter()
-            .fold(BTreeMap::new(), |mut acc, row| {
-                let inner_column = row[self.inner_idx].clone();
-                acc.insert(inner_column, row);
-                acc
-            });
-
-        let mut results = vec![];
-
-        for (key, mut value) in outer_table {
-            if inner_table.contains_key(&key) {
-                let mut inner_row = inner_table.get(&key).unwrap().clone();
-                value.append(&mut inner_row);
-                results.push(value);
+        loop {
+            if let Some(row) = self.pending.pop() {
+                return Some(row);
+            }
+            let (row, key) = if self.build_outer {
+                let row = self.inner.next()?;
+                let key = key(&row, inner_keys());
+                (row, key)
+            } else {
+                let row = self.outer.next()?;
+                let key = key(&row, outer_keys());
+                (row, key)
+            };
+            if let Some(matches) = self.table.as_ref()?.get(&key) {
+                // reversed since they're popped
+                self.pending = matches
+                    .iter()
+                    .rev()
+                    .map(|other| {
+                        let (outer_row, inner_row) = match self.build_outer {
+                            true => (other, &row),
+                            false => (&row, other),
+                        };
+                        let mut result = outer_row.clone();
+                        result.extend(inner_row.iter().cloned());
+                        result
+                    })
+                    .collect();
             }
         }
+    }
+}
 
-        Some(results)
+/// Joins two sides that are both sorted by the JOIN fields (the
+/// planner puts a Sort under each), the rows come out sorted by them too.
+pub struct MergeJoin<'a> {
+    outer: Peekable<Rows<'a>>,
+    inner: Peekable<Rows<'a>>,
+    keys: Vec<(usize, usize)>,
+    pending: VecDeque<Row>,
+}
+
+impl<'a> MergeJoin<'a> {
+    pub fn new(
+        outer: impl Iterator<Item = Row> + 'a,
+        inner: impl Iterator<Item = Row> + 'a,
+        outer_schema: &Schema,
+        inner_schema: &Schema,
+        on: Vec<String>,
+    ) -> Self {
+        let outer: Rows<'a> = Box::new(outer);
+        let inner: Rows<'a> = Box::new(inner);
+        Self {
+            outer: outer.peekable(),
+            inner: inner.peekable(),
+            keys: join_keys(&on, outer_schema, inner_schema),
+            pending: VecDeque::new(),
+        }
     }
 }
+
+fn compare_keys(keys: &[(usize, usize)], outer: &Row, inner: &Row) -> Ordering {
+    keys.iter()
+        .map(|(o, i)| compare(&outer[*o], &inner[*i]))
+        .find(|ord| ord.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+impl<'a> Iterator for MergeJoin<'a> {
+    type Item = Row;
+
+    // inner join
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.pending.pop_front() {
+                return Some(row);
+            }
+            let ord = compare_keys(&self.keys, self.outer.peek()?, self.inner.peek()?);
+            match ord {
+                Ordering::Less => {
+                    self.outer.next();
+                }
+                Ordering::Greater => {
+                    self.inner.next();
+                }
+                Ordering::Equal => {
+                    // every inner row with this key, then every outer one with it
+                    let mut group = vec![self.inner.next().unwrap()];
+                    while let Some(inner_row) = self.inner.peek() {
+                        if !compare_keys(&self.keys, self.outer.peek()?, inner_row).is_eq() {
+                            break;
+                        }
+                        group.push(self.inner.next().unwrap());
+                    }
+                    while let Some(outer_row) = self.outer.peek() {
+                        if !compare_keys(&self.keys, outer_row, &group[0]).is_eq() {
+                            break;
+                        }
+                        let outer_row = self.outer.next().unwrap();
+                        self.pending.extend(group.iter().map(|inner_row| {
+                            let mut result = outer_row.clone();
+                            result.extend(inner_row.iter().cloned());
+                            result
+                        }));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_limit_stops_pulling() {
+    let mut pulled = 0;
+    let mut source = (0..).map(|i: usize| {
+        pulled += 1;
+        vec![i.to_string()]
+    });
+    let limit = Limit::new(vec!["2".into(), "OFFSET".into(), "3".into()], &mut source);
+    assert_eq!(
+        limit.collect::<Vec<_>>(),
+        vec![vec!["3".to_owned()], vec!["4".to_owned()]]
+    );
+    drop(source);
+    assert_eq!(pulled, 5);
+}
+
+#[test]
+fn test_projected() {
+    let schema = Schema {
+        table: "movies".into(),
+        fields: vec![
+            "movies.movieId".into(),
+            "movies.title".into(),
+            "ratings.rating".into(),
+        ],
+    };
+    let projection = vec!["rating".to_owned(), "movies.movieId".to_owned()];
+    let row = vec!["1".to_owned(), "Toy Story".into(), "4.0".into()];
+    let projector = Projector::new(projection.clone(), vec![row].into_iter(), &schema);
+    assert_eq!(projector.collect::<Vec<_>>(), vec![vec!["1", "4.0"]]);
+    assert_eq!(
+        projected(&projection, &schema).fields,
+        vec!["movies.movieId", "ratings.rating"]
+    );
+}
+
+#[test]
+fn test_satisfies() {
+    assert!(satisfies("10", "GREATER", "9"));
+    assert!(satisfies("1.0", "GREATER_EQUALS", "1"));
+    assert!(!satisfies("1.0", "EQUALS", "1"));
+    assert!(satisfies("1.0", "NOT_EQUALS", "1"));
+}
+
+#[test]
+fn test_top_n_matches_sort() {
+    let schema = Schema {
+        table: "ratings".into(),
+        fields: vec!["movieId".into(), "rating".into()],
+    };
+    let rows: Vec<Row> = (1..=50)
+        .map(|i: usize| vec![i.to_string(), ((i * 7) % 10).to_string()])
+        .collect();
+    let order = vec!["rating".to_owned(), "DESC".to_owned(), "movieId".to_owned()];
+    let limit = vec!["5".to_owned(), "OFFSET".to_owned(), "2".to_owned()];
+
+    let mut source = rows.clone().into_iter();
+    let mut sort = Sort::new(order.clone(), &mut source, &schema);
+    let expected: Vec<Row> = Limit::new(limit.clone(), &mut sort).collect();
+
+    let mut source = rows.into_iter();
+    let mut top = TopN::new(order, &limit, &mut source, &schema);
+    let found: Vec<Row> = Limit::new(limit, &mut top).collect();
+
+    assert_eq!(found, expected);
+    assert_eq!(found[0], vec!["27".to_owned(), "9".to_owned()]);
+}
+
+#[test]
+fn test_joins_agree() {
+    let movies = Schema {
+        table: "movies".into(),
+        fields: vec!["movieId".into(), "title".into()],
+    };
+    let ratings = Schema {
+        table: "ratings".into(),
+        fields: vec!["userId".into(), "movieId".into(), "rating".into()],
+    };
+    let row = |fields: &[&str]| -> Row { fields.iter().map(|f| f.to_string()).collect() };
+    // both sides sorted by movieId, with duplicates on both of them,
+    // and a "1.0" that isn't "1"
+    let outer = vec![
+        row(&["1", "Toy Story"]),
+        row(&["2", "Jumanji"]),
+        row(&["2", "Jumanji (again)"]),
+        row(&["4", "Heat"]),
+    ];
+    let inner = vec![
+        row(&["7", "1", "3.5"]),
+        row(&["6", "1.0", "2.0"]),
+        row(&["8", "2", "4.0"]),
+        row(&["9", "2", "1.0"]),
+        row(&["7", "3", "5.0"]),
+    ];
+    let on: Vec<String> = ["movies.movieId", "EQUALS", "ratings.movieId"]
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+
+    let sorted = |rows: Vec<Row>| {
+        let mut rows = rows;
+        rows.sort();
+        rows
+    };
+    let hash: Vec<Row> = HashJoin::new(
+        outer.clone().into_iter(),
+        inner.clone().into_iter(),
+        &movies,
+        &ratings,
+        on.clone(),
+    )
+    .collect();
+    assert_eq!(hash.len(), 5);
+    assert_eq!(hash[0], row(&["1", "Toy Story", "7", "1", "3.5"]));
+
+    let build_outer = HashJoin::new(
+        outer.clone().into_iter(),
+        inner.clone().into_iter(),
+        &movies,
+        &ratings,
+        on.clone(),
+    )
+    .build_outer();
+    assert_eq!(sorted(build_outer.collect()), sorted(hash.clone()));
+
+    let nested = NestedJoin::new(
+        outer.clone().into_iter(),
+        Buffered::new("ratings", inner.clone().into_iter()),
+        &movies,
+        &ratings,
+        on.clone(),
+    );
+    assert_eq!(sorted(nested.collect()), sorted(hash.clone()));
+
+    let merge = MergeJoin::new(outer.into_iter(), inner.into_iter(), &movies, &ratings, on);
+    assert_eq!(sorted(merge.collect()), sorted(hash));
+}
+
+#[test]
+fn test_heap_scan() {
+    use crate::fs::BulkWriter;
+
+    crate::data::test_dir("test_heap_scan");
+    HeapFile::create("test_heap_scan", 0).unwrap();
+    let mut writer = BulkWriter::open("test_heap_scan", 0).unwrap();
+    for i in 0..1000 {
+        let row = vec![i.to_string(), format!("Movie {i} (1995)"), "Comedy".into()];
+        writer.write(&row).unwrap();
+    }
+    let pages = writer.finish().unwrap();
+    assert!(pages > 1);
+    HeapFile::open("test_heap_scan", 0)
+        .unwrap()
+        .delete(1)
+        .unwrap();
+
+    let mut scan = HeapScan::new("test_heap_scan", Some(vec![0, 2]));
+    assert_eq!(scan.offset(), 0);
+    assert_eq!(scan.next(), Some(vec!["0".to_owned(), "Comedy".into()]));
+    // the deleted one is skipped
+    assert_eq!(scan.next().unwrap()[0], "2");
+    assert_eq!(scan.offset(), 3);
+    assert_eq!(scan.by_ref().count(), 997);
+    assert_eq!(scan.pages_read(), pages);
+
+    scan.reset();
+    assert_eq!(scan.count(), 999);
+}
@@ -0,0 +1,224 @@
+use std::collections::HashSet;
+
+use crate::query::Query;
+use crate::source::{Row, Rows, Schema};
+
+/// `IN`/`NOT_IN` a subquery, by hashing what the subquery returned
+/// (its first field) and streaming the outer rows through it.
+/// Without NOT it's a semi join, with NOT it's an anti join.
+pub struct SemiJoin<'a> {
+    source: Rows<'a>,
+    idx: usize,
+    keys: HashSet<String>,
+    anti: bool,
+}
+
+impl<'a> SemiJoin<'a> {
+    pub fn new(
+        field: &str,
+        anti: bool,
+        source: impl Iterator<Item = Row> + 'a,
+        subquery: impl Iterator<Item = Row>,
+        schema: &Schema,
+    ) -> Self {
+        Self {
+            idx: schema.position(field).unwrap_or_else(|| {
+                panic!("'{}' field not found in table '{}'", field, schema.table)
+            }),
+            keys: subquery
+                .map(|mut row| {
+                    assert_eq!(row.len(), 1, "IN subqueries should return a single field");
+                    row.remove(0)
+                })
+                .collect(),
+            source: Box::new(source),
+            anti,
+        }
+    }
+}
+
+impl<'a> Iterator for SemiJoin<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Iterator::find(&mut self.source, |row| {
+            self.keys.contains(&row[self.idx]) != self.anti
+        })
+    }
+}
+
+/// Appends the result of the uncorrelated scalar subqueries
+/// in the projection to every row.
+pub struct Scalars<'a> {
+    source: Rows<'a>,
+    values: Vec<String>,
+}
+
+impl<'a> Scalars<'a> {
+    pub fn new(values: Vec<String>, source: impl Iterator<Item = Row> + 'a) -> Self {
+        Self {
+            source: Box::new(source),
+            values,
+        }
+    }
+}
+
+impl<'a> Iterator for Scalars<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut row = self.source.next()?;
+        row.extend(self.values.iter().cloned());
+        Some(row)
+    }
+}
+
+/// A scalar subquery should return one row with one field,
+/// no rows at all is a NULL.
+pub fn scalar(mut rows: Vec<Row>) -> String {
+    assert!(
+        rows.len() <= 1,
+        "scalar subquery returned more than one row"
+    );
+    match rows.pop() {
+        Some(mut row) => {
+            assert_eq!(row.len(), 1, "scalar subquery returned more than one field");
+            row.remove(0)
+        }
+        None => String::new(),
+    }
+}
+
+// in front of the `$n` a subquery is replaced with, the parts of a query
+// can't have it, so a value like "$5" is never taken for one
+const MARKER: char = '\0';
+
+/// What the n-th subquery is replaced with in the parts (see query::Query).
+pub fn marker(n: usize) -> String {
+    format!("{MARKER}${n}")
+}
+
+/// Which subquery a part refers to, if it refers to one.
+pub fn reference(part: &str) -> Option<usize> {
+    part.strip_prefix(MARKER)?.strip_prefix('$')?.parse().ok()
+}
+
+/// Panics if the part could be taken for a subquery.
+pub fn check(part: &str) {
+    assert!(
+        !part.contains(MARKER),
+        "{part:?} has a NUL character, queries can't have them"
+    );
+}
+
+/// Rewrites `EXISTS $n` (or `NOT_EXISTS`) where the subquery is correlated
+/// through an equality with the outer table, like
+///
+/// `["SELECTION", ["userId", "EQUALS", "ratings.userId"]]` inside the subquery,
+///
+/// into `["userId", "IN", $n]` against the subquery without that condition and
+/// projecting its side of it, so it can run once as a semi/anti join instead
+/// of once per outer row. Returns None if the subquery isn't correlated.
+pub fn decorrelate(
+    selection: &[String],
+    subquery: &Query,
+    outer: &Schema,
+) -> Option<(Vec<String>, Query)> {
+    let anti = match selection[0].as_str() {
+        "EXISTS" => false,
+        "NOT_EXISTS" => true,
+        _ => return None,
+    };
+    let condition = subquery.selection.as_ref()?;
+    let prefix = format!("{}.", outer.table);
+    let outer_field = |part: &str| {
+        part.strip_prefix(&prefix)
+            .filter(|field| outer.position(field).is_some())
+            .map(str::to_owned)
+    };
+    let (inner_field, outer_field) = match (outer_field(&condition[0]), outer_field(&condition[2]))
+    {
+        (None, None) => return None,
+        (Some(outer), None) => (condition[2].clone(), outer),
+        (None, Some(outer)) => (condition[0].clone(), outer),
+        (Some(_), Some(_)) => panic!("EXISTS subquery only refers to the outer table"),
+    };
+    assert_eq!(
+        condition[1], "EQUALS",
+        "only correlations with EQUALS can be decorrelated for now"
+    );
+    assert!(
+        subquery.group.is_none() && subquery.limit.is_none() && subquery.set.is_none(),
+        "only plain SCAN + SELECTION subqueries can be decorrelated for now"
+    );
+
+    let mut rewritten = subquery.clone();
+    rewritten.selection = None;
+    rewritten.projection = Some(vec![inner_field]);
+    rewritten.distinct = true;
+    let op = if anti { "NOT_IN" } else { "IN" };
+    Some((
+        vec![outer_field, op.to_owned(), selection[1].clone()],
+        rewritten,
+    ))
+}
+
+#[test]
+fn test_reference() {
+    let json = serde_json::json!([
+        ["SCAN", ["movies"]],
+        ["SELECTION", ["title", "IN", [["SCAN", ["tags"]]]]],
+        ["PROJECTION", ["$0", "$5"]]
+    ]);
+    let query = Query::from(json);
+    let selection = query.selection.unwrap();
+    assert_eq!(reference(&selection[2]), Some(0));
+    // values, not subqueries
+    let projection = query.projection.unwrap();
+    assert_eq!(projection.iter().find_map(|p| reference(p)), None);
+}
+
+#[test]
+fn test_decorrelate_exists() {
+    let json = serde_json::json!([
+        ["SCAN", ["ratings"]],
+        [
+            "SELECTION",
+            [
+                "NOT_EXISTS",
+                [
+                    ["SCAN", ["tags"]],
+                    ["SELECTION", ["ratings.userId", "EQUALS", "userId"]]
+                ]
+            ]
+        ]
+    ]);
+    let query = Query::from(json);
+    let outer = Schema {
+        table: "ratings".into(),
+        fields: vec!["userId".into(), "movieId".into()],
+    };
+    let selection = query.selection.as_ref().unwrap();
+    assert_eq!(selection, &vec!["NOT_EXISTS".to_owned(), marker(0)]);
+
+    let (selection, subquery) = decorrelate(selection, &query.subqueries[0], &outer).unwrap();
+    assert_eq!(
+        selection,
+        vec!["userId".to_owned(), "NOT_IN".into(), marker(0)]
+    );
+    assert_eq!(subquery.selection, None);
+    assert_eq!(subquery.projection, Some(vec!["userId".to_owned()]));
+
+    // rated something but never tagged anything
+    let mut source = vec![
+        vec!["1".to_owned(), "10".to_owned()],
+        vec!["2".to_owned(), "10".to_owned()],
+    ]
+    .into_iter();
+    let tagged = vec![vec!["2".to_owned()]];
+    let anti = SemiJoin::new(&selection[0], true, &mut source, tagged.into_iter(), &outer);
+    assert_eq!(
+        anti.collect::<Vec<_>>(),
+        vec![vec!["1".to_owned(), "10".to_owned()]]
+    );
+}
@@ -0,0 +1,187 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::io;
+
+use crate::wal::{self, Xid};
+use crate::xact::{self, Status};
+
+thread_local! {
+    // what the heap files opened on this thread see
+    static SNAPSHOT: RefCell<Option<Snapshot>> = const { RefCell::new(None) };
+}
+
+/// Every heap file opened on this thread sees what `snapshot` does
+/// (the statement's, or its transaction's), until it's None.
+pub fn use_snapshot(snapshot: Option<Snapshot>) {
+    SNAPSHOT.with(|current| *current.borrow_mut() = snapshot);
+}
+
+/// The one in use, or one taken now.
+pub fn snapshot() -> Snapshot {
+    SNAPSHOT
+        .with(|current| current.borrow().clone())
+        .unwrap_or_else(|| Snapshot::take(0))
+}
+
+/// Which changes a query sees, the ones of the transactions that
+/// committed before it was taken, and its own. Whatever commits
+/// after that isn't seen, so it's the same the whole time.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    // its own transaction, 0 if none
+    pub xid: Xid,
+    // this one and the ones after it hadn't started yet
+    xmax: Xid,
+    // the ones that were still going
+    running: BTreeSet<Xid>,
+}
+
+impl Snapshot {
+    pub fn take(xid: Xid) -> Self {
+        let (xmax, running) = wal::with(|wal| wal.running());
+        Self { xid, xmax, running }
+    }
+
+    /// If what the transaction did is seen.
+    pub fn sees(&self, xid: Xid) -> bool {
+        xid == 0
+            || xid == self.xid
+            || (xid < self.xmax
+                && !self.running.contains(&xid)
+                && xact::status(xid) == Status::Committed)
+    }
+
+    /// If a tuple inserted by `xmin`, and deleted by `xmax` (0 if it
+    /// wasn't), is there.
+    pub fn visible(&self, xmin: Xid, xmax: Xid) -> bool {
+        self.sees(xmin) && (xmax == 0 || !self.sees(xmax))
+    }
+}
+
+/// The changes between BEGIN and COMMIT, the others see all of them
+/// once it commits, or none if it doesn't (after a ROLLBACK, or a
+/// crash before the COMMIT). It sees what was committed when it
+/// began, and its own changes.
+pub struct Transaction {
+    pub xid: Xid,
+    pub snapshot: Snapshot,
+}
+
+impl Transaction {
+    pub fn begin() -> Self {
+        let xid = wal::with(|wal| wal.begin());
+        Self {
+            xid,
+            snapshot: Snapshot::take(xid),
+        }
+    }
+
+    pub fn commit(self) -> Result<(), io::Error> {
+        self.end(Status::Committed)
+    }
+
+    /// Nothing is undone, what it changed is never seen.
+    pub fn rollback(self) -> Result<(), io::Error> {
+        self.end(Status::Aborted)
+    }
+
+    // still running until the status is set, a snapshot taken in
+    // between doesn't see it either way
+    fn end(self, status: Status) -> Result<(), io::Error> {
+        wal::with(|wal| match status {
+            Status::Committed => wal.commit(self.xid),
+            _ => wal.abort(self.xid),
+        })?;
+        xact::set(self.xid, status)?;
+        wal::with(|wal| wal.end(self.xid));
+        Ok(())
+    }
+}
+
+/// Runs `f` in the transaction if there's one, otherwise in its own
+/// that's committed right after (or rolled back if it fails).
+pub fn autocommit<T>(
+    transaction: Option<&Transaction>,
+    f: impl FnOnce(Xid) -> Result<T, io::Error>,
+) -> Result<T, io::Error> {
+    if let Some(transaction) = transaction {
+        return f(transaction.xid);
+    }
+    let transaction = Transaction::begin();
+    match f(transaction.xid) {
+        Ok(result) => {
+            transaction.commit()?;
+            Ok(result)
+        }
+        Err(err) => {
+            transaction.rollback()?;
+            Err(err)
+        }
+    }
+}
+
+#[test]
+fn test_transactions() {
+    use crate::catalog::Table;
+    use crate::dml;
+    use crate::fs::{Heap, HeapFile};
+    use crate::index::Indexes;
+    use crate::source::Row;
+
+    crate::data::test_dir("test_transactions");
+    let table = Table::heap(
+        "test_transactions",
+        &[("movieId", "INTEGER"), ("note", "TEXT")],
+    );
+    let rows = |ids: &[&str]| -> Vec<Row> {
+        ids.iter()
+            .map(|id| vec![id.to_string(), "".into()])
+            .collect()
+    };
+    // with the snapshot
+    let read = |snapshot: Option<&Snapshot>| -> Vec<Row> {
+        use_snapshot(snapshot.cloned());
+        let heap = HeapFile::open(&table.name, 0).unwrap();
+        let rows = heap.into_iter().map(Result::unwrap).collect();
+        use_snapshot(None);
+        rows
+    };
+    let one = ["movieId".to_owned(), "EQUALS".into(), "1".into()];
+    let set = [("movieId".to_owned(), "5".to_owned())];
+    let indexes = Indexes::default();
+
+    // on its own
+    autocommit(None, |xid| {
+        dml::insert(&table, rows(&["1", "2"]), xid, &indexes)
+    })
+    .unwrap();
+    assert_eq!(read(None), rows(&["1", "2"]));
+
+    let transaction = Transaction::begin();
+    dml::insert(&table, rows(&["3"]), transaction.xid, &indexes).unwrap();
+    dml::update(&table, &set, Some(&one), transaction.xid, &indexes).unwrap();
+    // it sees its own changes, the others don't until it commits
+    assert_eq!(read(Some(&transaction.snapshot)), rows(&["2", "3", "5"]));
+    assert_eq!(read(None), rows(&["1", "2"]));
+    dml::delete(&table, None, transaction.xid).unwrap();
+    assert!(read(Some(&transaction.snapshot)).is_empty());
+    transaction.rollback().unwrap();
+    assert_eq!(read(None), rows(&["1", "2"]));
+
+    let first = Transaction::begin();
+    let second = Transaction::begin();
+    autocommit(Some(&second), |xid| {
+        dml::update(&table, &set, Some(&one), xid, &indexes)
+    })
+    .unwrap();
+    second.commit().unwrap();
+    // committed after it began
+    assert_eq!(read(Some(&first.snapshot)), rows(&["1", "2"]));
+    assert_eq!(read(None), rows(&["2", "5"]));
+    // and it can't change what the other one did
+    use_snapshot(Some(first.snapshot.clone()));
+    assert!(dml::delete(&table, Some(&one), first.xid).is_err());
+    use_snapshot(None);
+    first.rollback().unwrap();
+    assert_eq!(read(None), rows(&["2", "5"]));
+}
@@ -0,0 +1,633 @@
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::buffer::PageId;
+use crate::data::{self, Shared};
+
+/// A position in the log, the byte right after a record. A page
+/// changed by a record has its LSN, and can't be written before
+/// the log is flushed up to there.
+pub type Lsn = u64;
+
+// written (not synced) once there's this much waiting
+const BUFFER: usize = 1 << 20;
+
+// a new file is started once the one being written is this big
+const SEGMENT: u64 = 16 << 20;
+
+// how much it can grow before there's a checkpoint (see Wal::due)
+const DISTANCE: u64 = 4 * SEGMENT;
+
+// in the control file, a new one whenever the records (or the tuples
+// in them) change, a log from another version is never read
+const VERSION: u32 = 1;
+
+/// A folder in the data directory (see data::dir) with the segments
+/// of the log, each one named after the LSN it starts at, and the
+/// redo point (and the next transaction) of the last checkpoint.
+pub fn path() -> String {
+    data::path("wal")
+}
+
+// shared by every thread, unlike the buffer pool
+static WAL: Shared<Wal> =
+    Shared::new(|dir| Wal::open(&format!("{dir}/wal")).expect("the WAL in the data directory"));
+
+/// Runs `f` with the log every heap file writes to.
+pub fn with<T>(f: impl FnOnce(&mut Wal) -> T) -> T {
+    WAL.with(f)
+}
+
+// what wasn't written (or flushed) is lost, and who was running
+#[cfg(test)]
+pub(crate) fn crash() {
+    WAL.forget();
+}
+
+/// A transaction, what its changes are logged with. 0 is none, those
+/// are committed right away.
+pub type Xid = u64;
+
+/// The page changes that can be done again (redo) from the log, and
+/// how the transactions ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    // an empty page
+    Init {
+        page: PageId,
+    },
+    // the tuple as it is in the page, without its length
+    Insert {
+        page: PageId,
+        xid: Xid,
+        slot: u16,
+        tuple: Vec<u8>,
+    },
+    // the tuple's xmax, or its line pointer if there's no transaction
+    Delete {
+        page: PageId,
+        xid: Xid,
+        slot: u16,
+    },
+    Commit {
+        xid: Xid,
+    },
+    // rolled back, or a crash didn't let it finish
+    Abort {
+        xid: Xid,
+    },
+    // it's loading pages that aren't logged (see fs::BulkWriter),
+    // so recovery knows about it anyway
+    Load {
+        xid: Xid,
+    },
+    // the file was (re)created or removed, what came before is gone
+    Create {
+        table: String,
+    },
+    Remove {
+        table: String,
+    },
+    // the pages had every change before `redo` when it was written
+    Checkpoint {
+        redo: Lsn,
+        next_xid: Xid,
+    },
+}
+
+const INIT: u8 = 1;
+const INSERT: u8 = 2;
+const DELETE: u8 = 3;
+const COMMIT: u8 = 4;
+const CREATE: u8 = 5;
+const REMOVE: u8 = 6;
+const CHECKPOINT: u8 = 7;
+const ABORT: u8 = 8;
+const LOAD: u8 = 9;
+
+impl Record {
+    // [length][checksum][kind][table length][table][page][xid][slot][tuple length][tuple],
+    // as much of it as each kind has (commit is [kind][xid])
+    fn encode(&self) -> Vec<u8> {
+        let mut body = vec![];
+        let table = |body: &mut Vec<u8>, kind: u8, table: &str| {
+            body.push(kind);
+            body.extend((table.len() as u16).to_be_bytes());
+            body.extend(table.as_bytes());
+        };
+        let page = |body: &mut Vec<u8>, kind: u8, page: &PageId| {
+            table(body, kind, &page.table);
+            body.extend((page.page as u64).to_be_bytes());
+        };
+        match self {
+            Record::Init { page: id } => page(&mut body, INIT, id),
+            Record::Insert {
+                page: id,
+                xid,
+                slot,
+                tuple,
+            } => {
+                page(&mut body, INSERT, id);
+                body.extend(xid.to_be_bytes());
+                body.extend(slot.to_be_bytes());
+                body.extend((tuple.len() as u16).to_be_bytes());
+                body.extend(tuple);
+            }
+            Record::Delete {
+                page: id,
+                xid,
+                slot,
+            } => {
+                page(&mut body, DELETE, id);
+                body.extend(xid.to_be_bytes());
+                body.extend(slot.to_be_bytes());
+            }
+            Record::Commit { xid } => {
+                body.push(COMMIT);
+                body.extend(xid.to_be_bytes());
+            }
+            Record::Abort { xid } => {
+                body.push(ABORT);
+                body.extend(xid.to_be_bytes());
+            }
+            Record::Load { xid } => {
+                body.push(LOAD);
+                body.extend(xid.to_be_bytes());
+            }
+            Record::Create { table: name } => table(&mut body, CREATE, name),
+            Record::Remove { table: name } => table(&mut body, REMOVE, name),
+            Record::Checkpoint { redo, next_xid } => {
+                body.push(CHECKPOINT);
+                body.extend(redo.to_be_bytes());
+                body.extend(next_xid.to_be_bytes());
+            }
+        }
+        let mut record = (body.len() as u32).to_be_bytes().to_vec();
+        record.extend(checksum(&body).to_be_bytes());
+        record.extend(body);
+        record
+    }
+
+    // None if it isn't a record we know
+    fn decode(body: &[u8]) -> Option<Self> {
+        let mut at = 0;
+        let mut take = |n: usize| {
+            let bytes = body.get(at..at + n)?;
+            at += n;
+            Some(bytes)
+        };
+        let kind = take(1)?[0];
+        match kind {
+            COMMIT | ABORT | LOAD => {
+                let xid = Xid::from_be_bytes(take(8)?.try_into().ok()?);
+                return Some(match kind {
+                    COMMIT => Record::Commit { xid },
+                    ABORT => Record::Abort { xid },
+                    _ => Record::Load { xid },
+                });
+            }
+            CHECKPOINT => {
+                let redo = Lsn::from_be_bytes(take(8)?.try_into().ok()?);
+                let next_xid = Xid::from_be_bytes(take(8)?.try_into().ok()?);
+                return Some(Record::Checkpoint { redo, next_xid });
+            }
+            _ => {}
+        }
+        let len = u16::from_be_bytes(take(2)?.try_into().ok()?) as usize;
+        let table = String::from_utf8(take(len)?.to_vec()).ok()?;
+        match kind {
+            CREATE => return Some(Record::Create { table }),
+            REMOVE => return Some(Record::Remove { table }),
+            _ => {}
+        }
+        let page = u64::from_be_bytes(take(8)?.try_into().ok()?) as usize;
+        let page = PageId { table, page };
+        match kind {
+            INIT => Some(Record::Init { page }),
+            INSERT | DELETE => {
+                let xid = Xid::from_be_bytes(take(8)?.try_into().ok()?);
+                let slot = u16::from_be_bytes(take(2)?.try_into().ok()?);
+                if kind == DELETE {
+                    return Some(Record::Delete { page, xid, slot });
+                }
+                let len = u16::from_be_bytes(take(2)?.try_into().ok()?) as usize;
+                let tuple = take(len)?.to_vec();
+                Some(Record::Insert {
+                    page,
+                    xid,
+                    slot,
+                    tuple,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Record {
+    /// The transaction it's part of, or ends.
+    pub fn xid(&self) -> Option<Xid> {
+        match self {
+            Record::Insert { xid, .. }
+            | Record::Delete { xid, .. }
+            | Record::Commit { xid }
+            | Record::Abort { xid }
+            | Record::Load { xid } => Some(*xid),
+            _ => None,
+        }
+    }
+}
+
+// FNV-1a, enough to tell a record that was only partly written
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Appends the records to a file, they're kept in memory until
+/// it's flushed (at commit, or before a page they changed is
+/// written), so the log always has the changes before the pages do.
+pub struct Wal {
+    path: String,
+    // the last segment, it starts at `start`
+    file: File,
+    start: Lsn,
+    // how big a segment gets
+    segment: u64,
+    // what wasn't written yet, starting at `written`
+    buffer: Vec<u8>,
+    written: Lsn,
+    // synced up to here
+    flushed: Lsn,
+    // of the last checkpoint, or where the log ended when it was opened
+    redo: Lsn,
+    // handed out by begin
+    next_xid: Xid,
+    // the transactions that didn't commit or roll back yet
+    active: BTreeSet<Xid>,
+}
+
+impl Wal {
+    pub fn open(path: &str) -> Result<Self, io::Error> {
+        std::fs::create_dir_all(path)?;
+        let (redo, next_xid) = control(path)?;
+        // so it has a version from the start
+        if segments(path)?.is_empty() && redo == 0 {
+            write_control(path, redo, next_xid)?;
+        }
+        let start = match segments(path)?.last() {
+            Some((start, _)) => *start,
+            // they were all removed, the LSNs go on from the last checkpoint
+            None => redo,
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment(path, start))?;
+        let end = start + file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            start,
+            segment: SEGMENT,
+            buffer: vec![],
+            written: end,
+            flushed: end,
+            redo: end,
+            next_xid,
+            active: BTreeSet::new(),
+        })
+    }
+
+    /// Where the next record goes.
+    pub fn lsn(&self) -> Lsn {
+        self.written + self.buffer.len() as Lsn
+    }
+
+    pub fn flushed(&self) -> Lsn {
+        self.flushed
+    }
+
+    /// The LSN of the record, what the page it changes gets.
+    pub fn append(&mut self, record: &Record) -> Result<Lsn, io::Error> {
+        self.buffer.extend(record.encode());
+        if self.buffer.len() >= BUFFER {
+            self.write()?;
+        }
+        Ok(self.lsn())
+    }
+
+    // a record never spans two segments, the next one starts after a write
+    fn write(&mut self) -> Result<(), io::Error> {
+        self.file.write_all(&self.buffer)?;
+        self.written += self.buffer.len() as Lsn;
+        self.buffer.clear();
+        if self.written - self.start >= self.segment {
+            // nothing's synced in a segment that isn't the last one
+            self.file.sync_data()?;
+            self.flushed = self.written;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(segment(&self.path, self.written))?;
+            self.start = self.written;
+        }
+        Ok(())
+    }
+
+    /// Makes everything up to `lsn` durable (fsync), if it isn't already.
+    pub fn flush(&mut self, lsn: Lsn) -> Result<(), io::Error> {
+        if lsn <= self.flushed {
+            return Ok(());
+        }
+        self.write()?;
+        self.file.sync_data()?;
+        self.flushed = self.written;
+        Ok(())
+    }
+
+    /// Appends the record and flushes it, for the ones that have
+    /// to be there before the change is (like removing a file).
+    pub fn log(&mut self, record: &Record) -> Result<Lsn, io::Error> {
+        let lsn = self.append(record)?;
+        self.flush(lsn)?;
+        Ok(lsn)
+    }
+
+    pub fn begin(&mut self) -> Xid {
+        let xid = self.next_xid;
+        self.next_xid += 1;
+        self.active.insert(xid);
+        xid
+    }
+
+    /// The next transaction and the ones still going, for a snapshot
+    /// (see transaction::Snapshot).
+    pub fn running(&self) -> (Xid, BTreeSet<Xid>) {
+        (self.next_xid, self.active.clone())
+    }
+
+    /// So a transaction from before (a crash) isn't handed out again.
+    pub fn seen(&mut self, xid: Xid) {
+        self.next_xid = self.next_xid.max(xid + 1);
+    }
+
+    /// A commit record, flushed with everything before it.
+    pub fn commit(&mut self, xid: Xid) -> Result<Lsn, io::Error> {
+        self.log(&Record::Commit { xid })
+    }
+
+    pub fn abort(&mut self, xid: Xid) -> Result<Lsn, io::Error> {
+        self.log(&Record::Abort { xid })
+    }
+
+    /// Not running anymore, once its status is set (see xact).
+    pub fn end(&mut self, xid: Xid) {
+        self.active.remove(&xid);
+    }
+
+    /// If it grew enough since the last checkpoint to need another one.
+    pub fn due(&self) -> bool {
+        self.lsn() - self.redo >= DISTANCE
+    }
+
+    /// Logs a checkpoint, once every page changed before `redo` was
+    /// written (and synced), and the transaction statuses. Recovery
+    /// starts from there, so the segments that end before it are
+    /// removed.
+    pub fn checkpoint(&mut self, redo: Lsn) -> Result<(), io::Error> {
+        let next_xid = self.next_xid;
+        self.log(&Record::Checkpoint { redo, next_xid })?;
+        write_control(&self.path, redo, next_xid)?;
+        self.redo = redo;
+        let segments = segments(&self.path)?;
+        for pair in segments.windows(2) {
+            if pair[1].0 <= redo {
+                std::fs::remove_file(&pair[0].1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn segment(path: &str, start: Lsn) -> PathBuf {
+    PathBuf::from(format!("{path}/{start:016x}"))
+}
+
+// where each one starts, in order
+fn segments(path: &str) -> Result<Vec<(Lsn, PathBuf)>, io::Error> {
+    let mut segments = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if let Some(start) = name.to_str().and_then(|n| Lsn::from_str_radix(n, 16).ok()) {
+            segments.push((start, entry.path()));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Where recovery starts, the redo point of the last checkpoint (0
+/// if there wasn't one).
+pub fn redo_point(path: &str) -> Result<Lsn, io::Error> {
+    Ok(control(path)?.0)
+}
+
+// [version][redo point][next transaction], 0 isn't one
+fn control(path: &str) -> Result<(Lsn, Xid), io::Error> {
+    let other = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the log in {path} is from another version, it can't be read"),
+        )
+    };
+    let bytes = match std::fs::read(format!("{path}/checkpoint")) {
+        Ok(bytes) => bytes,
+        // a new log, one with segments always has it
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return match segments(path)?.is_empty() {
+                true => Ok((0, 1)),
+                false => Err(other()),
+            };
+        }
+        Err(err) => return Err(err),
+    };
+    if bytes.len() != 20 || bytes[..4] != VERSION.to_be_bytes() {
+        return Err(other());
+    }
+    Ok((
+        Lsn::from_be_bytes(bytes[4..12].try_into().unwrap()),
+        Xid::from_be_bytes(bytes[12..20].try_into().unwrap()),
+    ))
+}
+
+// replaced all at once, a crash leaves the old one or the new one
+fn write_control(path: &str, redo: Lsn, next_xid: Xid) -> Result<(), io::Error> {
+    let control = format!("{path}/checkpoint");
+    let mut file = File::create(format!("{control}.new"))?;
+    file.write_all(&VERSION.to_be_bytes())?;
+    file.write_all(&redo.to_be_bytes())?;
+    file.write_all(&next_xid.to_be_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(format!("{control}.new"), control)
+}
+
+/// Cuts the log off at `end`, the segments after it are removed.
+/// For what's after the last complete record when it's recovered.
+pub fn truncate(path: &str, end: Lsn) -> Result<(), io::Error> {
+    for (start, path) in segments(path)? {
+        if start > end {
+            std::fs::remove_file(path)?;
+            continue;
+        }
+        let file = OpenOptions::new().write(true).open(path)?;
+        if start + file.metadata()?.len() > end {
+            file.set_len(end - start)?;
+        }
+    }
+    Ok(())
+}
+
+/// Every record in the log from `from` (where one starts) and its
+/// LSN, up to the first one that wasn't completely written (or is
+/// corrupt).
+pub fn read(path: &str, from: Lsn) -> Result<Vec<(Lsn, Record)>, io::Error> {
+    // the segments from the one `from` is in, one after the other
+    let mut bytes = vec![];
+    for (start, path) in segments(path)? {
+        let mut file = File::open(path)?;
+        let at = from + bytes.len() as Lsn;
+        if start + file.metadata()?.len() <= at && bytes.is_empty() {
+            continue;
+        }
+        if start > at {
+            if bytes.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("the log before {start} was removed, can't read it from {from}"),
+                ));
+            }
+            break;
+        }
+        file.seek(SeekFrom::Start(at - start))?;
+        file.read_to_end(&mut bytes)?;
+    }
+    let mut records = vec![];
+    let mut at = 0;
+    while let Some(header) = bytes.get(at..at + 8) {
+        let len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+        let sum = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let Some(body) = bytes.get(at + 8..at + 8 + len) else {
+            break;
+        };
+        if checksum(body) != sum {
+            break;
+        }
+        let Some(record) = Record::decode(body) else {
+            break;
+        };
+        at += 8 + len;
+        records.push((from + at as Lsn, record));
+    }
+    Ok(records)
+}
+
+#[test]
+fn test_wal() {
+    let path = &data::test_dir("test_wal");
+    let mut wal = Wal::open(path).unwrap();
+    let xid = wal.begin();
+    let page = PageId::new("movies", 3);
+    let records = [
+        Record::Init { page: page.clone() },
+        Record::Insert {
+            page: page.clone(),
+            xid,
+            slot: 0,
+            tuple: vec![0, 1, b'1'],
+        },
+        Record::Delete { page, xid, slot: 0 },
+    ];
+    let lsns: Vec<Lsn> = records.iter().map(|r| wal.append(r).unwrap()).collect();
+    // nothing's there until it's flushed
+    assert!(read(path, 0).unwrap().is_empty());
+    wal.flush(lsns[1]).unwrap();
+    assert_eq!(wal.flushed(), lsns[2]);
+    let commit = wal.commit(xid).unwrap();
+
+    let read_back = read(path, 0).unwrap();
+    assert_eq!(read_back.len(), 4);
+    assert_eq!(read_back[1], (lsns[1], records[1].clone()));
+    assert_eq!(read_back[3], (commit, Record::Commit { xid }));
+    // or from where one of them starts
+    assert_eq!(read(path, lsns[1]).unwrap(), read_back[2..]);
+
+    // the last one only partly made it
+    let bytes = std::fs::read(segment(path, 0)).unwrap();
+    std::fs::write(segment(path, 0), &bytes[..bytes.len() - 3]).unwrap();
+    assert_eq!(read(path, 0).unwrap().len(), 3);
+}
+
+#[test]
+fn test_checkpoint() {
+    let path = &data::test_dir("test_checkpoint");
+    let mut wal = Wal::open(path).unwrap();
+    // a few records in each segment
+    wal.segment = 100;
+    let delete = |slot| Record::Delete {
+        page: PageId::new("movies", 0),
+        xid: 0,
+        slot,
+    };
+    let lsns: Vec<Lsn> = (0..20).map(|n| wal.log(&delete(n)).unwrap()).collect();
+    let before = segments(path).unwrap().len();
+    assert!(before > 3);
+    // the same as if it was one file
+    let all = read(path, 0).unwrap();
+    assert_eq!(all.len(), 20);
+    assert_eq!(all[19], (lsns[19], delete(19)));
+
+    assert_eq!(redo_point(path).unwrap(), 0);
+    wal.checkpoint(lsns[10]).unwrap();
+    let checkpoint = wal.lsn();
+    assert_eq!(redo_point(path).unwrap(), lsns[10]);
+    assert!(segments(path).unwrap().len() < before);
+    assert!(read(path, 0).is_err());
+    let after = read(path, lsns[10]).unwrap();
+    assert_eq!(after[..9], all[11..]);
+    let next_xid = wal.next_xid;
+    assert_eq!(
+        after[9],
+        (
+            checkpoint,
+            Record::Checkpoint {
+                redo: lsns[10],
+                next_xid
+            }
+        )
+    );
+
+    // it goes on where it was
+    drop(wal);
+    let mut wal = Wal::open(path).unwrap();
+    assert_eq!(wal.lsn(), checkpoint);
+    assert_eq!(wal.next_xid, next_xid);
+    let lsn = wal.log(&delete(20)).unwrap();
+    assert_eq!(
+        read(path, lsns[10]).unwrap().last(),
+        Some(&(lsn, delete(20)))
+    );
+
+    truncate(path, lsns[15]).unwrap();
+    assert_eq!(read(path, lsns[10]).unwrap(), all[11..16]);
+
+    // one from before there was a version, its records aren't these
+    drop(wal);
+    std::fs::write(format!("{path}/checkpoint"), [0; 16]).unwrap();
+    assert!(Wal::open(path).is_err());
+    assert!(redo_point(path).is_err());
+}
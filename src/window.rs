@@ -0,0 +1,377 @@
+use std::collections::VecDeque;
+
+use crate::source::{compare_rows, sort_keys, Row, Schema, Sort, SortKey};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Function {
+    RowNumber,
+    Rank,
+    DenseRank,
+    // field and how many rows back/ahead
+    Lag(String, usize),
+    Lead(String, usize),
+    Sum(String),
+    Avg(String),
+}
+
+/// Which rows around the current one SUM/AVG look at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Frame {
+    // the default, from the start of the partition up to the last row
+    // that ties with the current one in the ORDER (or the whole partition
+    // if there's no ORDER)
+    Range,
+    // ROWS BETWEEN n PRECEDING AND CURRENT ROW, None being UNBOUNDED
+    Rows(Option<usize>),
+}
+
+/// A window function from the projection, written like in SQL, e.g.
+/// `RANK() OVER (PARTITION BY userId ORDER BY rating DESC)` or
+/// `AVG(rating) OVER (PARTITION BY userId ORDER BY timestamp ROWS BETWEEN 2 PRECEDING AND CURRENT ROW)`.
+#[derive(Clone, Debug)]
+pub struct WindowFunction {
+    function: Function,
+    partition: Vec<String>,
+    // same format as the ORDER clause
+    order: Vec<String>,
+    frame: Frame,
+    name: String,
+}
+
+impl WindowFunction {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Every field it reads: its argument, the PARTITION and the ORDER.
+    pub fn fields(&self) -> Vec<&str> {
+        let arg = match &self.function {
+            Function::Lag(field, _)
+            | Function::Lead(field, _)
+            | Function::Sum(field)
+            | Function::Avg(field) => Some(field.as_str()),
+            _ => None,
+        };
+        arg.into_iter()
+            .chain(self.partition.iter().map(String::as_str))
+            .chain(self.order.iter().map(String::as_str))
+            .filter(|f| *f != "ASC" && *f != "DESC")
+            .collect()
+    }
+
+    /// Returns None if the field isn't a window function call at all.
+    pub fn parse(field: &str) -> Option<Self> {
+        let (call, over) = field.split_once(" OVER ")?;
+        let (function, args) = call.trim().split_once('(')?;
+        let args: Vec<&str> = args
+            .strip_suffix(')')?
+            .split(',')
+            .map(str::trim)
+            .filter(|arg| !arg.is_empty())
+            .collect();
+        let offset = |args: &[&str]| -> usize {
+            args.get(1)
+                .map(|n| n.parse().expect("LAG/LEAD offset should be a number"))
+                .unwrap_or(1)
+        };
+        let function = match (function, args.first()) {
+            ("ROW_NUMBER", None) => Function::RowNumber,
+            ("RANK", None) => Function::Rank,
+            ("DENSE_RANK", None) => Function::DenseRank,
+            ("LAG", Some(field)) => Function::Lag(field.to_string(), offset(&args)),
+            ("LEAD", Some(field)) => Function::Lead(field.to_string(), offset(&args)),
+            ("SUM", Some(field)) => Function::Sum(field.to_string()),
+            ("AVG", Some(field)) => Function::Avg(field.to_string()),
+            _ => panic!("unsupported window function '{call}'"),
+        };
+
+        let over = over.trim().strip_prefix('(')?.strip_suffix(')')?;
+        let tokens: Vec<&str> = over
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|t| !t.is_empty())
+            .collect();
+        let (mut partition, mut order, mut frame) = (vec![], vec![], Frame::Range);
+        let mut i = 0;
+        while i < tokens.len() {
+            match (tokens[i], tokens.get(i + 1)) {
+                ("PARTITION", Some(&"BY")) => {
+                    i += 2;
+                    while i < tokens.len() && !["ORDER", "ROWS", "RANGE"].contains(&tokens[i]) {
+                        partition.push(tokens[i].to_owned());
+                        i += 1;
+                    }
+                }
+                ("ORDER", Some(&"BY")) => {
+                    i += 2;
+                    while i < tokens.len() && !["ROWS", "RANGE"].contains(&tokens[i]) {
+                        order.push(tokens[i].to_owned());
+                        i += 1;
+                    }
+                }
+                ("ROWS" | "RANGE", Some(&"BETWEEN")) => {
+                    let start = match &tokens[i + 2..] {
+                        ["UNBOUNDED", "PRECEDING", "AND", "CURRENT", "ROW"] => None,
+                        [n, "PRECEDING", "AND", "CURRENT", "ROW"] if tokens[i] == "ROWS" => {
+                            Some(n.parse().expect("PRECEDING should be a number"))
+                        }
+                        _ => panic!("unsupported window frame in '{over}'"),
+                    };
+                    frame = match (tokens[i], start) {
+                        ("ROWS", start) => Frame::Rows(start),
+                        _ => Frame::Range,
+                    };
+                    i = tokens.len();
+                }
+                (token, _) => panic!("unexpected '{token}' in window '{over}'"),
+            }
+        }
+
+        Some(Self {
+            function,
+            partition,
+            order,
+            frame,
+            name: field.to_owned(),
+        })
+    }
+}
+
+/// Splits the projection into the window functions it has, if any.
+pub fn windows(projection: &[String]) -> Vec<WindowFunction> {
+    projection
+        .iter()
+        .filter_map(|field| WindowFunction::parse(field))
+        .collect()
+}
+
+/// The input fields followed by one field per window function,
+/// named as they were written so QUALIFY/ORDER/PROJECTION can refer to them.
+pub fn schema(functions: &[WindowFunction], schema: &Schema) -> Schema {
+    Schema {
+        table: schema.table.clone(),
+        fields: schema
+            .fields
+            .iter()
+            .cloned()
+            .chain(functions.iter().map(|f| f.name.clone()))
+            .collect(),
+    }
+}
+
+fn position(field: &str, schema: &Schema) -> usize {
+    schema
+        .position(field)
+        .unwrap_or_else(|| panic!("'{}' field not found in table '{}'", field, schema.table))
+}
+
+/// Sorts the input by PARTITION + ORDER and then evaluates the
+/// functions one partition at a time, appending a field per function.
+/// All functions have to share the same PARTITION and ORDER (the frame
+/// can differ), since they're all evaluated over the same sort.
+pub struct Window<'a> {
+    source: Sort<'a>,
+    functions: Vec<WindowFunction>,
+    // field positions of each function's argument, if it has one
+    args: Vec<Option<usize>>,
+    partition: Vec<usize>,
+    order: Vec<SortKey>,
+    // first row of the next partition
+    pending: Option<Row>,
+    results: VecDeque<Row>,
+}
+
+impl<'a> Window<'a> {
+    pub fn new(
+        functions: Vec<WindowFunction>,
+        source: impl Iterator<Item = Row> + 'a,
+        schema: &Schema,
+    ) -> Self {
+        let first = functions.first().expect("at least one window function");
+        assert!(
+            functions
+                .iter()
+                .all(|f| f.partition == first.partition && f.order == first.order),
+            "for now all window functions need the same PARTITION and ORDER"
+        );
+        let partition = first
+            .partition
+            .iter()
+            .map(|f| position(f, schema))
+            .collect();
+        let order = sort_keys(&first.order, schema);
+        let mut sort = first.partition.clone();
+        sort.extend(first.order.iter().cloned());
+        let args = functions
+            .iter()
+            .map(|f| match &f.function {
+                Function::Lag(field, _)
+                | Function::Lead(field, _)
+                | Function::Sum(field)
+                | Function::Avg(field) => Some(position(field, schema)),
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            source: Sort::new(sort, source, schema),
+            functions,
+            args,
+            partition,
+            order,
+            pending: None,
+            results: VecDeque::new(),
+        }
+    }
+
+    fn evaluate(&self, mut rows: Vec<Row>) -> Vec<Row> {
+        let len = rows.len();
+        let peers = |a: usize, b: usize| compare_rows(&self.order, &rows[a], &rows[b]).is_eq();
+        // last row tied with each one, for RANK and the default frame
+        let mut peer_end = vec![len - 1; len];
+        for i in (0..len - 1).rev() {
+            if peers(i, i + 1) {
+                peer_end[i] = peer_end[i + 1];
+            } else {
+                peer_end[i] = i;
+            }
+        }
+
+        let mut columns = vec![];
+        for (function, arg) in self.functions.iter().zip(&self.args) {
+            let column: Vec<String> = match &function.function {
+                Function::RowNumber => (1..=len).map(|n| n.to_string()).collect(),
+                Function::Rank | Function::DenseRank => {
+                    let dense = function.function == Function::DenseRank;
+                    let (mut rank, mut column) = (0, vec![]);
+                    for i in 0..len {
+                        if i == 0 || !peers(i - 1, i) {
+                            rank = if dense { rank + 1 } else { i + 1 };
+                        }
+                        column.push(rank.to_string());
+                    }
+                    column
+                }
+                Function::Lag(_, n) | Function::Lead(_, n) => {
+                    let idx = arg.unwrap();
+                    let lag = matches!(function.function, Function::Lag(..));
+                    (0..len)
+                        .map(|i| {
+                            let other = if lag { i.checked_sub(*n) } else { Some(i + n) };
+                            // out of the partition is NULL
+                            other
+                                .and_then(|other| rows.get(other))
+                                .map(|row| row[idx].clone())
+                                .unwrap_or_default()
+                        })
+                        .collect()
+                }
+                Function::Sum(_) | Function::Avg(_) => {
+                    let idx = arg.unwrap();
+                    // prefix sums, empty fields (NULLs) don't count
+                    let (mut sums, mut counts) = (vec![0.0], vec![0]);
+                    for row in &rows {
+                        let value = &row[idx];
+                        let (sum, count) = match value.is_empty() {
+                            true => (0.0, 0),
+                            false => (
+                                value
+                                    .parse::<f64>()
+                                    .unwrap_or_else(|_| panic!("'{value}' is not a number")),
+                                1,
+                            ),
+                        };
+                        sums.push(sums.last().unwrap() + sum);
+                        counts.push(counts.last().unwrap() + count);
+                    }
+                    (0..len)
+                        .map(|i| {
+                            let (start, end) = match function.frame {
+                                Frame::Range => (0, peer_end[i]),
+                                Frame::Rows(None) => (0, i),
+                                Frame::Rows(Some(n)) => (i.saturating_sub(n), i),
+                            };
+                            let sum = sums[end + 1] - sums[start];
+                            let count = counts[end + 1] - counts[start];
+                            match function.function {
+                                Function::Sum(_) => sum.to_string(),
+                                _ if count == 0 => String::new(),
+                                _ => (sum / count as f64).to_string(),
+                            }
+                        })
+                        .collect()
+                }
+            };
+            columns.push(column);
+        }
+
+        for (i, row) in rows.iter_mut().enumerate() {
+            row.extend(columns.iter().map(|column| column[i].clone()));
+        }
+        rows
+    }
+}
+
+impl<'a> Iterator for Window<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.results.is_empty() {
+            let first = self.pending.take().or_else(|| self.source.next())?;
+            let mut rows = vec![first];
+            for row in &mut self.source {
+                if self.partition.iter().any(|i| row[*i] != rows[0][*i]) {
+                    self.pending = Some(row);
+                    break;
+                }
+                rows.push(row);
+            }
+            self.results = self.evaluate(rows).into();
+        }
+        self.results.pop_front()
+    }
+}
+
+#[test]
+fn test_window_functions() {
+    let schema = Schema {
+        table: "ratings".into(),
+        fields: vec!["userId".into(), "rating".into(), "timestamp".into()],
+    };
+    let rows: Vec<Row> = [
+        ["2", "4.0", "30"],
+        ["1", "3.0", "20"],
+        ["1", "5.0", "10"],
+        ["1", "3.0", "40"],
+        ["2", "1.0", "10"],
+    ]
+    .iter()
+    .map(|r| r.iter().map(|s| s.to_string()).collect())
+    .collect();
+    let projection: Vec<String> = [
+        "ROW_NUMBER() OVER (PARTITION BY userId ORDER BY rating DESC)",
+        "RANK() OVER (PARTITION BY userId ORDER BY rating DESC)",
+        "DENSE_RANK() OVER (PARTITION BY userId ORDER BY rating DESC)",
+        "LAG(timestamp) OVER (PARTITION BY userId ORDER BY rating DESC)",
+        "LEAD(timestamp, 2) OVER (PARTITION BY userId ORDER BY rating DESC)",
+        "SUM(rating) OVER (PARTITION BY userId ORDER BY rating DESC)",
+        "AVG(rating) OVER (PARTITION BY userId ORDER BY rating DESC ROWS BETWEEN 1 PRECEDING AND CURRENT ROW)",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+
+    let mut source = rows.into_iter();
+    let found: Vec<Row> = Window::new(windows(&projection), &mut source, &schema).collect();
+
+    let expected: Vec<Row> = vec![
+        vec!["1", "5.0", "10", "1", "1", "1", "", "40", "5", "5"],
+        vec!["1", "3.0", "20", "2", "2", "2", "10", "", "11", "4"],
+        vec!["1", "3.0", "40", "3", "2", "2", "20", "", "11", "3"],
+        vec!["2", "4.0", "30", "1", "1", "1", "", "", "4", "4"],
+        vec!["2", "1.0", "10", "2", "2", "2", "30", "", "5", "2.5"],
+    ]
+    .into_iter()
+    .map(|r| r.into_iter().map(String::from).collect())
+    .collect();
+    assert_eq!(found, expected);
+}
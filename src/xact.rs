@@ -0,0 +1,129 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::data::Shared;
+use crate::wal::Xid;
+
+// whether each transaction committed, a byte for every one of them
+// in the data directory's xact file (like Postgres' pg_xact, that has 2 bits)
+static STATUSES: Shared<Statuses> = Shared::new(|dir| {
+    Statuses::open(&format!("{dir}/xact")).expect("the transaction statuses in the data directory")
+});
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    // or it never got to finish, until recovery says it aborted
+    InProgress = 0,
+    Committed = 1,
+    Aborted = 2,
+}
+
+/// The status of the transaction, 0 (no transaction) is committed.
+pub fn status(xid: Xid) -> Status {
+    if xid == 0 {
+        return Status::Committed;
+    }
+    with(|statuses| statuses.get(xid))
+}
+
+/// Written, but not synced: what the log has of the commits and
+/// aborts since the last checkpoint is set again at recovery.
+pub fn set(xid: Xid, status: Status) -> Result<(), io::Error> {
+    with(|statuses| statuses.set(xid, status))
+}
+
+/// For a checkpoint, the log before it isn't there to set them again.
+pub fn sync() -> Result<(), io::Error> {
+    with(|statuses| statuses.file.sync_data())
+}
+
+/// Every transaction before `next` that didn't commit aborted, for
+/// recovery: after a crash none of them is running anymore.
+pub fn abort_unfinished(next: Xid) -> Result<(), io::Error> {
+    with(|statuses| statuses.abort_unfinished(next))
+}
+
+// what wasn't written is lost
+#[cfg(test)]
+pub(crate) fn crash() {
+    STATUSES.forget();
+}
+
+fn with<T>(f: impl FnOnce(&mut Statuses) -> T) -> T {
+    STATUSES.with(f)
+}
+
+// all of them in memory, and in the file at their xid
+struct Statuses {
+    file: File,
+    statuses: Vec<u8>,
+}
+
+impl Statuses {
+    fn open(path: &str) -> Result<Self, io::Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut statuses = vec![];
+        file.read_to_end(&mut statuses)?;
+        Ok(Self { file, statuses })
+    }
+
+    fn get(&self, xid: Xid) -> Status {
+        match self.statuses.get(xid as usize) {
+            Some(1) => Status::Committed,
+            Some(2) => Status::Aborted,
+            _ => Status::InProgress,
+        }
+    }
+
+    fn set(&mut self, xid: Xid, status: Status) -> Result<(), io::Error> {
+        let at = xid as usize;
+        if self.statuses.len() <= at {
+            self.statuses.resize(at + 1, Status::InProgress as u8);
+        }
+        self.statuses[at] = status as u8;
+        self.file.seek(SeekFrom::Start(xid))?;
+        self.file.write_all(&[status as u8])
+    }
+
+    fn abort_unfinished(&mut self, next: Xid) -> Result<(), io::Error> {
+        let next = next as usize;
+        if self.statuses.len() < next {
+            self.statuses.resize(next, Status::InProgress as u8);
+        }
+        // 0 isn't a transaction
+        for status in &mut self.statuses[1..next] {
+            if *status == Status::InProgress as u8 {
+                *status = Status::Aborted as u8;
+            }
+        }
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&self.statuses)
+    }
+}
+
+#[test]
+fn test_statuses() {
+    crate::data::test_dir("test_statuses");
+    let path = &crate::data::path("xact");
+    let mut statuses = Statuses::open(path).unwrap();
+    statuses.set(3, Status::Committed).unwrap();
+    statuses.set(5, Status::Aborted).unwrap();
+    assert_eq!(statuses.get(3), Status::Committed);
+    // the ones in between weren't set
+    assert_eq!(statuses.get(4), Status::InProgress);
+    assert_eq!(statuses.get(9), Status::InProgress);
+
+    let statuses = Statuses::open(path).unwrap();
+    assert_eq!(statuses.get(3), Status::Committed);
+    assert_eq!(statuses.get(5), Status::Aborted);
+    assert_eq!(std::fs::read(path).unwrap(), [0, 0, 0, 1, 0, 2]);
+
+    let mut statuses = Statuses::open(path).unwrap();
+    statuses.abort_unfinished(8).unwrap();
+    assert_eq!(std::fs::read(path).unwrap(), [0, 2, 2, 1, 2, 2, 2, 2]);
+}